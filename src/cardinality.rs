@@ -0,0 +1,338 @@
+//! Cardinality limits of the instruments created through the crate's meter
+//! provider, see [`MetricsConfig::with_cardinality_limit`].
+//!
+//! The SDK collapses the attribute sets over its limit silently, so the limits
+//! are enforced before the measurements reach it: once an instrument has seen
+//! as many attribute sets as its limit, the measurements with new attribute
+//! sets are recorded with the single [`OVERFLOW_KEY`] attribute. The first
+//! overflow of each instrument is reported to the error handler, and all of
+//! them are counted with the `uptrace.metric.overflow` counter.
+
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+use opentelemetry::metrics::{AsyncInstrument, Callback, Counter, Meter, SyncInstrument};
+use opentelemetry::{Key, KeyValue};
+use opentelemetry_sdk::metrics::InstrumentKind;
+
+use crate::error_handler::{self, OtelError};
+use crate::metrics::{MetricsConfig, OVERFLOW_KEY};
+
+/// Cardinality limits resolved from the [`MetricsConfig`].
+#[derive(Debug)]
+pub(crate) struct CardinalityLimits {
+    config: MetricsConfig,
+    overflowed: Counter<u64>,
+}
+
+impl CardinalityLimits {
+    /// Returns `None` if the config doesn't set any limit.
+    pub(crate) fn new(config: &MetricsConfig, meter: &Meter) -> Option<Self> {
+        if !config.has_cardinality_limits() {
+            return None;
+        }
+        Some(Self {
+            config: config.clone(),
+            overflowed: overflow_counter(meter),
+        })
+    }
+
+    /// Returns the limiter of the instrument, or `None` if it isn't limited.
+    pub(crate) fn limiter(&self, name: &str, kind: InstrumentKind) -> Option<Arc<Limiter>> {
+        let (limit, allowed_keys) = self.config.cardinality_limit(name, kind)?;
+        Some(Limiter::new(
+            name,
+            limit,
+            allowed_keys.map(<[Key]>::to_vec),
+            self.overflowed.clone(),
+        ))
+    }
+}
+
+/// Returns the `uptrace.metric.overflow` counter.
+pub(crate) fn overflow_counter(meter: &Meter) -> Counter<u64> {
+    meter
+        .u64_counter("uptrace.metric.overflow")
+        .with_description(
+            "Number of measurements recorded in the overflow series \
+             because of the cardinality limits",
+        )
+        .build()
+}
+
+#[derive(Debug, Default)]
+struct Seen {
+    /// Attribute sets, both sorted by key and in the order they were recorded.
+    sets: HashSet<Vec<KeyValue>>,
+    /// Number of distinct attribute sets.
+    count: usize,
+}
+
+/// Tracks the attribute sets of an instrument.
+#[derive(Debug)]
+pub(crate) struct Limiter {
+    instrument: String,
+    limit: usize,
+    /// Keys kept by the view of the instrument; only they count towards the limit.
+    allowed_keys: Option<Vec<Key>>,
+    seen: RwLock<Seen>,
+    overflow: [KeyValue; 1],
+    overflowed: Counter<u64>,
+    warned: AtomicBool,
+}
+
+impl Limiter {
+    pub(crate) fn new(
+        instrument: &str,
+        limit: usize,
+        allowed_keys: Option<Vec<Key>>,
+        overflowed: Counter<u64>,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            instrument: instrument.to_string(),
+            limit,
+            allowed_keys,
+            seen: RwLock::default(),
+            overflow: [KeyValue::new(OVERFLOW_KEY, true)],
+            overflowed,
+            warned: AtomicBool::new(false),
+        })
+    }
+
+    /// Returns the attributes to record the measurement with.
+    pub(crate) fn attributes<'a>(&'a self, attributes: &'a [KeyValue]) -> &'a [KeyValue] {
+        if self.admit(attributes) {
+            return attributes;
+        }
+        self.overflowed
+            .add(1, &[KeyValue::new("instrument", self.instrument.clone())]);
+        if !self.warned.swap(true, Ordering::Relaxed) {
+            error_handler::handle_error(OtelError::internal(
+                "Metrics.CardinalityLimitExceeded",
+                format!(
+                    "instrument {} exceeded its cardinality limit of {}; \
+                     new attribute sets are recorded with {}=true",
+                    self.instrument, self.limit, OVERFLOW_KEY
+                ),
+            ));
+        }
+        &self.overflow
+    }
+
+    fn admit(&self, attributes: &[KeyValue]) -> bool {
+        let attributes = match &self.allowed_keys {
+            Some(keys) => Cow::Owned(
+                attributes
+                    .iter()
+                    .filter(|kv| keys.contains(&kv.key))
+                    .cloned()
+                    .collect(),
+            ),
+            None => Cow::Borrowed(attributes),
+        };
+        let seen = self.seen.read().unwrap_or_else(|e| e.into_inner());
+        if seen.sets.contains(attributes.as_ref()) {
+            return true;
+        }
+        drop(seen);
+
+        let recorded = attributes.into_owned();
+        let mut sorted = recorded.clone();
+        sorted.sort_unstable_by(|a, b| a.key.cmp(&b.key));
+        let mut seen = self.seen.write().unwrap_or_else(|e| e.into_inner());
+        if !seen.sets.contains(&sorted) {
+            if seen.count >= self.limit {
+                return false;
+            }
+            seen.count += 1;
+            seen.sets.insert(sorted);
+        }
+        seen.sets.insert(recorded);
+        true
+    }
+}
+
+type Record<T> = dyn Fn(T, &[KeyValue]) + Send + Sync;
+
+/// Sync instrument that records its measurements with the limited attributes.
+pub(crate) struct Limited<T> {
+    record: Box<Record<T>>,
+    limiter: Arc<Limiter>,
+}
+
+impl<T> Limited<T> {
+    pub(crate) fn new<F>(limiter: Arc<Limiter>, record: F) -> Arc<Self>
+    where
+        F: Fn(T, &[KeyValue]) + Send + Sync + 'static,
+    {
+        Arc::new(Self {
+            record: Box::new(record),
+            limiter,
+        })
+    }
+}
+
+impl<T> SyncInstrument<T> for Limited<T> {
+    fn measure(&self, measurement: T, attributes: &[KeyValue]) {
+        (self.record)(measurement, self.limiter.attributes(attributes))
+    }
+}
+
+/// Wraps the callbacks of an async instrument to limit the attributes of
+/// their observations.
+pub(crate) fn callbacks<M: 'static>(
+    callbacks: Vec<Callback<M>>,
+    limiter: Arc<Limiter>,
+) -> Vec<Callback<M>> {
+    callbacks
+        .into_iter()
+        .map(|callback| {
+            let limiter = limiter.clone();
+            Box::new(move |observer: &dyn AsyncInstrument<M>| {
+                callback(&LimitedObserver {
+                    inner: observer,
+                    limiter: &limiter,
+                })
+            }) as Callback<M>
+        })
+        .collect()
+}
+
+struct LimitedObserver<'a, M> {
+    inner: &'a dyn AsyncInstrument<M>,
+    limiter: &'a Limiter,
+}
+
+impl<M> AsyncInstrument<M> for LimitedObserver<'_, M> {
+    fn observe(&self, measurement: M, attributes: &[KeyValue]) {
+        self.inner
+            .observe(measurement, self.limiter.attributes(attributes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use opentelemetry::metrics::MeterProvider as _;
+    use opentelemetry::KeyValue;
+    use opentelemetry_sdk::metrics::data::{AggregatedMetrics, MetricData};
+    use opentelemetry_sdk::metrics::{InMemoryMetricExporter, PeriodicReader, SdkMeterProvider};
+
+    use super::CardinalityLimits;
+    use crate::meter::MeterProvider;
+    use crate::metrics::{MetricsConfig, View, OVERFLOW_KEY};
+
+    /// Returns the data points of the sums as (name, attributes, value).
+    fn collect<F>(config: MetricsConfig, record: F) -> Vec<(String, Vec<KeyValue>, u64)>
+    where
+        F: FnOnce(&MeterProvider),
+    {
+        let exporter = InMemoryMetricExporter::default();
+        let builder = SdkMeterProvider::builder()
+            .with_reader(PeriodicReader::builder(exporter.clone()).build());
+        let sdk_provider = config.apply(builder).unwrap().build();
+        let limits = CardinalityLimits::new(&config, &sdk_provider.meter("uptrace"));
+        let provider = MeterProvider::new(sdk_provider, None, limits.map(Arc::new));
+        record(&provider);
+        provider.sdk_provider().force_flush().unwrap();
+
+        let mut points = Vec::new();
+        for rm in exporter.get_finished_metrics().unwrap() {
+            for metric in rm.scope_metrics().flat_map(|sm| sm.metrics()) {
+                let AggregatedMetrics::U64(MetricData::Sum(sum)) = metric.data() else {
+                    continue;
+                };
+                for point in sum.data_points() {
+                    let mut attributes: Vec<_> = point.attributes().cloned().collect();
+                    attributes.sort_by(|a, b| a.key.cmp(&b.key));
+                    points.push((metric.name().to_string(), attributes, point.value()));
+                }
+            }
+        }
+        points.sort_by_key(|(name, attributes, _)| format!("{name}{attributes:?}"));
+        points
+    }
+
+    fn overflow() -> Vec<KeyValue> {
+        vec![KeyValue::new(OVERFLOW_KEY, true)]
+    }
+
+    #[test]
+    fn limit() {
+        let points = collect(
+            MetricsConfig::default().with_cardinality_limit(2),
+            |provider| {
+                let counter = provider.meter("test").u64_counter("requests").build();
+                let route = |route: &'static str| KeyValue::new("route", route);
+                counter.add(1, &[route("/a"), KeyValue::new("method", "GET")]);
+                counter.add(1, &[KeyValue::new("method", "GET"), route("/a")]);
+                counter.add(1, &[route("/b")]);
+                counter.add(1, &[route("/c")]);
+                counter.add(1, &[route("/d")]);
+                counter.add(1, &[route("/b")]);
+            },
+        );
+
+        let requests: Vec<_> = points.iter().filter(|p| p.0 == "requests").collect();
+        assert_eq!(requests.len(), 3);
+        let overflowed = requests.iter().find(|p| p.1 == overflow()).unwrap();
+        assert_eq!(overflowed.2, 2);
+        let counted = points
+            .iter()
+            .find(|p| p.0 == "uptrace.metric.overflow")
+            .unwrap();
+        assert_eq!(counted.1, [KeyValue::new("instrument", "requests")]);
+        assert_eq!(counted.2, 2);
+    }
+
+    #[test]
+    fn view_limit() {
+        let config = MetricsConfig::default().with_view(
+            View::new("jobs")
+                .with_allowed_attribute_keys(["queue"])
+                .with_cardinality_limit(1),
+        );
+        let points = collect(config, |provider| {
+            let meter = provider.meter("test");
+            let jobs = meter.u64_counter("jobs").build();
+            jobs.add(1, &[KeyValue::new("queue", "a"), KeyValue::new("id", "1")]);
+            jobs.add(1, &[KeyValue::new("queue", "a"), KeyValue::new("id", "2")]);
+            jobs.add(1, &[KeyValue::new("queue", "b"), KeyValue::new("id", "3")]);
+            let other = meter.u64_counter("other").build();
+            for id in 0..3 {
+                other.add(1, &[KeyValue::new("id", id)]);
+            }
+        });
+
+        let jobs: Vec<_> = points.iter().filter(|p| p.0 == "jobs").collect();
+        assert_eq!(jobs.len(), 2);
+        assert_eq!(jobs[0].1, overflow());
+        assert_eq!(jobs[1].1, [KeyValue::new("queue", "a")]);
+        assert_eq!(jobs[1].2, 2);
+        assert_eq!(points.iter().filter(|p| p.0 == "other").count(), 3);
+    }
+
+    #[test]
+    fn observable_limit() {
+        let points = collect(
+            MetricsConfig::default().with_cardinality_limit(1),
+            |provider| {
+                provider
+                    .meter("test")
+                    .u64_observable_counter("bytes")
+                    .with_callback(|observer| {
+                        observer.observe(10, &[KeyValue::new("device", "sda")]);
+                        observer.observe(20, &[KeyValue::new("device", "sdb")]);
+                    })
+                    .build();
+            },
+        );
+
+        let bytes: Vec<_> = points.iter().filter(|p| p.0 == "bytes").collect();
+        assert_eq!(bytes.len(), 2);
+        assert!(bytes.iter().any(|p| p.1 == overflow() && p.2 == 20));
+    }
+}
@@ -0,0 +1,1115 @@
+//! Declarative configuration read from a YAML or TOML file, enabled with the
+//! `config-file` feature.
+//!
+//! [`UptraceBuilder::from_config_file`] picks the format from the extension,
+//! `.yaml`, `.yml` or `.toml`, and [`UptraceBuilder::from_config_env`] reads
+//! the file named by [`UPTRACE_CONFIG_FILE`]. Every key is optional and the
+//! unknown keys are rejected; the settings left out keep the defaults of the
+//! builder, including the ones read from the environment, e.g. `UPTRACE_DSN`.
+//!
+//! ```yaml
+//! dsn: https://<secret>@api.uptrace.dev?grpc=4317
+//! service:
+//!   name: checkout
+//!   version: 1.2.0
+//!   environment: production
+//! resource:
+//!   attributes:
+//!     k8s.namespace.name: shop
+//!     service.instance.id: checkout-1
+//! propagators: [tracecontext, baggage, b3]
+//! redact:
+//!   recommended: true
+//!   deny_keys: [ssn]
+//!
+//! traces:
+//!   exporters: [otlp]          # an empty list disables the signal
+//!   sampler:
+//!     type: parentbased_traceidratio
+//!     ratio: 0.25
+//!   batch:
+//!     max_queue_size: 30000
+//!     max_export_batch_size: 10000
+//!     scheduled_delay: 5s
+//!   limits:
+//!     max_attributes_per_span: 128
+//!   span_metrics:
+//!     dimensions: [http.route]
+//!
+//! metrics:
+//!   exporters: [otlp, prometheus]
+//!   temporality: delta
+//!   interval: 15s
+//!   exemplar_filter: trace_based
+//!   cardinality_limit: 2000
+//!   prometheus:
+//!     addr: 0.0.0.0:9464
+//!   views:
+//!     - instrument: http.server.request.duration
+//!       buckets: [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5]
+//!     - instrument: db.client.*
+//!       attribute_keys: [db.system]
+//!     - instrument: debug.*
+//!       aggregation: drop
+//!
+//! logs:
+//!   exporters: [otlp]
+//!   limits:
+//!     max_attributes_per_log_record: 64
+//! ```
+//!
+//! The TOML format has the same structure. Durations are written as an
+//! integer with a `ms`, `s`, `m` or `h` unit. The samplers are the ones of
+//! `OTEL_TRACES_SAMPLER`: `always_on`, `always_off`, `traceidratio`,
+//! `parentbased_always_on`, `parentbased_always_off` and
+//! `parentbased_traceidratio`; the view aggregations are `default`, `drop`,
+//! `sum`, `last_value`, `explicit_bucket_histogram` and
+//! `base2_exponential_bucket_histogram`.
+//!
+//! Invalid files fail with [`Error::InvalidConfigFile`], which names the
+//! offending key, e.g. `traces.sampler.ratio` or `metrics.views[1].kind`.
+//!
+//! [`UptraceBuilder::from_config_file`]: crate::UptraceBuilder::from_config_file
+//! [`UptraceBuilder::from_config_env`]: crate::UptraceBuilder::from_config_env
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use opentelemetry::KeyValue;
+use opentelemetry_sdk::trace::Sampler;
+use regex::Regex;
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
+
+use crate::limits::{LogLimits, SpanLimits};
+use crate::metrics::{Aggregation, ExemplarFilter, InstrumentKind, Temporality, View};
+use crate::propagation::Propagator;
+use crate::redact::Redactor;
+use crate::span_metrics::SpanMetricsConfig;
+use crate::{Dsn, Error, UptraceBuilder};
+
+/// Env var with the path of the configuration file read by
+/// [`UptraceBuilder::from_config_env`](crate::UptraceBuilder::from_config_env).
+pub const UPTRACE_CONFIG_FILE: &str = "UPTRACE_CONFIG_FILE";
+
+/// Reads the file and applies it to the builder.
+pub(crate) fn load(path: &Path, builder: UptraceBuilder) -> Result<UptraceBuilder, Error> {
+    let error = |invalid: Invalid| Error::InvalidConfigFile {
+        path: path.to_path_buf(),
+        key: invalid.key,
+        reason: invalid.reason,
+    };
+    let format = Format::from_path(path).map_err(error)?;
+    let content = std::fs::read_to_string(path).map_err(|err| {
+        error(Invalid {
+            key: None,
+            reason: err.to_string(),
+        })
+    })?;
+    format
+        .parse(&content)
+        .and_then(|file| file.apply(builder))
+        .map_err(error)
+}
+
+/// Validation error of the file, with the path of the offending key.
+#[derive(Debug)]
+struct Invalid {
+    key: Option<String>,
+    reason: String,
+}
+
+impl Invalid {
+    fn new<K: Into<String>, R: fmt::Display>(key: K, reason: R) -> Self {
+        Self {
+            key: Some(key.into()),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Yaml,
+    Toml,
+}
+
+impl Format {
+    fn from_path(path: &Path) -> Result<Self, Invalid> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml" | "yml") => Ok(Self::Yaml),
+            Some("toml") => Ok(Self::Toml),
+            _ => Err(Invalid {
+                key: None,
+                reason: "unsupported format, expected a .yaml, .yml or .toml file".into(),
+            }),
+        }
+    }
+
+    fn parse(self, content: &str) -> Result<ConfigFile, Invalid> {
+        match self {
+            Self::Yaml => {
+                let result =
+                    serde_path_to_error::deserialize(serde_yaml::Deserializer::from_str(content));
+                result.map_err(|err| invalid(err.path(), err.inner().to_string()))
+            }
+            Self::Toml => {
+                let result = serde_path_to_error::deserialize(toml::Deserializer::new(content));
+                result.map_err(|err| match invalid(err.path(), "") {
+                    // The message without the line, which is redundant with the key.
+                    Invalid { key: Some(key), .. } => Invalid::new(key, err.inner().message()),
+                    Invalid { key: None, .. } => Invalid {
+                        key: None,
+                        reason: err.inner().to_string().trim_end().to_string(),
+                    },
+                })
+            }
+        }
+    }
+}
+
+/// Returns the error at the given path, without a key for the errors of the
+/// whole file, e.g. syntax errors.
+fn invalid<R: Into<String>>(path: &serde_path_to_error::Path, reason: R) -> Invalid {
+    let key = path.to_string();
+    Invalid {
+        key: (key != ".").then_some(key),
+        reason: reason.into(),
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    dsn: Option<String>,
+    service: ServiceSection,
+    resource: ResourceSection,
+    propagators: Option<Vec<Parsed<Propagator>>>,
+    redact: Option<RedactSection>,
+    traces: TracesSection,
+    metrics: MetricsSection,
+    logs: LogsSection,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ServiceSection {
+    name: Option<String>,
+    version: Option<String>,
+    environment: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ResourceSection {
+    attributes: BTreeMap<String, AttributeValue>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged, expecting = "a string, boolean or number")]
+enum AttributeValue {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+}
+
+impl AttributeValue {
+    fn key_value(self, key: String) -> KeyValue {
+        match self {
+            Self::Bool(value) => KeyValue::new(key, value),
+            Self::Int(value) => KeyValue::new(key, value),
+            Self::Float(value) => KeyValue::new(key, value),
+            Self::String(value) => KeyValue::new(key, value),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RedactSection {
+    recommended: bool,
+    deny_keys: Vec<String>,
+    allow_keys: Vec<String>,
+    url_keys: Vec<String>,
+    query_keys: Vec<String>,
+    patterns: Vec<Parsed<Regex>>,
+    replacement: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Exporter {
+    Otlp,
+    Prometheus,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TracesSection {
+    exporters: Option<Vec<Exporter>>,
+    sampler: Option<SamplerSection>,
+    batch: Option<BatchSection>,
+    limits: Option<SpanLimitsSection>,
+    span_metrics: Option<SpanMetricsSection>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SamplerSection {
+    #[serde(rename = "type")]
+    kind: SamplerKind,
+    ratio: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+enum SamplerKind {
+    #[serde(rename = "always_on")]
+    AlwaysOn,
+    #[serde(rename = "always_off")]
+    AlwaysOff,
+    #[serde(rename = "traceidratio")]
+    TraceIdRatio,
+    #[serde(rename = "parentbased_always_on")]
+    ParentBasedAlwaysOn,
+    #[serde(rename = "parentbased_always_off")]
+    ParentBasedAlwaysOff,
+    #[serde(rename = "parentbased_traceidratio")]
+    ParentBasedTraceIdRatio,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct BatchSection {
+    max_queue_size: Option<usize>,
+    max_export_batch_size: Option<usize>,
+    scheduled_delay: Option<Parsed<Duration>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct SpanLimitsSection {
+    max_attributes_per_span: Option<u32>,
+    max_events_per_span: Option<u32>,
+    max_links_per_span: Option<u32>,
+    max_attributes_per_event: Option<u32>,
+    max_attributes_per_link: Option<u32>,
+    max_attribute_value_length: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct SpanMetricsSection {
+    dimensions: Vec<String>,
+    max_series: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct MetricsSection {
+    exporters: Option<Vec<Exporter>>,
+    temporality: Option<TemporalityValue>,
+    interval: Option<Parsed<Duration>>,
+    timeout: Option<Parsed<Duration>>,
+    exemplar_filter: Option<ExemplarFilterValue>,
+    exponential_histograms: Option<bool>,
+    cardinality_limit: Option<NonZeroUsize>,
+    runtime_metrics: Option<bool>,
+    prometheus: Option<PrometheusSection>,
+    system_metrics: Option<SystemMetricsSection>,
+    views: Vec<ViewSection>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum TemporalityValue {
+    Delta,
+    Cumulative,
+    LowMemory,
+}
+
+impl From<TemporalityValue> for Temporality {
+    fn from(value: TemporalityValue) -> Self {
+        match value {
+            TemporalityValue::Delta => Temporality::Delta,
+            TemporalityValue::Cumulative => Temporality::Cumulative,
+            TemporalityValue::LowMemory => Temporality::LowMemory,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ExemplarFilterValue {
+    AlwaysOn,
+    TraceBased,
+    AlwaysOff,
+}
+
+impl From<ExemplarFilterValue> for ExemplarFilter {
+    fn from(value: ExemplarFilterValue) -> Self {
+        match value {
+            ExemplarFilterValue::AlwaysOn => ExemplarFilter::AlwaysOn,
+            ExemplarFilterValue::TraceBased => ExemplarFilter::TraceBased,
+            ExemplarFilterValue::AlwaysOff => ExemplarFilter::AlwaysOff,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct PrometheusSection {
+    addr: Option<SocketAddr>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct SystemMetricsSection {
+    interval: Option<Parsed<Duration>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ViewSection {
+    instrument: String,
+    kind: Option<InstrumentKindValue>,
+    name: Option<String>,
+    description: Option<String>,
+    attribute_keys: Option<Vec<String>>,
+    aggregation: Option<AggregationValue>,
+    buckets: Option<Vec<f64>>,
+    cardinality_limit: Option<NonZeroUsize>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum InstrumentKindValue {
+    Counter,
+    UpDownCounter,
+    Histogram,
+    Gauge,
+    ObservableCounter,
+    ObservableUpDownCounter,
+    ObservableGauge,
+}
+
+impl From<InstrumentKindValue> for InstrumentKind {
+    fn from(value: InstrumentKindValue) -> Self {
+        match value {
+            InstrumentKindValue::Counter => InstrumentKind::Counter,
+            InstrumentKindValue::UpDownCounter => InstrumentKind::UpDownCounter,
+            InstrumentKindValue::Histogram => InstrumentKind::Histogram,
+            InstrumentKindValue::Gauge => InstrumentKind::Gauge,
+            InstrumentKindValue::ObservableCounter => InstrumentKind::ObservableCounter,
+            InstrumentKindValue::ObservableUpDownCounter => InstrumentKind::ObservableUpDownCounter,
+            InstrumentKindValue::ObservableGauge => InstrumentKind::ObservableGauge,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum AggregationValue {
+    Default,
+    Drop,
+    Sum,
+    LastValue,
+    ExplicitBucketHistogram,
+    Base2ExponentialBucketHistogram,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LogsSection {
+    exporters: Option<Vec<Exporter>>,
+    limits: Option<LogLimitsSection>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LogLimitsSection {
+    max_attributes_per_log_record: Option<u32>,
+    max_attribute_value_length: Option<usize>,
+}
+
+/// Value deserialized from a string.
+#[derive(Debug)]
+struct Parsed<T>(T);
+
+trait ParseValue: Sized {
+    fn parse_value(value: &str) -> Result<Self, String>;
+}
+
+impl ParseValue for Propagator {
+    fn parse_value(value: &str) -> Result<Self, String> {
+        Propagator::from_str(value)
+    }
+}
+
+impl ParseValue for Regex {
+    fn parse_value(value: &str) -> Result<Self, String> {
+        Regex::new(value).map_err(|err| err.to_string())
+    }
+}
+
+impl ParseValue for Duration {
+    fn parse_value(value: &str) -> Result<Self, String> {
+        let value = value.trim();
+        let split = value
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(value.len());
+        let (n, unit) = value.split_at(split);
+        let n: u64 = n.parse().map_err(|_| invalid_duration(value))?;
+        match unit.trim() {
+            "ms" => Some(Duration::from_millis(n)),
+            "s" => Some(Duration::from_secs(n)),
+            "m" => n.checked_mul(60).map(Duration::from_secs),
+            "h" => n.checked_mul(3600).map(Duration::from_secs),
+            _ => None,
+        }
+        .ok_or_else(|| invalid_duration(value))
+    }
+}
+
+fn invalid_duration(value: &str) -> String {
+    format!("invalid duration {value:?}, expected e.g. 500ms, 15s or 1m")
+}
+
+impl<'de, T: ParseValue> Deserialize<'de> for Parsed<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor<T>(PhantomData<T>);
+
+        impl<T: ParseValue> de::Visitor<'_> for Visitor<T> {
+            type Value = Parsed<T>;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a string")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+                T::parse_value(value).map(Parsed).map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_str(Visitor(PhantomData))
+    }
+}
+
+impl ConfigFile {
+    fn apply(self, mut builder: UptraceBuilder) -> Result<UptraceBuilder, Invalid> {
+        if let Some(dsn) = self.dsn {
+            Dsn::try_from(dsn.clone()).map_err(|err| Invalid::new("dsn", err))?;
+            builder = builder.with_dsn(dsn);
+        }
+        if let Some(name) = self.service.name {
+            builder = builder.with_service_name(name);
+        }
+        if let Some(version) = self.service.version {
+            builder = builder.with_service_version(version);
+        }
+        if let Some(environment) = self.service.environment {
+            builder = builder.with_deployment_environment(environment);
+        }
+        builder = builder.with_resource_attributes(
+            self.resource
+                .attributes
+                .into_iter()
+                .map(|(key, value)| value.key_value(key)),
+        );
+        if let Some(propagators) = self.propagators {
+            builder = builder.with_propagators(propagators.into_iter().map(|p| p.0));
+        }
+        if let Some(redact) = self.redact {
+            builder = builder.with_redactor(redact.redactor());
+        }
+        builder = self.traces.apply(builder)?;
+        builder = self.metrics.apply(builder)?;
+        self.logs.apply(builder)
+    }
+}
+
+impl RedactSection {
+    fn redactor(self) -> Redactor {
+        let mut redactor = if self.recommended {
+            Redactor::recommended()
+        } else {
+            Redactor::new()
+        };
+        redactor = redactor
+            .with_deny_keys(self.deny_keys)
+            .with_allow_keys(self.allow_keys)
+            .with_url_keys(self.url_keys)
+            .with_query_keys(self.query_keys);
+        for pattern in self.patterns {
+            redactor = redactor.with_pattern(pattern.0);
+        }
+        if let Some(replacement) = self.replacement {
+            redactor = redactor.with_replacement(replacement);
+        }
+        redactor
+    }
+}
+
+/// Returns whether the signal is exported, i.e. the exporters aren't an empty list.
+fn otlp_enabled(signal: &str, exporters: Option<&[Exporter]>) -> Result<bool, Invalid> {
+    let Some(exporters) = exporters else {
+        return Ok(true);
+    };
+    if let Some(i) = exporters.iter().position(|e| *e == Exporter::Prometheus) {
+        return Err(Invalid::new(
+            format!("{signal}.exporters[{i}]"),
+            "the prometheus exporter only supports metrics",
+        ));
+    }
+    Ok(!exporters.is_empty())
+}
+
+impl TracesSection {
+    fn apply(self, mut builder: UptraceBuilder) -> Result<UptraceBuilder, Invalid> {
+        if !otlp_enabled("traces", self.exporters.as_deref())? {
+            builder = builder.with_tracing_disabled();
+        }
+        if let Some(sampler) = self.sampler {
+            builder = builder.with_sampler(sampler.sampler()?);
+        }
+        if let Some(batch) = self.batch {
+            let mut config = crate::default_batch_config();
+            if let Some(n) = batch.max_queue_size {
+                config = config.with_max_queue_size(n);
+            }
+            if let Some(n) = batch.max_export_batch_size {
+                config = config.with_max_export_batch_size(n);
+            }
+            if let Some(delay) = batch.scheduled_delay {
+                config = config.with_scheduled_delay(delay.0);
+            }
+            builder = builder.with_batch_config(config.build());
+        }
+        if let Some(limits) = self.limits {
+            let mut span_limits = SpanLimits::from_env();
+            if let Some(n) = limits.max_attributes_per_span {
+                span_limits = span_limits.with_max_attributes_per_span(n);
+            }
+            if let Some(n) = limits.max_events_per_span {
+                span_limits = span_limits.with_max_events_per_span(n);
+            }
+            if let Some(n) = limits.max_links_per_span {
+                span_limits = span_limits.with_max_links_per_span(n);
+            }
+            if let Some(n) = limits.max_attributes_per_event {
+                span_limits = span_limits.with_max_attributes_per_event(n);
+            }
+            if let Some(n) = limits.max_attributes_per_link {
+                span_limits = span_limits.with_max_attributes_per_link(n);
+            }
+            if let Some(n) = limits.max_attribute_value_length {
+                span_limits = span_limits.with_max_attribute_value_length(Some(n));
+            }
+            builder = builder.with_span_limits(span_limits);
+        }
+        if let Some(span_metrics) = self.span_metrics {
+            let mut config = SpanMetricsConfig::new().with_dimensions(span_metrics.dimensions);
+            if let Some(n) = span_metrics.max_series {
+                config = config.with_max_series(n);
+            }
+            builder = builder.with_span_metrics(config);
+        }
+        Ok(builder)
+    }
+}
+
+impl SamplerSection {
+    fn sampler(self) -> Result<Sampler, Invalid> {
+        let ratio = match self.kind {
+            SamplerKind::TraceIdRatio | SamplerKind::ParentBasedTraceIdRatio => {
+                let ratio = self.ratio.unwrap_or(1.0);
+                if !(0.0..=1.0).contains(&ratio) {
+                    return Err(Invalid::new(
+                        "traces.sampler.ratio",
+                        format!("must be between 0 and 1, got {ratio}"),
+                    ));
+                }
+                ratio
+            }
+            _ if self.ratio.is_some() => {
+                return Err(Invalid::new(
+                    "traces.sampler.ratio",
+                    "only used by the traceidratio and parentbased_traceidratio samplers",
+                ));
+            }
+            _ => 1.0,
+        };
+        let parent_based = |root: Sampler| Sampler::ParentBased(Box::new(root));
+        Ok(match self.kind {
+            SamplerKind::AlwaysOn => Sampler::AlwaysOn,
+            SamplerKind::AlwaysOff => Sampler::AlwaysOff,
+            SamplerKind::TraceIdRatio => Sampler::TraceIdRatioBased(ratio),
+            SamplerKind::ParentBasedAlwaysOn => parent_based(Sampler::AlwaysOn),
+            SamplerKind::ParentBasedAlwaysOff => parent_based(Sampler::AlwaysOff),
+            SamplerKind::ParentBasedTraceIdRatio => parent_based(Sampler::TraceIdRatioBased(ratio)),
+        })
+    }
+}
+
+impl MetricsSection {
+    fn apply(self, builder: UptraceBuilder) -> Result<UptraceBuilder, Invalid> {
+        let exporters = self.exporters.unwrap_or_else(|| vec![Exporter::Otlp]);
+        if exporters.is_empty() {
+            return Ok(builder.with_metrics_disabled());
+        }
+        let prometheus = exporters.iter().position(|e| *e == Exporter::Prometheus);
+        if prometheus.is_none() && self.prometheus.is_some() {
+            return Err(Invalid::new(
+                "metrics.prometheus",
+                "the prometheus exporter isn't listed in metrics.exporters",
+            ));
+        }
+
+        let mut config = builder
+            .metrics
+            .clone()
+            .with_otlp_export(exporters.contains(&Exporter::Otlp));
+        if let Some(i) = prometheus {
+            config = prometheus_config(config, i, self.prometheus)?;
+        }
+        if let Some(temporality) = self.temporality {
+            config = config.with_temporality(temporality.into());
+        }
+        if let Some(interval) = self.interval {
+            if interval.0.is_zero() {
+                return Err(Invalid::new(
+                    "metrics.interval",
+                    "must be greater than zero",
+                ));
+            }
+            config = config.with_interval(interval.0);
+        }
+        if let Some(timeout) = self.timeout {
+            config = config.with_timeout(timeout.0);
+        }
+        if let Some(filter) = self.exemplar_filter {
+            config = config.with_exemplar_filter(filter.into());
+        }
+        if let Some(enabled) = self.exponential_histograms {
+            config = config.with_exponential_histograms(enabled);
+        }
+        if let Some(limit) = self.cardinality_limit {
+            config = config.with_cardinality_limit(limit.get());
+        }
+        if let Some(enabled) = self.runtime_metrics {
+            config = config.with_runtime_metrics(enabled);
+        }
+        if let Some(system_metrics) = self.system_metrics {
+            config = system_metrics_config(config, system_metrics)?;
+        }
+        for (i, view) in self.views.into_iter().enumerate() {
+            config = config.with_view(view.view(i)?);
+        }
+        Ok(builder.with_metrics(config))
+    }
+}
+
+#[cfg(feature = "prometheus")]
+fn prometheus_config(
+    config: crate::metrics::MetricsConfig,
+    _index: usize,
+    section: Option<PrometheusSection>,
+) -> Result<crate::metrics::MetricsConfig, Invalid> {
+    let mut prometheus = crate::prometheus::PrometheusConfig::default();
+    if let Some(addr) = section.and_then(|section| section.addr) {
+        prometheus = prometheus.with_addr(addr);
+    }
+    Ok(config.with_prometheus(prometheus))
+}
+
+#[cfg(not(feature = "prometheus"))]
+fn prometheus_config(
+    _config: crate::metrics::MetricsConfig,
+    index: usize,
+    _section: Option<PrometheusSection>,
+) -> Result<crate::metrics::MetricsConfig, Invalid> {
+    Err(Invalid::new(
+        format!("metrics.exporters[{index}]"),
+        "requires the prometheus feature",
+    ))
+}
+
+#[cfg(feature = "system-metrics")]
+fn system_metrics_config(
+    config: crate::metrics::MetricsConfig,
+    section: SystemMetricsSection,
+) -> Result<crate::metrics::MetricsConfig, Invalid> {
+    let mut system_metrics = crate::system_metrics::SystemMetricsConfig::default();
+    if let Some(interval) = section.interval {
+        if interval.0.is_zero() {
+            return Err(Invalid::new(
+                "metrics.system_metrics.interval",
+                "must be greater than zero",
+            ));
+        }
+        system_metrics = system_metrics.with_interval(interval.0);
+    }
+    Ok(config.with_system_metrics(system_metrics))
+}
+
+#[cfg(not(feature = "system-metrics"))]
+fn system_metrics_config(
+    _config: crate::metrics::MetricsConfig,
+    _section: SystemMetricsSection,
+) -> Result<crate::metrics::MetricsConfig, Invalid> {
+    Err(Invalid::new(
+        "metrics.system_metrics",
+        "requires the system-metrics feature",
+    ))
+}
+
+impl ViewSection {
+    fn view(self, index: usize) -> Result<View, Invalid> {
+        let key = |field: &str| format!("metrics.views[{index}].{field}");
+
+        let mut view = View::new(self.instrument);
+        if let Some(kind) = self.kind {
+            view = view.with_instrument_kind(kind.into());
+        }
+        if let Some(name) = self.name {
+            view = view.with_name(name);
+        }
+        if let Some(description) = self.description {
+            view = view.with_description(description);
+        }
+        if let Some(keys) = self.attribute_keys {
+            view = view.with_allowed_attribute_keys(keys);
+        }
+        view = match (self.aggregation, self.buckets) {
+            (None | Some(AggregationValue::ExplicitBucketHistogram), Some(buckets)) => {
+                view.with_explicit_buckets(buckets)
+            }
+            (Some(AggregationValue::ExplicitBucketHistogram), None) => {
+                return Err(Invalid::new(
+                    key("buckets"),
+                    "required by the explicit_bucket_histogram aggregation",
+                ));
+            }
+            (Some(_), Some(_)) => {
+                return Err(Invalid::new(
+                    key("buckets"),
+                    "only used by the explicit_bucket_histogram aggregation",
+                ));
+            }
+            (Some(AggregationValue::Base2ExponentialBucketHistogram), None) => {
+                view.with_exponential_histogram()
+            }
+            (Some(aggregation), None) => view.with_aggregation(match aggregation {
+                AggregationValue::Drop => Aggregation::Drop,
+                AggregationValue::Sum => Aggregation::Sum,
+                AggregationValue::LastValue => Aggregation::LastValue,
+                _ => Aggregation::Default,
+            }),
+            (None, None) => view,
+        };
+        if let Some(limit) = self.cardinality_limit {
+            view = view.with_cardinality_limit(limit.get());
+        }
+
+        view.validate().map_err(|err| match err {
+            Error::MetricsBuildError(cause) => {
+                Invalid::new(format!("metrics.views[{index}]"), cause)
+            }
+            err => Invalid::new(format!("metrics.views[{index}]"), err),
+        })?;
+        Ok(view)
+    }
+}
+
+impl LogsSection {
+    fn apply(self, mut builder: UptraceBuilder) -> Result<UptraceBuilder, Invalid> {
+        if !otlp_enabled("logs", self.exporters.as_deref())? {
+            builder = builder.with_logs_disabled();
+        }
+        if let Some(limits) = self.limits {
+            let mut log_limits = LogLimits::from_env();
+            if let Some(n) = limits.max_attributes_per_log_record {
+                log_limits = log_limits.with_max_attributes_per_log_record(n);
+            }
+            if let Some(n) = limits.max_attribute_value_length {
+                log_limits = log_limits.with_max_attribute_value_length(Some(n));
+            }
+            builder = builder.with_log_limits(log_limits);
+        }
+        Ok(builder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::KeyValue;
+
+    use super::{Format, Invalid};
+    use crate::propagation::Propagator;
+    use crate::{Error, ErrorKind, UptraceBuilder};
+
+    fn parse(format: Format, content: &str) -> Result<UptraceBuilder, Invalid> {
+        format
+            .parse(content)
+            .and_then(|file| file.apply(UptraceBuilder::default()))
+    }
+
+    fn invalid(format: Format, content: &str) -> (Option<String>, String) {
+        let err = parse(format, content).err().expect("invalid config");
+        (err.key, err.reason)
+    }
+
+    const YAML: &str = r#"
+dsn: https://token@api.uptrace.dev?grpc=4317
+service:
+  name: checkout
+  version: 1.2.0
+  environment: production
+resource:
+  attributes:
+    k8s.namespace.name: shop
+    replicas: 3
+propagators: [tracecontext, b3]
+traces:
+  sampler:
+    type: parentbased_traceidratio
+    ratio: 0.25
+  batch:
+    scheduled_delay: 2s
+  limits:
+    max_events_per_span: 16
+  span_metrics:
+    dimensions: [http.route]
+metrics:
+  exporters: [otlp]
+  interval: 30s
+  cardinality_limit: 100
+  views:
+    - instrument: http.server.duration
+      kind: histogram
+      buckets: [5, 10, 25]
+    - instrument: debug.*
+      aggregation: drop
+logs:
+  exporters: []
+"#;
+
+    #[test]
+    fn yaml() {
+        let builder = parse(Format::Yaml, YAML).unwrap();
+        assert_eq!(builder.dsn, "https://token@api.uptrace.dev?grpc=4317");
+        assert_eq!(builder.service_name.as_deref(), Some("checkout"));
+        assert_eq!(builder.service_version.as_deref(), Some("1.2.0"));
+        assert_eq!(
+            builder.deployment_environment.as_deref(),
+            Some("production")
+        );
+        assert_eq!(
+            builder.resource_attributes,
+            [
+                KeyValue::new("k8s.namespace.name", "shop"),
+                KeyValue::new("replicas", 3),
+            ]
+        );
+        assert_eq!(
+            builder.propagators,
+            Some(vec![Propagator::TraceContext, Propagator::B3])
+        );
+        assert!(format!("{:?}", builder.sampler).contains("TraceIdRatioBased(0.25)"));
+        assert!(format!("{:?}", builder.batch_config).contains("scheduled_delay: 2s"));
+        assert_eq!(builder.span_limits.unwrap().max_events_per_span, 16);
+        assert!(builder.span_metrics.is_some());
+        assert!(builder.metrics.otlp_export);
+        assert!(builder.metrics.has_cardinality_limits());
+        assert!(!builder.tracing_disabled);
+        assert!(!builder.metrics_disabled);
+        assert!(builder.logs_disabled);
+    }
+
+    #[test]
+    fn toml() {
+        let builder = parse(
+            Format::Toml,
+            r#"
+dsn = "https://token@api.uptrace.dev?grpc=4317"
+propagators = ["jaeger"]
+
+[service]
+name = "checkout"
+
+[traces]
+exporters = []
+
+[metrics]
+temporality = "cumulative"
+exemplar_filter = "always_off"
+
+[[metrics.views]]
+instrument = "db.client.*"
+attribute_keys = ["db.system"]
+"#,
+        )
+        .unwrap();
+        assert_eq!(builder.service_name.as_deref(), Some("checkout"));
+        assert_eq!(builder.propagators, Some(vec![Propagator::Jaeger]));
+        assert!(builder.tracing_disabled);
+        assert!(!builder.logs_disabled);
+        assert_eq!(
+            builder.metrics.temporality,
+            crate::metrics::Temporality::Cumulative
+        );
+    }
+
+    #[test]
+    fn unknown_key() {
+        let (key, reason) = invalid(Format::Yaml, "traces:\n  sampler:\n    kind: always_on\n");
+        assert_eq!(key.as_deref(), Some("traces.sampler.kind"));
+        assert!(reason.contains("unknown field `kind`"), "{reason}");
+
+        let (key, reason) = invalid(Format::Toml, "[metrics]\nintervl = \"15s\"\n");
+        assert_eq!(key.as_deref(), Some("metrics.intervl"));
+        assert!(reason.contains("unknown field `intervl`"), "{reason}");
+    }
+
+    #[test]
+    fn invalid_values() {
+        let cases = [
+            (
+                "traces:\n  sampler:\n    type: traceidratio\n    ratio: 1.5\n",
+                "traces.sampler.ratio",
+                "between 0 and 1",
+            ),
+            (
+                "traces:\n  sampler:\n    type: always_on\n    ratio: 0.5\n",
+                "traces.sampler.ratio",
+                "only used by",
+            ),
+            (
+                "traces:\n  sampler:\n    type: sometimes\n",
+                "traces.sampler.type",
+                "unknown variant `sometimes`",
+            ),
+            (
+                "propagators: [tracecontext, xray]\n",
+                "propagators[1]",
+                "unsupported propagator",
+            ),
+            (
+                "metrics:\n  interval: 15 seconds\n",
+                "metrics.interval",
+                "invalid duration",
+            ),
+            (
+                "metrics:\n  interval: 18446744073709551615h\n",
+                "metrics.interval",
+                "invalid duration",
+            ),
+            (
+                "dsn: https://api.uptrace.dev\n",
+                "dsn",
+                "invalid dsn",
+            ),
+            (
+                "metrics:\n  interval: 0s\n",
+                "metrics.interval",
+                "greater than zero",
+            ),
+            (
+                "metrics:\n  cardinality_limit: 0\n",
+                "metrics.cardinality_limit",
+                "nonzero",
+            ),
+            (
+                "metrics:\n  views:\n    - instrument: a\n    - instrument: b\n      kind: timer\n",
+                "metrics.views[1].kind",
+                "unknown variant `timer`",
+            ),
+            (
+                "metrics:\n  views:\n    - instrument: a\n      buckets: [10, 5]\n",
+                "metrics.views[0]",
+                "invalid view",
+            ),
+            (
+                "metrics:\n  views:\n    - instrument: a\n      aggregation: sum\n      buckets: [5]\n",
+                "metrics.views[0].buckets",
+                "only used by",
+            ),
+            (
+                "logs:\n  exporters: [prometheus]\n",
+                "logs.exporters[0]",
+                "only supports metrics",
+            ),
+            (
+                "redact:\n  patterns: ['(']\n",
+                "redact.patterns[0]",
+                "regex",
+            ),
+            (
+                "resource:\n  attributes:\n    tags: [a, b]\n",
+                "resource.attributes.tags",
+                "a string, boolean or number",
+            ),
+        ];
+        for (content, expected_key, expected_reason) in cases {
+            let (key, reason) = invalid(Format::Yaml, content);
+            assert_eq!(key.as_deref(), Some(expected_key), "{content}");
+            assert!(reason.contains(expected_reason), "{content}: {reason}");
+        }
+    }
+
+    #[test]
+    fn syntax_error() {
+        // The YAML parser is streaming, so the error is at the key being parsed.
+        let (key, reason) = invalid(Format::Yaml, "dsn: \"unterminated\n");
+        assert_eq!(key.as_deref(), Some("dsn"));
+        assert!(reason.contains("line 1"), "{reason}");
+
+        let (key, reason) = invalid(Format::Toml, "[service\n");
+        assert_eq!(key, None);
+        assert!(reason.contains("line 1"), "{reason}");
+    }
+
+    #[test]
+    fn from_config_file() {
+        let dir = std::env::temp_dir().join(format!("uptrace-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("uptrace.yml");
+        std::fs::write(&path, "service:\n  name: checkout\n").unwrap();
+        let builder = UptraceBuilder::from_config_file(&path).unwrap();
+        assert_eq!(builder.service_name.as_deref(), Some("checkout"));
+
+        let path = dir.join("uptrace.toml");
+        std::fs::write(
+            &path,
+            "[traces.sampler]\ntype = \"always_off\"\nratio = 0.5\n",
+        )
+        .unwrap();
+        let err = UptraceBuilder::from_config_file(&path).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::Config);
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "invalid config file {}: traces.sampler.ratio: \
+                 only used by the traceidratio and parentbased_traceidratio samplers",
+                path.display()
+            )
+        );
+
+        let path = dir.join("uptrace.json");
+        let err = UptraceBuilder::from_config_file(&path).err().unwrap();
+        assert!(matches!(err, Error::InvalidConfigFile { key: None, .. }));
+
+        let path = dir.join("missing.yaml");
+        let err = UptraceBuilder::from_config_file(&path).err().unwrap();
+        assert!(matches!(err, Error::InvalidConfigFile { key: None, .. }));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,416 @@
+//! Helpers for database client spans.
+//!
+//! ```no_run
+//! # async fn query(uptrace: &uptrace::Uptrace) -> Result<(), std::io::Error> {
+//! use uptrace::db::DbSpan;
+//!
+//! DbSpan::new("postgresql")
+//!     .with_name("blog")
+//!     .with_statement("SELECT * FROM posts WHERE id = 123")
+//!     .run(uptrace.tracer(), async {
+//!         // Execute the query with sqlx, tokio-postgres, ...
+//!         Ok::<_, std::io::Error>(())
+//!     })
+//!     .await
+//! # }
+//! ```
+
+use std::fmt::Display;
+use std::future::Future;
+
+use opentelemetry::trace::{FutureExt, SpanKind, TraceContextExt, Tracer};
+use opentelemetry::{Context, KeyValue};
+
+use crate::exception::record_display_error;
+use crate::propagation;
+
+/// Builder for a database client span with `db.*` attributes.
+///
+/// Statements passed to [`DbSpan::with_statement`] are normalized with
+/// [`sanitize_sql_for`], so literals such as user ids are not exported.
+#[derive(Debug, Clone)]
+pub struct DbSpan {
+    system: String,
+    operation: Option<String>,
+    attrs: Vec<KeyValue>,
+    statement: Option<String>,
+    statement_hash: bool,
+}
+
+impl DbSpan {
+    /// Creates a span builder for the given `db.system`, e.g. `postgresql` or `mysql`.
+    pub fn new<T: Into<String>>(system: T) -> Self {
+        Self {
+            system: system.into(),
+            operation: None,
+            attrs: Vec::new(),
+            statement: None,
+            statement_hash: false,
+        }
+    }
+
+    /// Set the `db.name` attribute.
+    pub fn with_name<T: Into<String>>(mut self, name: T) -> Self {
+        self.attrs.push(KeyValue::new("db.name", name.into()));
+        self
+    }
+
+    /// Set the `db.user` attribute.
+    pub fn with_user<T: Into<String>>(mut self, user: T) -> Self {
+        self.attrs.push(KeyValue::new("db.user", user.into()));
+        self
+    }
+
+    /// Set the `net.peer.name` and `net.peer.port` attributes.
+    pub fn with_peer<T: Into<String>>(mut self, host: T, port: u16) -> Self {
+        self.attrs.push(KeyValue::new("net.peer.name", host.into()));
+        self.attrs.push(KeyValue::new("net.peer.port", port as i64));
+        self
+    }
+
+    /// Set the `db.operation` attribute, which is also used as the span name.
+    /// Defaults to the first keyword of the statement.
+    pub fn with_operation<T: Into<String>>(mut self, operation: T) -> Self {
+        self.operation = Some(operation.into());
+        self
+    }
+
+    /// Set the `db.statement` attribute to the statement with literals replaced
+    /// by `?`, following the quoting rules of the `db.system`.
+    pub fn with_statement(mut self, statement: &str) -> Self {
+        self.statement = Some(sanitize_sql_for(&self.system, statement));
+        self
+    }
+
+    /// Set the `db.statement` attribute verbatim, e.g. for statements that only
+    /// contain bind parameters.
+    pub fn with_raw_statement<T: Into<String>>(mut self, statement: T) -> Self {
+        self.statement = Some(statement.into());
+        self
+    }
+
+    /// Also set the `db.statement.hash` attribute to a hash of the statement,
+    /// so that queries can be grouped without reading the text.
+    pub fn with_statement_hash(mut self) -> Self {
+        self.statement_hash = true;
+        self
+    }
+
+    /// Starts the span as a child of the active OpenTelemetry span, or else
+    /// of the current `tracing` span.
+    pub fn start<T: Tracer>(self, tracer: &T) -> T::Span {
+        self.start_with_context(tracer, &propagation::current_context())
+    }
+
+    fn start_with_context<T: Tracer>(self, tracer: &T, parent_cx: &Context) -> T::Span {
+        let operation = self.operation.or_else(|| {
+            self.statement
+                .as_deref()
+                .and_then(|statement| statement.split_whitespace().next())
+                .map(|keyword| keyword.to_uppercase())
+        });
+
+        let mut attrs = self.attrs;
+        attrs.push(KeyValue::new("db.system", self.system.clone()));
+        if let Some(operation) = &operation {
+            attrs.push(KeyValue::new("db.operation", operation.clone()));
+        }
+        if let Some(statement) = self.statement {
+            if self.statement_hash {
+                attrs.push(KeyValue::new(
+                    "db.statement.hash",
+                    format!("{:016x}", fnv1a(statement.as_bytes())),
+                ));
+            }
+            attrs.push(KeyValue::new("db.statement", statement));
+        }
+
+        tracer
+            .span_builder(operation.unwrap_or(self.system))
+            .with_kind(SpanKind::Client)
+            .with_attributes(attrs)
+            .start_with_context(tracer, parent_cx)
+    }
+
+    /// Runs the query future inside the span, marking the span as failed if
+    /// the future returns an error.
+    pub async fn run<T, F, R, E>(self, tracer: &T, future: F) -> Result<R, E>
+    where
+        T: Tracer,
+        T::Span: Send + Sync + 'static,
+        F: Future<Output = Result<R, E>>,
+        E: Display,
+    {
+        let parent_cx = propagation::current_context();
+        let cx = parent_cx.with_span(self.start_with_context(tracer, &parent_cx));
+        let result = future.with_context(cx.clone()).await;
+
+        let mut span = cx.span();
+        if let Err(err) = &result {
+            record_display_error(&mut span, err);
+        }
+        span.end();
+        result
+    }
+}
+
+/// Normalizes a SQL statement: string and numeric literals are replaced with
+/// `?`, comments are removed and whitespace is collapsed. Identifiers, quoted
+/// identifiers and bind parameters such as `$1` are kept.
+///
+/// Quoting follows standard SQL and PostgreSQL: double quotes delimit
+/// identifiers, backslashes are only escapes in `E'...'` strings and
+/// `$tag$...$tag$` strings are dollar-quoted. Use [`sanitize_sql_for`] for
+/// MySQL.
+pub fn sanitize_sql(sql: &str) -> String {
+    sanitize(sql, false)
+}
+
+/// Like [`sanitize_sql`], with the quoting rules of the given `db.system`.
+/// For `mysql` and `mariadb`, double-quoted text is a string literal and
+/// backslashes escape quotes in strings.
+pub fn sanitize_sql_for(system: &str, sql: &str) -> String {
+    sanitize(sql, matches!(system, "mysql" | "mariadb"))
+}
+
+fn sanitize(sql: &str, mysql: bool) -> String {
+    let chars: Vec<char> = sql.chars().collect();
+    let mut out = String::with_capacity(sql.len());
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+
+        match c {
+            '\'' => {
+                i = skip_quoted(&chars, i, '\'', mysql);
+                out.push('?');
+            }
+            '"' if mysql => {
+                i = skip_quoted(&chars, i, '"', true);
+                out.push('?');
+            }
+            '"' | '`' => {
+                let end = skip_quoted(&chars, i, c, false);
+                out.extend(&chars[i..end]);
+                i = end;
+            }
+            'E' | 'e' if next == Some('\'') && !mysql => {
+                // PostgreSQL escape string.
+                i = skip_quoted(&chars, i + 1, '\'', true);
+                out.push('?');
+            }
+            '-' if next == Some('-') => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+                push_space(&mut out);
+            }
+            '/' if next == Some('*') => {
+                i += 2;
+                while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                    i += 1;
+                }
+                i = (i + 2).min(chars.len());
+                push_space(&mut out);
+            }
+            '$' => match dollar_tag_len(&chars, i) {
+                Some(len) => {
+                    i = skip_dollar_quoted(&chars, i, len);
+                    out.push('?');
+                }
+                None => i = push_bind_param(&chars, i, &mut out),
+            },
+            ':' | '@' if next.is_some_and(is_ident_char) => {
+                i = push_bind_param(&chars, i, &mut out);
+            }
+            c if c.is_ascii_digit() || (c == '.' && next.is_some_and(|n| n.is_ascii_digit())) => {
+                while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '.') {
+                    i += 1;
+                }
+                out.push('?');
+            }
+            c if is_ident_char(c) => {
+                while i < chars.len() && is_ident_char(chars[i]) {
+                    out.push(chars[i]);
+                    i += 1;
+                }
+            }
+            c if c.is_whitespace() => {
+                push_space(&mut out);
+                i += 1;
+            }
+            c => {
+                out.push(c);
+                i += 1;
+            }
+        }
+    }
+
+    out.trim().to_string()
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn push_space(out: &mut String) {
+    if !out.is_empty() && !out.ends_with(' ') {
+        out.push(' ');
+    }
+}
+
+/// Copies a bind parameter such as `$1`, `:name` or `@p1` and returns the
+/// index after it.
+fn push_bind_param(chars: &[char], start: usize, out: &mut String) -> usize {
+    out.push(chars[start]);
+    let mut i = start + 1;
+    while i < chars.len() && is_ident_char(chars[i]) {
+        out.push(chars[i]);
+        i += 1;
+    }
+    i
+}
+
+/// Returns the index after the closing quote, treating doubled quotes, and
+/// backslash escapes if enabled, as escapes.
+fn skip_quoted(chars: &[char], start: usize, quote: char, backslash_escapes: bool) -> usize {
+    let mut i = start + 1;
+    while i < chars.len() {
+        if chars[i] == '\\' && backslash_escapes {
+            i += 2;
+            continue;
+        }
+        if chars[i] == quote {
+            if chars.get(i + 1) == Some(&quote) {
+                i += 2;
+                continue;
+            }
+            return i + 1;
+        }
+        i += 1;
+    }
+    chars.len()
+}
+
+/// Returns the length of the `$tag$` starting a dollar-quoted string at
+/// `start`, or `None` for a bind parameter such as `$1`.
+fn dollar_tag_len(chars: &[char], start: usize) -> Option<usize> {
+    let mut i = start + 1;
+    if chars.get(i).is_some_and(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    while i < chars.len() && is_ident_char(chars[i]) {
+        i += 1;
+    }
+    (chars.get(i) == Some(&'$')).then_some(i + 1 - start)
+}
+
+/// Returns the index after the tag closing the dollar-quoted string at `start`.
+fn skip_dollar_quoted(chars: &[char], start: usize, tag_len: usize) -> usize {
+    let tag = &chars[start..start + tag_len];
+    let mut i = start + tag_len;
+    while i + tag_len <= chars.len() {
+        if &chars[i..i + tag_len] == tag {
+            return i + tag_len;
+        }
+        i += 1;
+    }
+    chars.len()
+}
+
+/// 64-bit FNV-1a, stable across platforms and Rust versions.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry_sdk::metrics::InMemoryMetricExporter;
+    use tracing::Instrument;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::{sanitize_sql, sanitize_sql_for, DbSpan};
+    use crate::testing::{self, Collect};
+
+    #[tokio::test]
+    async fn tracing_parent() {
+        let spans = Collect::default();
+        let uptrace = testing::uptrace(&spans, &InMemoryMetricExporter::default());
+        let subscriber = tracing_subscriber::registry().with(uptrace.tracing_layer());
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        DbSpan::new("postgresql")
+            .with_statement("SELECT 1")
+            .run(uptrace.tracer(), async { Ok::<_, std::io::Error>(()) })
+            .instrument(tracing::info_span!("parent"))
+            .await
+            .unwrap();
+
+        let spans = spans.spans();
+        let db = spans.iter().find(|span| span.name == "SELECT").unwrap();
+        let parent = spans.iter().find(|span| span.name == "parent").unwrap();
+        assert_eq!(db.parent_span_id, parent.span_context.span_id());
+    }
+
+    #[test]
+    fn sanitize() {
+        let tables = vec![
+            (
+                "SELECT * FROM users WHERE id = 42",
+                "SELECT * FROM users WHERE id = ?",
+            ),
+            (
+                "SELECT * FROM users WHERE email = 'john@example.com' AND age > 21.5",
+                "SELECT * FROM users WHERE email = ? AND age > ?",
+            ),
+            (
+                "INSERT INTO t (a, b) VALUES ('it''s', -1)",
+                "INSERT INTO t (a, b) VALUES (?, -?)",
+            ),
+            (
+                "SELECT \"user\".\"id\", `col1` FROM t2 WHERE x = $1 AND y = :name",
+                "SELECT \"user\".\"id\", `col1` FROM t2 WHERE x = $1 AND y = :name",
+            ),
+            (
+                "SELECT 1 -- secret token\nFROM   dual /* id=5 */ WHERE a IN (1, 2, 0x1F)",
+                "SELECT ? FROM dual WHERE a IN (?, ?, ?)",
+            ),
+            ("SELECT $$raw text$$", "SELECT ?"),
+            ("SELECT $tag$it's a $$secret$$$tag$, $1", "SELECT ?, $1"),
+            ("SELECT $body$unterminated secret", "SELECT ?"),
+            (
+                "SELECT 'C:\\' AS path, 'secret' FROM t",
+                "SELECT ? AS path, ? FROM t",
+            ),
+            ("SELECT E'it\\'s secret', e'x'", "SELECT ?, ?"),
+        ];
+
+        for (sql, expected) in tables {
+            assert_eq!(sanitize_sql(sql), expected, "{}", sql);
+        }
+    }
+
+    #[test]
+    fn sanitize_mysql() {
+        let tables = vec![
+            (
+                "SELECT * FROM users WHERE email = \"john@example.com\"",
+                "SELECT * FROM users WHERE email = ?",
+            ),
+            ("SELECT 'it\\'s', 'secret' FROM `t`", "SELECT ?, ? FROM `t`"),
+            ("SELECT \"a\\\"b\", 1", "SELECT ?, ?"),
+        ];
+
+        for (sql, expected) in tables {
+            assert_eq!(sanitize_sql_for("mysql", sql), expected, "{}", sql);
+        }
+        assert_eq!(
+            sanitize_sql_for("postgresql", "SELECT \"id\" FROM t"),
+            "SELECT \"id\" FROM t"
+        );
+    }
+}
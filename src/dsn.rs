@@ -1,16 +1,23 @@
 use core::fmt;
 use std::fmt::Display;
 
+use opentelemetry::trace::TraceId;
 use url::Url;
 
 use crate::Error;
 
-#[derive(Default)]
+/// Uptrace DSN, e.g. `https://<token>@api.uptrace.dev/<project_id>`.
+///
+/// A self-hosted Uptrace can also be addressed as
+/// `http://<token>@localhost:14318?grpc=14317`: the `grpc` query param sets
+/// the port of the OTLP/gRPC endpoint, and the project id is then optional.
+#[derive(Default, Clone)]
 pub struct Dsn {
     pub(crate) original: String,
     pub(crate) scheme: String,
     pub(crate) host: String,
     pub(crate) port: Option<u16>,
+    pub(crate) grpc_port: Option<u16>,
     pub(crate) project_id: String,
     pub(crate) token: String,
 }
@@ -20,7 +27,7 @@ impl Dsn {
         if self.host == "uptrace.dev" {
             return "otlp.uptrace.dev:4317".into();
         }
-        match self.port {
+        match self.grpc_port.or(self.port) {
             Some(i) => format!("{}:{}", self.host, i),
             None => self.host.clone(),
         }
@@ -34,11 +41,16 @@ impl Dsn {
         format!("{}://{}:{}", self.scheme, self.host, 14318)
     }
 
+    /// Returns the URL of the trace in the Uptrace UI.
+    pub fn trace_url(&self, trace_id: TraceId) -> String {
+        format!("{}/traces/{}", self.app_addr(), trace_id)
+    }
+
     pub fn otlp_grpc_addr(&self) -> String {
         if self.host == "uptrace.dev" {
             return "https://otlp.uptrace.dev:4317".into();
         }
-        match self.port {
+        match self.grpc_port.or(self.port) {
             Some(port) => format!("{}://{}:{}", self.scheme, self.host, port),
             None => format!("{}://{}", self.scheme, self.host),
         }
@@ -50,9 +62,37 @@ impl Dsn {
     }
 }
 
+/// Leaves out the token, and the original DSN that contains it.
+impl fmt::Debug for Dsn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Dsn")
+            .field("scheme", &self.scheme)
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("grpc_port", &self.grpc_port)
+            .field("project_id", &self.project_id)
+            .field("token", &"[REDACTED]")
+            .finish_non_exhaustive()
+    }
+}
+
+/// Formats the original DSN with the token masked.
 impl Display for Dsn {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.original)
+        write!(f, "{}", mask_token(&self.original))
+    }
+}
+
+/// Replaces the user info of the DSN, i.e. the token, with `[REDACTED]`.
+/// Also works for malformed DSNs, so they can be included in errors.
+pub(crate) fn mask_token(dsn: &str) -> String {
+    let start = dsn.find("://").map_or(0, |i| i + 3);
+    let end = dsn[start..]
+        .find(['/', '?', '#'])
+        .map_or(dsn.len(), |i| start + i);
+    match dsn[start..end].rfind('@') {
+        Some(at) => format!("{}[REDACTED]{}", &dsn[..start], &dsn[start + at..]),
+        None => dsn.to_string(),
     }
 }
 
@@ -65,12 +105,12 @@ impl TryFrom<String> for Dsn {
         }
 
         let url = Url::parse(&s).map_err(|e| Error::InvalidDsn {
-            dsn: s.clone(),
+            dsn: mask_token(&s),
             reason: e.to_string(),
         })?;
         if url.scheme().is_empty() {
             return Err(Error::InvalidDsn {
-                dsn: s,
+                dsn: mask_token(&s),
                 reason: "schema is not exist".into(),
             });
         }
@@ -83,29 +123,31 @@ impl TryFrom<String> for Dsn {
             h.to_string()
         } else {
             return Err(Error::InvalidDsn {
-                dsn: s,
+                dsn: mask_token(&s),
                 reason: "host is not exist".into(),
             });
         };
 
-        let path = url
+        let grpc_port = url
+            .query_pairs()
+            .find(|(k, _)| k == "grpc")
+            .and_then(|(_, v)| v.parse::<u16>().ok());
+
+        // DSNs with the grpc query param don't require a project id.
+        let project_id = url
             .path_segments()
-            .and_then(|x| {
-                let path = x.filter(|x| !x.is_empty()).collect::<Vec<&str>>();
-                if path.is_empty() {
-                    None
-                } else {
-                    Some(path)
-                }
-            })
-            .ok_or_else(|| Error::InvalidDsn {
-                dsn: s.clone(),
+            .and_then(|mut x| x.find(|x| !x.is_empty()))
+            .map(String::from);
+        if project_id.is_none() && grpc_port.is_none() {
+            return Err(Error::InvalidDsn {
+                dsn: mask_token(&s),
                 reason: "project id is not exist".into(),
-            })?;
+            });
+        }
 
         if url.username().is_empty() {
             return Err(Error::InvalidDsn {
-                dsn: s.clone(),
+                dsn: mask_token(&s),
                 reason: "token is not exist".into(),
             });
         }
@@ -119,8 +161,9 @@ impl TryFrom<String> for Dsn {
                 host
             },
             port: url.port(),
+            grpc_port,
             token: url.username().into(),
-            project_id: path[0].into(),
+            project_id: project_id.unwrap_or_default(),
         })
     }
 }
@@ -129,7 +172,7 @@ impl TryFrom<String> for Dsn {
 mod tests {
     use std::vec;
 
-    use super::Dsn;
+    use super::{mask_token, Dsn};
 
     #[test]
     fn valid_dsn() {
@@ -138,11 +181,35 @@ mod tests {
         assert_eq!(dsn.original, raw.to_string());
         assert_eq!(dsn.host, "localhost".to_string());
         assert_eq!(dsn.port, Some(14317));
+        assert_eq!(dsn.grpc_port, None);
         assert_eq!(dsn.scheme, "http".to_string());
         assert_eq!(dsn.token, "project1_secret".to_string());
         assert_eq!(dsn.project_id, "1".to_string());
     }
 
+    #[test]
+    fn debug_masks_token() {
+        let dsn = Dsn::try_from("http://project1_secret@localhost:14317/1".to_string()).unwrap();
+        let debug = format!("{:?}", dsn);
+        assert!(!debug.contains("project1_secret"), "{}", debug);
+        assert!(debug.contains(r#"token: "[REDACTED]""#), "{}", debug);
+        assert!(debug.contains(r#"project_id: "1""#), "{}", debug);
+    }
+
+    #[test]
+    fn display_masks_token() {
+        let dsn = Dsn::try_from("http://project1_secret@localhost:14317/1".to_string()).unwrap();
+        assert_eq!(dsn.to_string(), "http://[REDACTED]@localhost:14317/1");
+
+        let err = Dsn::try_from("http://project1_secret@localhost:14317".to_string()).unwrap_err();
+        assert!(!err.to_string().contains("project1_secret"), "{}", err);
+        assert_eq!(
+            mask_token("project1_secret@localhost:14317/1"),
+            "[REDACTED]@localhost:14317/1"
+        );
+        assert_eq!(mask_token("http://localhost/a@b"), "http://localhost/a@b");
+    }
+
     #[test]
     fn invalid_dsn() {
         let dsn = vec![
@@ -168,6 +235,7 @@ mod tests {
                 "demo-api.uptrace.dev:4317",
             ),
             ("http://token@localhost:14317/project_id", "localhost:14317"),
+            ("http://token@localhost:14318?grpc=14317", "localhost:14317"),
             (
                 "https://key@uptrace.dev/project_id",
                 "otlp.uptrace.dev:4317",
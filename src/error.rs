@@ -1,13 +1,126 @@
 use std::error::Error as StdError;
+use std::path::PathBuf;
 
+/// Boxed error that can be sent across threads, e.g. the cause of a failed exporter build.
+pub type BoxError = Box<dyn StdError + Send + Sync + 'static>;
+
+/// Errors returned by the crate.
+///
+/// Variants wrapping a cause don't repeat it in their message; it is returned
+/// by [`source`](StdError::source), so error reporters such as `anyhow` print
+/// each cause once.
 #[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
 pub enum Error {
     #[error("DSN is empty (use WithDSN or UPTRACE_DSN env var)")]
     EmptyDsn,
     #[error("invalid dsn: {}, reason: {}", .dsn, .reason)]
     InvalidDsn { dsn: String, reason: String },
-    #[error("trace build error: {}", 0)]
-    TraceBuildError(Box<dyn StdError>),
-    #[error("metrics build error: {}", 0)]
-    MetricsBuildError(Box<dyn StdError>),
+    #[error("trace build error")]
+    TraceBuildError(#[source] BoxError),
+    #[error("metrics build error")]
+    MetricsBuildError(#[source] BoxError),
+    #[error("logs build error")]
+    LogsBuildError(#[source] BoxError),
+    #[error("TLS configuration error")]
+    Tls(#[source] BoxError),
+    #[error("resource detection error")]
+    ResourceDetection(#[source] BoxError),
+    #[error("runtime error")]
+    Runtime(#[source] BoxError),
+    #[error("shutdown error")]
+    Shutdown(#[source] BoxError),
+    #[error("tracing subscriber error")]
+    TracingSubscriber(#[source] BoxError),
+    /// The configuration file can't be read, can't be parsed or has an
+    /// invalid value; `key` is the path of the offending key, e.g.
+    /// `traces.sampler.ratio`, when it is known.
+    #[error("invalid config file {}: {}{}", .path.display(), key_prefix(.key), .reason)]
+    InvalidConfigFile {
+        path: PathBuf,
+        key: Option<String>,
+        reason: String,
+    },
+}
+
+fn key_prefix(key: &Option<String>) -> String {
+    key.as_ref()
+        .map(|key| format!("{key}: "))
+        .unwrap_or_default()
+}
+
+/// Broad category of an [`Error`], see [`Error::kind`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// The DSN or other configuration is missing, malformed or conflicts with
+    /// the process state, e.g. a global subscriber is already set.
+    Config,
+    /// A trace, metrics or logs pipeline could not be built.
+    Build,
+    /// TLS could not be configured for the exporter.
+    Tls,
+    /// The resource describing the service could not be detected, e.g.
+    /// `OTEL_RESOURCE_ATTRIBUTES` is malformed.
+    ResourceDetection,
+    /// The async runtime required by the exporters is not available.
+    Runtime,
+    /// Flushing or shutting down a provider failed.
+    Shutdown,
+}
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::EmptyDsn
+            | Error::InvalidDsn { .. }
+            | Error::InvalidConfigFile { .. }
+            | Error::TracingSubscriber(_) => ErrorKind::Config,
+            Error::TraceBuildError(_) | Error::MetricsBuildError(_) | Error::LogsBuildError(_) => {
+                ErrorKind::Build
+            }
+            Error::Tls(_) => ErrorKind::Tls,
+            Error::ResourceDetection(_) => ErrorKind::ResourceDetection,
+            Error::Runtime(_) => ErrorKind::Runtime,
+            Error::Shutdown(_) => ErrorKind::Shutdown,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error as StdError;
+
+    use super::{Error, ErrorKind};
+
+    fn assert_send_sync<T: Send + Sync + 'static>() {}
+
+    #[test]
+    fn send_sync() {
+        assert_send_sync::<Error>();
+    }
+
+    #[test]
+    fn source_chain() {
+        let cause = std::io::Error::other("connection refused");
+        let err = Error::TraceBuildError(Box::new(cause));
+
+        assert_eq!(err.to_string(), "trace build error");
+        assert_eq!(err.kind(), ErrorKind::Build);
+        assert_eq!(err.source().unwrap().to_string(), "connection refused");
+    }
+
+    #[test]
+    fn cause_printed_once() {
+        let cause = std::io::Error::other("handshake failed");
+        let err = Error::Tls(Box::new(cause));
+
+        let mut chain = vec![err.to_string()];
+        let mut source = err.source();
+        while let Some(err) = source {
+            chain.push(err.to_string());
+            source = err.source();
+        }
+        assert_eq!(chain, ["TLS configuration error", "handshake failed"]);
+    }
 }
@@ -0,0 +1,346 @@
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Once, RwLock};
+use std::time::{Duration, Instant};
+
+use tracing::field::{Field, Visit};
+use tracing::{Level, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::Layer;
+
+/// Target of the `tracing` events emitted by [`tracing_handler`].
+///
+/// Layers that export `tracing` data to Uptrace should filter this target out,
+/// otherwise a failing exporter keeps reporting its own failures.
+pub const INTERNAL_TARGET: &str = "uptrace::internal";
+
+/// Default minimum interval between two reported errors.
+pub(crate) const DEFAULT_RATE_LIMIT: Duration = Duration::from_secs(10);
+
+/// Error reported by the OpenTelemetry SDK, e.g. a failed export, or by this crate,
+/// e.g. an invalid env var.
+///
+/// The SDK reports errors as `tracing` warnings and errors, which the [`ErrorLayer`]
+/// converts to this type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OtelError {
+    /// Target of the event, e.g. `opentelemetry_sdk`.
+    pub target: Cow<'static, str>,
+    /// Name of the event, e.g. `BatchSpanProcessor.ExportError`.
+    pub name: Cow<'static, str>,
+    /// Message followed by the other fields of the event.
+    pub message: String,
+}
+
+impl OtelError {
+    pub(crate) fn internal(name: &'static str, message: String) -> Self {
+        Self {
+            target: Cow::Borrowed(env!("CARGO_PKG_NAME")),
+            name: Cow::Borrowed(name),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for OtelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.message.is_empty() {
+            f.write_str(&self.name)
+        } else {
+            write!(f, "{}: {}", self.name, self.message)
+        }
+    }
+}
+
+impl std::error::Error for OtelError {}
+
+/// Callback that receives errors reported by the OpenTelemetry SDK,
+/// e.g. failed exports.
+pub type ErrorHandler = Arc<dyn Fn(&OtelError) + Send + Sync>;
+
+/// Writes the error to stderr.
+pub fn stderr_handler(err: &OtelError) {
+    eprintln!("OpenTelemetry error occurred. {}", err);
+}
+
+/// Emits the error as a `tracing` event with the [`INTERNAL_TARGET`] target.
+pub fn tracing_handler(err: &OtelError) {
+    tracing::error!(target: INTERNAL_TARGET, error = %err, "OpenTelemetry error occurred");
+}
+
+/// Handler installed by [`Uptrace::install_global`](crate::Uptrace::install_global).
+static GLOBAL_HANDLER: RwLock<Option<Arc<RateLimitedHandler>>> = RwLock::new(None);
+
+pub(crate) fn set_global_handler(handler: Arc<RateLimitedHandler>) {
+    if let Ok(mut global) = GLOBAL_HANDLER.write() {
+        *global = Some(handler);
+    }
+}
+
+/// Reports an error of this crate to the global handler, or to stderr if
+/// none is installed yet.
+pub(crate) fn handle_error(err: OtelError) {
+    let handler = GLOBAL_HANDLER.read().ok().and_then(|global| global.clone());
+    match handler {
+        Some(handler) => handler.handle(&err),
+        None => stderr_handler(&err),
+    }
+}
+
+/// Set once an [`ErrorLayer`] is added to a `tracing` subscriber.
+static LAYER_ADDED: AtomicBool = AtomicBool::new(false);
+
+static LAYER_MISSING: Once = Once::new();
+
+/// Reports, once per process, that no [`ErrorLayer`] was added to a `tracing`
+/// subscriber, so the errors reported by the SDK never reach the handler.
+pub(crate) fn check_layer_added() {
+    if LAYER_ADDED.load(Ordering::Relaxed) {
+        return;
+    }
+    LAYER_MISSING.call_once(|| {
+        handle_error(OtelError::internal(
+            "ErrorHandler.LayerMissing",
+            "Uptrace::error_layer was not added to a tracing subscriber, \
+             so errors reported by the OpenTelemetry SDK are not handled"
+                .into(),
+        ))
+    });
+}
+
+/// Returns whether the target belongs to one of the OpenTelemetry crates,
+/// which report their internal errors as `tracing` events.
+pub(crate) fn is_sdk_target(target: &str) -> bool {
+    target.starts_with("opentelemetry")
+}
+
+/// `tracing` layer that passes warnings and errors emitted by the OpenTelemetry
+/// crates to the error handler, see
+/// [`UptraceBuilder::with_error_handler`](crate::UptraceBuilder::with_error_handler).
+///
+/// Returned by [`Uptrace::error_layer`](crate::Uptrace::error_layer). If the
+/// layer is never added to a subscriber, the handler is told so once, when
+/// Uptrace is installed after the global subscriber or when it is flushed or
+/// shut down.
+#[derive(Clone)]
+pub struct ErrorLayer {
+    handler: Arc<RateLimitedHandler>,
+}
+
+impl ErrorLayer {
+    pub(crate) fn new(handler: Arc<RateLimitedHandler>) -> Self {
+        Self { handler }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for ErrorLayer {
+    fn on_layer(&mut self, _subscriber: &mut S) {
+        LAYER_ADDED.store(true, Ordering::Relaxed);
+    }
+
+    fn on_event(&self, event: &tracing::Event<'_>, _cx: Context<'_, S>) {
+        let meta = event.metadata();
+        if *meta.level() > Level::WARN || !is_sdk_target(meta.target()) {
+            return;
+        }
+
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+        self.handler.handle(&OtelError {
+            target: Cow::Borrowed(meta.target()),
+            name: Cow::Borrowed(meta.name()),
+            message: visitor.message,
+        });
+    }
+}
+
+/// Joins the event message and the other fields, skipping the `name` field
+/// that the OpenTelemetry macros duplicate from the metadata.
+#[derive(Default)]
+struct MessageVisitor {
+    message: String,
+}
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        use std::fmt::Write;
+
+        match field.name() {
+            "name" => {}
+            "message" => {
+                let value = format!("{:?}", value);
+                if !value.is_empty() {
+                    self.push_separator();
+                    self.message.push_str(&value);
+                }
+            }
+            name => {
+                self.push_separator();
+                let _ = write!(self.message, "{}={:?}", name, value);
+            }
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record_debug(field, &format_args!("{}", value));
+    }
+}
+
+impl MessageVisitor {
+    fn push_separator(&mut self) {
+        if !self.message.is_empty() {
+            self.message.push_str(", ");
+        }
+    }
+}
+
+thread_local! {
+    static HANDLING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Wraps an [`ErrorHandler`] so that it is called at most once per `interval`
+/// and never re-entered from the thread that is already handling an error.
+pub(crate) struct RateLimitedHandler {
+    handler: ErrorHandler,
+    interval: Duration,
+    last: Mutex<Option<Instant>>,
+}
+
+impl RateLimitedHandler {
+    pub(crate) fn new(handler: ErrorHandler, interval: Duration) -> Self {
+        Self {
+            handler,
+            interval,
+            last: Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn handle(&self, err: &OtelError) {
+        if HANDLING.with(|handling| handling.replace(true)) {
+            return;
+        }
+
+        if self.allow() {
+            (self.handler)(err);
+        }
+
+        HANDLING.with(|handling| handling.set(false));
+    }
+
+    fn allow(&self) -> bool {
+        if self.interval.is_zero() {
+            return true;
+        }
+
+        let mut last = match self.last.lock() {
+            Ok(last) => last,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let now = Instant::now();
+        match *last {
+            Some(at) if now.duration_since(at) < self.interval => false,
+            _ => {
+                *last = Some(now);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use std::sync::Mutex;
+
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::{ErrorLayer, OtelError, RateLimitedHandler, LAYER_ADDED};
+
+    fn counting_handler(interval: Duration) -> (RateLimitedHandler, Arc<AtomicUsize>) {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let handler = RateLimitedHandler::new(
+            Arc::new(move |_: &OtelError| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }),
+            interval,
+        );
+        (handler, count)
+    }
+
+    #[test]
+    fn rate_limited() {
+        let (handler, count) = counting_handler(Duration::from_secs(60));
+        for _ in 0..10 {
+            handler.handle(&OtelError::internal("Test", "export failed".into()));
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn zero_interval_disables_rate_limit() {
+        let (handler, count) = counting_handler(Duration::ZERO);
+        for _ in 0..10 {
+            handler.handle(&OtelError::internal("Test", "export failed".into()));
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 10);
+    }
+
+    #[test]
+    fn not_reentrant() {
+        let inner = Arc::new(AtomicUsize::new(0));
+        let counter = inner.clone();
+        let handler = Arc::new(std::sync::OnceLock::<RateLimitedHandler>::new());
+        let nested = handler.clone();
+        let _ = handler.set(RateLimitedHandler::new(
+            Arc::new(move |err: &OtelError| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                nested.get().unwrap().handle(err);
+            }),
+            Duration::ZERO,
+        ));
+
+        handler
+            .get()
+            .unwrap()
+            .handle(&OtelError::internal("Test", "export failed".into()));
+        assert_eq!(inner.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn error_layer() {
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let sink = errors.clone();
+        let handler = RateLimitedHandler::new(
+            Arc::new(move |err: &OtelError| sink.lock().unwrap().push(err.clone())),
+            Duration::ZERO,
+        );
+        let subscriber = tracing_subscriber::registry().with(ErrorLayer::new(Arc::new(handler)));
+        assert!(LAYER_ADDED.load(Ordering::Relaxed));
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::error!(
+                name: "BatchSpanProcessor.ExportError",
+                target: "opentelemetry_sdk",
+                name = "BatchSpanProcessor.ExportError",
+                error = "connection refused",
+                ""
+            );
+            tracing::info!(name: "Started", target: "opentelemetry_sdk", "");
+            tracing::error!(target: "myapp", "not an SDK error");
+        });
+
+        let errors = errors.lock().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].target, "opentelemetry_sdk");
+        assert_eq!(
+            errors[0].to_string(),
+            "BatchSpanProcessor.ExportError: error=connection refused"
+        );
+    }
+}
@@ -0,0 +1,306 @@
+//! Recording errors on spans with the exception semantic conventions.
+//!
+//! ```
+//! use opentelemetry::trace::TraceContextExt;
+//! use opentelemetry::Context;
+//! use uptrace::{ResultExt, SpanExt};
+//!
+//! fn read_config() -> Result<String, std::io::Error> {
+//!     // Records the error on the current span before returning it.
+//!     let config = std::fs::read_to_string("config.yaml").record_err()?;
+//!     Ok(config)
+//! }
+//!
+//! if let Err(err) = read_config() {
+//!     Context::current().span().record_exception(&err);
+//! }
+//! ```
+
+use std::backtrace::{Backtrace, BacktraceStatus};
+use std::error::Error as StdError;
+use std::fmt::{self, Write};
+
+use opentelemetry::global::BoxedSpan;
+use opentelemetry::trace::{Span, SpanRef, Status, TraceContextExt};
+use opentelemetry::{Context, KeyValue};
+use opentelemetry_sdk::trace::Span as SdkSpan;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Extension for spans to record errors as `exception` events.
+pub trait SpanExt {
+    /// Adds an `exception` event with `exception.type`, `exception.message`
+    /// and `exception.stacktrace` attributes and sets the span status to error.
+    ///
+    /// The stacktrace lists the error and its `source()` chain, followed by a
+    /// backtrace of the caller if backtraces are enabled with `RUST_BACKTRACE`.
+    ///
+    /// Unlike `Span::record_error` from the OpenTelemetry API, this also
+    /// records the type, the `source()` chain and the span status.
+    fn record_exception<E: StdError + ?Sized>(&mut self, err: &E) {
+        self.record_exception_with_type(&exception_type(err), err)
+    }
+
+    /// Like [`record_exception`](SpanExt::record_exception) with the given
+    /// `exception.type`, e.g. for a `&dyn Error` whose type is known to the
+    /// caller.
+    fn record_exception_with_type<E: StdError + ?Sized>(&mut self, type_name: &str, err: &E);
+}
+
+impl SpanExt for SpanRef<'_> {
+    fn record_exception_with_type<E: StdError + ?Sized>(&mut self, type_name: &str, err: &E) {
+        let (message, attrs) = exception_attributes_with_type(type_name, err);
+        self.add_event("exception", attrs);
+        self.set_status(Status::error(message));
+    }
+}
+
+impl SpanExt for SdkSpan {
+    fn record_exception_with_type<E: StdError + ?Sized>(&mut self, type_name: &str, err: &E) {
+        let (message, attrs) = exception_attributes_with_type(type_name, err);
+        self.add_event("exception", attrs);
+        self.set_status(Status::error(message));
+    }
+}
+
+impl SpanExt for BoxedSpan {
+    fn record_exception_with_type<E: StdError + ?Sized>(&mut self, type_name: &str, err: &E) {
+        let (message, attrs) = exception_attributes_with_type(type_name, err);
+        self.add_event("exception", attrs);
+        self.set_status(Status::error(message));
+    }
+}
+
+/// Records the error on the OpenTelemetry span backing a `tracing` span
+/// exported by [`Uptrace::tracing_layer`](crate::Uptrace::tracing_layer).
+impl SpanExt for tracing::Span {
+    fn record_exception_with_type<E: StdError + ?Sized>(&mut self, type_name: &str, err: &E) {
+        let (message, attrs) = exception_attributes_with_type(type_name, err);
+        OpenTelemetrySpanExt::add_event(self, "exception", attrs);
+        OpenTelemetrySpanExt::set_status(self, Status::error(message));
+    }
+}
+
+/// Error known only by its `Display` output, such as the error of a wrapped
+/// service, so that it can be recorded with [`SpanExt`].
+pub(crate) struct DisplayError<'a, T: ?Sized>(pub(crate) &'a T);
+
+impl<T: fmt::Display + ?Sized> fmt::Debug for DisplayError<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self.0, f)
+    }
+}
+
+impl<T: fmt::Display + ?Sized> fmt::Display for DisplayError<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self.0, f)
+    }
+}
+
+impl<T: fmt::Display + ?Sized> StdError for DisplayError<'_, T> {}
+
+/// Records an error that only implements `Display`, with its type name as
+/// the `exception.type`.
+pub(crate) fn record_display_error<S, E>(span: &mut S, err: &E)
+where
+    S: SpanExt + ?Sized,
+    E: fmt::Display + ?Sized,
+{
+    span.record_exception_with_type(std::any::type_name::<E>(), &DisplayError(err))
+}
+
+/// Extension for results to record errors on the current span.
+pub trait ResultExt<T, E> {
+    /// Records the error, if any, on the active OpenTelemetry span or,
+    /// without one, on the current `tracing` span, and returns the result unchanged.
+    fn record_err(self) -> Result<T, E>;
+}
+
+impl<T, E: StdError> ResultExt<T, E> for Result<T, E> {
+    fn record_err(self) -> Result<T, E> {
+        if let Err(err) = &self {
+            let cx = Context::current();
+            if cx.has_active_span() {
+                cx.span().record_exception(err);
+            } else {
+                tracing::Span::current().record_exception(err);
+            }
+        }
+        self
+    }
+}
+
+/// Returns the error message and the `exception.*` attributes for the error.
+///
+/// `exception.type` is the Rust type name of the error, which Uptrace uses
+/// together with the message to group errors. The concrete type of a trait
+/// object such as `&dyn Error` isn't known, so its `exception.type` is the
+/// type name its `Debug` output starts with, e.g. `QueryError` for
+/// `QueryError(..)`; use [`exception_attributes_with_type`] if the caller
+/// knows the type.
+pub fn exception_attributes<E: StdError + ?Sized>(err: &E) -> (String, Vec<KeyValue>) {
+    exception_attributes_with_type(&exception_type(err), err)
+}
+
+/// Like [`exception_attributes`] with the given `exception.type`.
+pub fn exception_attributes_with_type<E: StdError + ?Sized>(
+    type_name: &str,
+    err: &E,
+) -> (String, Vec<KeyValue>) {
+    let message = err.to_string();
+
+    let mut stacktrace = message.clone();
+    let mut source = err.source();
+    if source.is_some() {
+        stacktrace.push_str("\n\nCaused by:");
+    }
+    let mut i = 0;
+    while let Some(err) = source {
+        let _ = write!(stacktrace, "\n    {}: {}", i, err);
+        source = err.source();
+        i += 1;
+    }
+    let backtrace = Backtrace::capture();
+    if backtrace.status() == BacktraceStatus::Captured {
+        let _ = write!(stacktrace, "\n\nStack backtrace:\n{}", backtrace);
+    }
+
+    let attrs = vec![
+        KeyValue::new("exception.type", type_name.to_string()),
+        KeyValue::new("exception.message", message.clone()),
+        KeyValue::new("exception.stacktrace", stacktrace),
+    ];
+    (message, attrs)
+}
+
+/// Returns the type name of the error, or for a trait object the type name its
+/// `Debug` output starts with, if any.
+fn exception_type<E: StdError + ?Sized>(err: &E) -> String {
+    let type_name = std::any::type_name::<E>();
+    if !type_name.starts_with("dyn ") {
+        return type_name.to_string();
+    }
+
+    let debug = format!("{:?}", err);
+    let end = debug
+        .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == ':'))
+        .unwrap_or(debug.len());
+    let name = &debug[..end];
+    if name.starts_with(|c: char| c.is_ascii_uppercase()) {
+        name.to_string()
+    } else {
+        type_name.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt;
+
+    use opentelemetry::trace::{Status, TraceContextExt, Tracer, TracerProvider as _};
+    use opentelemetry::{Context, Key};
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::{exception_attributes, exception_attributes_with_type, ResultExt};
+    use crate::testing::Collect;
+
+    #[derive(Debug)]
+    struct QueryError(std::io::Error);
+
+    impl fmt::Display for QueryError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("query failed")
+        }
+    }
+
+    impl std::error::Error for QueryError {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    fn query_error() -> QueryError {
+        QueryError(std::io::Error::other("connection reset"))
+    }
+
+    fn attr(attrs: &[opentelemetry::KeyValue], key: &'static str) -> String {
+        let key = Key::new(key);
+        attrs
+            .iter()
+            .find(|kv| kv.key == key)
+            .unwrap()
+            .value
+            .as_str()
+            .into_owned()
+    }
+
+    #[test]
+    fn attributes() {
+        let (message, attrs) = exception_attributes(&query_error());
+
+        assert_eq!(message, "query failed");
+        assert!(attr(&attrs, "exception.type").ends_with("tests::QueryError"));
+        assert_eq!(attr(&attrs, "exception.message"), "query failed");
+        assert!(attr(&attrs, "exception.stacktrace")
+            .starts_with("query failed\n\nCaused by:\n    0: connection reset"));
+    }
+
+    #[test]
+    fn dyn_error() {
+        let err: Box<dyn std::error::Error + Send + Sync> = Box::new(query_error());
+        let (_, attrs) = exception_attributes(&*err);
+        assert_eq!(attr(&attrs, "exception.type"), "QueryError");
+
+        let err: &dyn std::error::Error = &std::fmt::Error;
+        let (_, attrs) = exception_attributes(err);
+        assert_eq!(attr(&attrs, "exception.type"), "Error");
+
+        let (_, attrs) = exception_attributes_with_type("std::io::Error", err);
+        assert_eq!(attr(&attrs, "exception.type"), "std::io::Error");
+    }
+
+    #[test]
+    fn record_err() {
+        let spans = Collect::default();
+        let provider = SdkTracerProvider::builder()
+            .with_span_processor(spans.clone())
+            .build();
+        let tracer = provider.tracer("test");
+
+        let cx = Context::current_with_span(tracer.start("query"));
+        {
+            let _guard = cx.clone().attach();
+            let result: Result<(), _> = Err(query_error());
+            assert!(result.record_err().is_err());
+        }
+        cx.span().end();
+
+        let spans = spans.spans();
+        assert_eq!(spans[0].status, Status::error("query failed"));
+        let event = spans[0].events.iter().next().unwrap();
+        assert_eq!(event.name, "exception");
+    }
+
+    #[test]
+    fn tracing_span() {
+        let spans = Collect::default();
+        let provider = SdkTracerProvider::builder()
+            .with_span_processor(spans.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("query").in_scope(|| {
+                let result: Result<(), _> = Err(query_error());
+                assert!(result.record_err().is_err());
+            });
+        });
+
+        let spans = spans.spans();
+        assert_eq!(spans[0].name, "query");
+        assert_eq!(spans[0].status, Status::error("query failed"));
+        let event = spans[0].events.iter().next().unwrap();
+        assert_eq!(event.name, "exception");
+    }
+}
@@ -0,0 +1,715 @@
+//! Exemplars linking the histogram data points exported to Uptrace with the
+//! traces that were active when the measurements were recorded.
+//!
+//! The SDK doesn't record exemplars yet, so the crate's meter provider samples
+//! the measurements of histograms into per-series [`Reservoirs`], and
+//! [`MetricExporter`] attaches the samples to the data points when they are
+//! exported over OTLP. Each data point carries the exemplars of the largest
+//! measurements recorded since the previous export.
+//!
+//! Exemplars are off by default. Enabling them replaces the SDK's OTLP metric
+//! exporter with [`MetricExporter`], which compresses requests like the SDK's
+//! exporter and retries the exports that fail with a transient error.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use opentelemetry::metrics::{Histogram, SyncInstrument};
+use opentelemetry::trace::{SpanContext, SpanId, TraceContextExt, TraceId};
+use opentelemetry::{Context, InstrumentationScope, KeyValue};
+use opentelemetry_otlp::{
+    Compression, OTEL_EXPORTER_OTLP_COMPRESSION, OTEL_EXPORTER_OTLP_METRICS_COMPRESSION,
+};
+use opentelemetry_proto::tonic::collector::metrics::v1::metrics_service_client::MetricsServiceClient;
+use opentelemetry_proto::tonic::collector::metrics::v1::ExportMetricsServiceRequest;
+use opentelemetry_proto::tonic::common::v1::KeyValue as ProtoKeyValue;
+use opentelemetry_proto::tonic::metrics::v1::{exemplar, metric, Exemplar};
+use opentelemetry_proto::transform::common::tonic::Attributes;
+use opentelemetry_sdk::error::{OTelSdkError, OTelSdkResult};
+use opentelemetry_sdk::metrics::data::ResourceMetrics;
+use opentelemetry_sdk::metrics::exporter::PushMetricExporter;
+use opentelemetry_sdk::metrics::{InstrumentKind, Temporality};
+use tokio::runtime::Handle;
+use tonic::codec::CompressionEncoding;
+use tonic::metadata::MetadataMap;
+use tonic::transport::Channel;
+use tonic::Code;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::metrics::MetricsConfig;
+use crate::Error;
+
+/// Env var selecting the exemplar filter: `always_on`, `always_off` or `trace_based`.
+pub const OTEL_METRICS_EXEMPLAR_FILTER: &str = "OTEL_METRICS_EXEMPLAR_FILTER";
+
+/// Maximum number of exemplars attached to a data point.
+const MAX_EXEMPLARS: usize = 4;
+/// Maximum number of attribute sets sampled per instrument between two exports.
+const MAX_SERIES: usize = 2000;
+/// Maximum number of attempts to send an export.
+const MAX_ATTEMPTS: u32 = 4;
+/// Delay before the first retry, doubled for every following one.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Selects the histogram measurements that are sampled as exemplars.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ExemplarFilter {
+    /// Sample all measurements, with the trace and span IDs when a span is active.
+    AlwaysOn,
+    /// Sample the measurements recorded inside a sampled span.
+    TraceBased,
+    /// Don't record exemplars.
+    #[default]
+    AlwaysOff,
+}
+
+impl ExemplarFilter {
+    /// Returns the filter set with `OTEL_METRICS_EXEMPLAR_FILTER`, or
+    /// [`ExemplarFilter::AlwaysOff`] if it's unset or invalid.
+    pub fn from_env() -> Self {
+        std::env::var(OTEL_METRICS_EXEMPLAR_FILTER)
+            .ok()
+            .and_then(|value| Self::parse(&value))
+            .unwrap_or_default()
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "always_on" => Some(Self::AlwaysOn),
+            "trace_based" => Some(Self::TraceBased),
+            "always_off" => Some(Self::AlwaysOff),
+            _ => None,
+        }
+    }
+
+    /// Returns the trace and span IDs of the exemplar, invalid if no span is
+    /// active, or `None` if the measurement isn't sampled.
+    fn sample(self) -> Option<(TraceId, SpanId)> {
+        let ids = |cx: &SpanContext| (cx.trace_id(), cx.span_id());
+        match self {
+            Self::AlwaysOff => None,
+            Self::TraceBased => {
+                let cx = current_span_context();
+                cx.is_sampled().then(|| ids(&cx))
+            }
+            Self::AlwaysOn => Some(ids(&current_span_context())),
+        }
+    }
+}
+
+/// Returns the active OpenTelemetry span, or the span of the current
+/// `tracing` span when the former is not set.
+fn current_span_context() -> SpanContext {
+    let cx = Context::current().span().span_context().clone();
+    if cx.is_valid() {
+        return cx;
+    }
+    tracing::Span::current()
+        .context()
+        .span()
+        .span_context()
+        .clone()
+}
+
+#[derive(Debug, Clone)]
+struct Sample {
+    value: exemplar::Value,
+    time_unix_nano: u64,
+    trace_id: TraceId,
+    span_id: SpanId,
+    attributes: Vec<KeyValue>,
+}
+
+impl Sample {
+    fn value(&self) -> f64 {
+        Self::value_of(&self.value)
+    }
+
+    fn value_of(value: &exemplar::Value) -> f64 {
+        match *value {
+            exemplar::Value::AsDouble(value) => value,
+            exemplar::Value::AsInt(value) => value as f64,
+        }
+    }
+}
+
+/// Samples of one histogram, keyed by the hash of the attribute set.
+#[derive(Debug, Default)]
+struct Reservoir {
+    series: Mutex<HashMap<u64, Vec<Sample>>>,
+}
+
+impl Reservoir {
+    /// Keeps the measurement if it's one of the largest of its series.
+    fn offer(&self, value: exemplar::Value, attributes: &[KeyValue], ids: (TraceId, SpanId)) {
+        let key = series_key(attributes);
+        let mut series = self.series.lock().unwrap_or_else(|e| e.into_inner());
+        if !series.contains_key(&key) && series.len() >= MAX_SERIES {
+            return;
+        }
+        let samples = series.entry(key).or_default();
+        let slot = if samples.len() < MAX_EXEMPLARS {
+            samples.len()
+        } else {
+            let value = Sample::value_of(&value);
+            match samples
+                .iter()
+                .enumerate()
+                .min_by(|(_, a), (_, b)| a.value().total_cmp(&b.value()))
+            {
+                Some((i, min)) if value > min.value() => i,
+                _ => return,
+            }
+        };
+        let sample = Sample {
+            value,
+            time_unix_nano: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos() as u64),
+            trace_id: ids.0,
+            span_id: ids.1,
+            attributes: attributes.to_vec(),
+        };
+        if slot == samples.len() {
+            samples.push(sample);
+        } else {
+            samples[slot] = sample;
+        }
+    }
+
+    fn drain(&self) -> Vec<Sample> {
+        let mut series = self.series.lock().unwrap_or_else(|e| e.into_inner());
+        series.drain().flat_map(|(_, samples)| samples).collect()
+    }
+}
+
+/// Returns a hash of the attribute set that doesn't depend on the order of
+/// the attributes, without allocating. Series whose hashes collide share
+/// their samples, which are matched to data points by attributes anyway.
+fn series_key(attributes: &[KeyValue]) -> u64 {
+    attributes.iter().fold(0, |key: u64, kv| {
+        let mut hasher = DefaultHasher::new();
+        kv.hash(&mut hasher);
+        key.wrapping_add(hasher.finish())
+    })
+}
+
+/// Instrumentation scope name and version, and name of the exported stream.
+type InstrumentKey = (String, String, String);
+
+/// Reservoirs of all the histograms created through the crate's meter provider.
+#[derive(Debug)]
+pub(crate) struct Reservoirs {
+    filter: ExemplarFilter,
+    config: MetricsConfig,
+    instruments: Mutex<HashMap<InstrumentKey, Arc<Reservoir>>>,
+}
+
+impl Reservoirs {
+    pub(crate) fn new(filter: ExemplarFilter, config: &MetricsConfig) -> Self {
+        Self {
+            filter,
+            config: config.clone(),
+            instruments: Mutex::default(),
+        }
+    }
+
+    /// Wraps the SDK histogram of the given scope and name to sample its
+    /// measurements.
+    ///
+    /// The reservoir is keyed by the stream name the views give the histogram,
+    /// which is the metric name of the exported data points.
+    pub(crate) fn histogram<T: ExemplarValue>(
+        &self,
+        scope: &InstrumentationScope,
+        name: &str,
+        inner: Histogram<T>,
+    ) -> Histogram<T> {
+        let key = (
+            scope.name().to_string(),
+            scope.version().unwrap_or_default().to_string(),
+            self.config
+                .stream_name(name, InstrumentKind::Histogram)
+                .to_string(),
+        );
+        let mut instruments = self.instruments.lock().unwrap_or_else(|e| e.into_inner());
+        let reservoir = instruments.entry(key).or_default().clone();
+        Histogram::new(Arc::new(ExemplarHistogram {
+            inner,
+            filter: self.filter,
+            reservoir,
+        }))
+    }
+
+    fn get(&self, key: &InstrumentKey) -> Option<Arc<Reservoir>> {
+        let instruments = self.instruments.lock().unwrap_or_else(|e| e.into_inner());
+        instruments.get(key).cloned()
+    }
+
+    /// Moves the samples recorded since the previous export to the histogram
+    /// data points whose attributes they match.
+    pub(crate) fn attach(&self, request: &mut ExportMetricsServiceRequest) {
+        for scope_metrics in request
+            .resource_metrics
+            .iter_mut()
+            .flat_map(|rm| &mut rm.scope_metrics)
+        {
+            let (scope, version) = scope_metrics
+                .scope
+                .as_ref()
+                .map_or(("", ""), |s| (s.name.as_str(), s.version.as_str()));
+            for metric in &mut scope_metrics.metrics {
+                let key = (scope.to_string(), version.to_string(), metric.name.clone());
+                let Some(reservoir) = self.get(&key) else {
+                    continue;
+                };
+                let samples: Vec<_> = reservoir
+                    .drain()
+                    .into_iter()
+                    .map(|sample| {
+                        let attributes = Attributes::from(sample.attributes.iter().cloned()).0;
+                        (sample, attributes)
+                    })
+                    .collect();
+                if samples.is_empty() {
+                    continue;
+                }
+                match &mut metric.data {
+                    Some(metric::Data::Histogram(histogram)) => {
+                        for point in &mut histogram.data_points {
+                            point.exemplars = exemplars(&samples, &point.attributes);
+                        }
+                    }
+                    Some(metric::Data::ExponentialHistogram(histogram)) => {
+                        for point in &mut histogram.data_points {
+                            point.exemplars = exemplars(&samples, &point.attributes);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// Returns the exemplars of the largest samples recorded with a superset of
+/// the data point attributes; the attributes removed by views are kept as
+/// filtered attributes.
+fn exemplars(samples: &[(Sample, Vec<ProtoKeyValue>)], point: &[ProtoKeyValue]) -> Vec<Exemplar> {
+    let mut matching: Vec<_> = samples
+        .iter()
+        .filter(|(_, attributes)| point.iter().all(|kv| attributes.contains(kv)))
+        .collect();
+    matching.sort_by(|(a, _), (b, _)| b.value().total_cmp(&a.value()));
+    matching
+        .into_iter()
+        .take(MAX_EXEMPLARS)
+        .map(|(sample, attributes)| Exemplar {
+            filtered_attributes: attributes
+                .iter()
+                .filter(|kv| !point.contains(kv))
+                .cloned()
+                .collect(),
+            time_unix_nano: sample.time_unix_nano,
+            span_id: if sample.span_id == SpanId::INVALID {
+                Vec::new()
+            } else {
+                sample.span_id.to_bytes().to_vec()
+            },
+            trace_id: if sample.trace_id == TraceId::INVALID {
+                Vec::new()
+            } else {
+                sample.trace_id.to_bytes().to_vec()
+            },
+            value: Some(sample.value),
+        })
+        .collect()
+}
+
+/// Histogram that offers its measurements to a reservoir before recording
+/// them with the SDK histogram.
+struct ExemplarHistogram<T> {
+    inner: Histogram<T>,
+    filter: ExemplarFilter,
+    reservoir: Arc<Reservoir>,
+}
+
+pub(crate) trait ExemplarValue: Copy + Send + Sync + 'static {
+    fn exemplar_value(self) -> exemplar::Value;
+}
+
+impl ExemplarValue for f64 {
+    fn exemplar_value(self) -> exemplar::Value {
+        exemplar::Value::AsDouble(self)
+    }
+}
+
+impl ExemplarValue for u64 {
+    fn exemplar_value(self) -> exemplar::Value {
+        exemplar::Value::AsInt(self as i64)
+    }
+}
+
+impl<T: ExemplarValue> SyncInstrument<T> for ExemplarHistogram<T> {
+    fn measure(&self, measurement: T, attributes: &[KeyValue]) {
+        self.inner.record(measurement, attributes);
+        if let Some(ids) = self.filter.sample() {
+            self.reservoir
+                .offer(measurement.exemplar_value(), attributes, ids);
+        }
+    }
+}
+
+/// OTLP metric exporter that attaches the exemplars of the [`Reservoirs`] to
+/// the exported histogram data points.
+///
+/// Requests are compressed with the encoding of
+/// `OTEL_EXPORTER_OTLP_METRICS_COMPRESSION` or `OTEL_EXPORTER_OTLP_COMPRESSION`,
+/// like the SDK's exporter, and sent on the runtime the exporter was built on.
+/// An export failing with a transient error is retried with an exponential
+/// backoff, up to [`MAX_ATTEMPTS`] times. `force_flush` has nothing to flush
+/// since every export is sent right away.
+pub(crate) struct MetricExporter {
+    client: MetricsServiceClient<Channel>,
+    metadata: MetadataMap,
+    temporality: Temporality,
+    reservoirs: Arc<Reservoirs>,
+    runtime: Handle,
+    is_shutdown: AtomicBool,
+}
+
+impl MetricExporter {
+    pub(crate) fn new(
+        channel: Channel,
+        metadata: MetadataMap,
+        compression: Option<CompressionEncoding>,
+        temporality: Temporality,
+        reservoirs: Arc<Reservoirs>,
+        runtime: Handle,
+    ) -> Self {
+        let mut client = MetricsServiceClient::new(channel);
+        if let Some(compression) = compression {
+            client = client
+                .send_compressed(compression)
+                .accept_compressed(compression);
+        }
+        Self {
+            client,
+            metadata,
+            temporality,
+            reservoirs,
+            runtime,
+            is_shutdown: AtomicBool::new(false),
+        }
+    }
+}
+
+/// Returns the compression of the metric exports set with
+/// `OTEL_EXPORTER_OTLP_METRICS_COMPRESSION` or `OTEL_EXPORTER_OTLP_COMPRESSION`.
+pub(crate) fn compression_from_env() -> Result<Option<CompressionEncoding>, Error> {
+    let value = std::env::var(OTEL_EXPORTER_OTLP_METRICS_COMPRESSION)
+        .or_else(|_| std::env::var(OTEL_EXPORTER_OTLP_COMPRESSION));
+    let Ok(value) = value else {
+        return Ok(None);
+    };
+    value
+        .parse::<Compression>()
+        .and_then(CompressionEncoding::try_from)
+        .map(Some)
+        .map_err(|e| Error::MetricsBuildError(Box::new(e)))
+}
+
+/// Returns whether an export failing with the code may succeed when retried,
+/// as listed by the OTLP specification.
+fn is_retryable(code: Code) -> bool {
+    matches!(
+        code,
+        Code::Cancelled
+            | Code::DeadlineExceeded
+            | Code::ResourceExhausted
+            | Code::Aborted
+            | Code::OutOfRange
+            | Code::Unavailable
+            | Code::DataLoss
+    )
+}
+
+async fn send(
+    client: MetricsServiceClient<Channel>,
+    metadata: MetadataMap,
+    request: ExportMetricsServiceRequest,
+) -> Result<(), tonic::Status> {
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        let mut req = tonic::Request::new(request.clone());
+        *req.metadata_mut() = metadata.clone();
+        match client.clone().export(req).await {
+            Ok(_) => return Ok(()),
+            Err(status) if attempt < MAX_ATTEMPTS && is_retryable(status.code()) => {
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(status) => return Err(status),
+        }
+    }
+}
+
+impl PushMetricExporter for MetricExporter {
+    async fn export(&self, metrics: &ResourceMetrics) -> OTelSdkResult {
+        if self.is_shutdown.load(Ordering::Relaxed) {
+            return Err(OTelSdkError::AlreadyShutdown);
+        }
+        let mut request = ExportMetricsServiceRequest::from(metrics);
+        self.reservoirs.attach(&mut request);
+
+        // The reader exports on its own thread, while the backoff timer
+        // needs the runtime.
+        self.runtime
+            .spawn(send(self.client.clone(), self.metadata.clone(), request))
+            .await
+            .map_err(|e| OTelSdkError::InternalFailure(e.to_string()))?
+            .map_err(|e| OTelSdkError::InternalFailure(format!("{e:?}")))
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        Ok(())
+    }
+
+    fn shutdown_with_timeout(&self, _timeout: Duration) -> OTelSdkResult {
+        self.is_shutdown.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn temporality(&self) -> Temporality {
+        self.temporality
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::metrics::MeterProvider as _;
+    use opentelemetry::trace::{Tracer, TracerProvider as _};
+    use opentelemetry_proto::tonic::collector::metrics::v1::metrics_service_server::{
+        MetricsService, MetricsServiceServer,
+    };
+    use opentelemetry_proto::tonic::collector::metrics::v1::ExportMetricsServiceResponse;
+    use opentelemetry_sdk::metrics::{InMemoryMetricExporter, PeriodicReader, SdkMeterProvider};
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use tonic::transport::server::TcpIncoming;
+
+    use super::*;
+    use crate::meter::MeterProvider;
+    use crate::metrics::{MetricsConfig, View};
+
+    fn collect(filter: ExemplarFilter, config: MetricsConfig) -> ExportMetricsServiceRequest {
+        let exporter = InMemoryMetricExporter::default();
+        let reservoirs = Arc::new(Reservoirs::new(filter, &config));
+        let builder = SdkMeterProvider::builder()
+            .with_reader(PeriodicReader::builder(exporter.clone()).build());
+        let provider = MeterProvider::new(
+            config.apply(builder).unwrap().build(),
+            Some(reservoirs.clone()),
+            None,
+        );
+
+        let histogram = provider.meter("test").f64_histogram("duration").build();
+        let attrs = [KeyValue::new("route", "/"), KeyValue::new("user", "42")];
+        histogram.record(1.0, &attrs);
+        let tracer_provider = SdkTracerProvider::builder().build();
+        let trace_id = tracer_provider.tracer("test").in_span("request", |cx| {
+            histogram.record(5.0, &attrs);
+            histogram.record(3.0, &attrs);
+            cx.span().span_context().trace_id()
+        });
+
+        provider.sdk_provider().force_flush().unwrap();
+        let metrics = exporter.get_finished_metrics().unwrap();
+        let mut request = ExportMetricsServiceRequest::from(&metrics[0]);
+        reservoirs.attach(&mut request);
+        for exemplar in point_exemplars(&request) {
+            if !exemplar.trace_id.is_empty() {
+                assert_eq!(exemplar.trace_id, trace_id.to_bytes());
+            }
+        }
+        request
+    }
+
+    fn point_exemplars(request: &ExportMetricsServiceRequest) -> Vec<&Exemplar> {
+        let metric = &request.resource_metrics[0].scope_metrics[0].metrics[0];
+        match &metric.data {
+            Some(metric::Data::Histogram(histogram)) => histogram
+                .data_points
+                .iter()
+                .flat_map(|point| &point.exemplars)
+                .collect(),
+            _ => panic!("not a histogram: {metric:?}"),
+        }
+    }
+
+    fn values(request: &ExportMetricsServiceRequest) -> Vec<exemplar::Value> {
+        point_exemplars(request)
+            .into_iter()
+            .filter_map(|exemplar| exemplar.value)
+            .collect()
+    }
+
+    #[test]
+    fn trace_based() {
+        let request = collect(ExemplarFilter::TraceBased, MetricsConfig::default());
+        assert_eq!(
+            values(&request),
+            [
+                exemplar::Value::AsDouble(5.0),
+                exemplar::Value::AsDouble(3.0)
+            ]
+        );
+        assert!(point_exemplars(&request)[0].filtered_attributes.is_empty());
+    }
+
+    #[test]
+    fn always_on() {
+        let request = collect(ExemplarFilter::AlwaysOn, MetricsConfig::default());
+        assert_eq!(values(&request).len(), 3);
+        let untraced = point_exemplars(&request)[2];
+        assert!(untraced.trace_id.is_empty() && untraced.span_id.is_empty());
+    }
+
+    #[test]
+    fn always_off() {
+        let request = collect(ExemplarFilter::AlwaysOff, MetricsConfig::default());
+        assert!(values(&request).is_empty());
+    }
+
+    #[test]
+    fn filtered_attributes() {
+        let config = MetricsConfig::default()
+            .with_view(View::new("duration").with_allowed_attribute_keys(["route"]));
+        let request = collect(ExemplarFilter::TraceBased, config);
+        let exemplars = point_exemplars(&request);
+        assert_eq!(exemplars.len(), 2);
+        let filtered: Vec<_> = exemplars[0]
+            .filtered_attributes
+            .iter()
+            .map(|kv| kv.key.as_str())
+            .collect();
+        assert_eq!(filtered, ["user"]);
+    }
+
+    #[test]
+    fn renamed_view() {
+        let config = MetricsConfig::default().with_view(View::new("duration").with_name("latency"));
+        let request = collect(ExemplarFilter::TraceBased, config);
+        let metric = &request.resource_metrics[0].scope_metrics[0].metrics[0];
+        assert_eq!(metric.name, "latency");
+        assert_eq!(values(&request).len(), 2);
+    }
+
+    /// Fails the first export with `Unavailable` and records the encoding of
+    /// every request.
+    #[derive(Default, Clone)]
+    struct FlakyService {
+        encodings: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[tonic::async_trait]
+    impl MetricsService for FlakyService {
+        async fn export(
+            &self,
+            request: tonic::Request<ExportMetricsServiceRequest>,
+        ) -> Result<tonic::Response<ExportMetricsServiceResponse>, tonic::Status> {
+            let encoding = request
+                .metadata()
+                .get("grpc-encoding")
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default();
+            let mut encodings = self.encodings.lock().unwrap();
+            encodings.push(encoding.to_string());
+            if encodings.len() == 1 {
+                return Err(tonic::Status::unavailable("try again"));
+            }
+            Ok(tonic::Response::new(ExportMetricsServiceResponse::default()))
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_compressed() {
+        let service = FlakyService::default();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(
+                    MetricsServiceServer::new(service.clone())
+                        .accept_compressed(CompressionEncoding::Gzip),
+                )
+                .serve_with_incoming(TcpIncoming::from(listener)),
+        );
+
+        let metrics = InMemoryMetricExporter::default();
+        let provider = SdkMeterProvider::builder()
+            .with_reader(PeriodicReader::builder(metrics.clone()).build())
+            .build();
+        provider
+            .meter("test")
+            .u64_counter("requests")
+            .build()
+            .add(1, &[]);
+        provider.force_flush().unwrap();
+
+        let exporter = MetricExporter::new(
+            Channel::from_shared(format!("http://{addr}"))
+                .unwrap()
+                .connect_lazy(),
+            MetadataMap::new(),
+            Some(CompressionEncoding::Gzip),
+            Temporality::Cumulative,
+            Arc::new(Reservoirs::new(
+                ExemplarFilter::AlwaysOff,
+                &MetricsConfig::default(),
+            )),
+            Handle::current(),
+        );
+        let resource_metrics = &metrics.get_finished_metrics().unwrap()[0];
+        exporter.export(resource_metrics).await.unwrap();
+        assert_eq!(*service.encodings.lock().unwrap(), ["gzip", "gzip"]);
+    }
+
+    #[test]
+    fn largest_samples() {
+        let reservoir = Reservoir::default();
+        for value in [3.0, 9.0, 1.0, 7.0, 5.0, 2.0] {
+            reservoir.offer(
+                exemplar::Value::AsDouble(value),
+                &[],
+                (TraceId::INVALID, SpanId::INVALID),
+            );
+        }
+        let mut values: Vec<_> = reservoir.drain().iter().map(Sample::value).collect();
+        values.sort_by(f64::total_cmp);
+        assert_eq!(values, [3.0, 5.0, 7.0, 9.0]);
+        assert!(reservoir.drain().is_empty());
+    }
+
+    #[test]
+    fn parse_filter() {
+        assert_eq!(
+            ExemplarFilter::parse("always_on"),
+            Some(ExemplarFilter::AlwaysOn)
+        );
+        assert_eq!(
+            ExemplarFilter::parse("TRACE_BASED"),
+            Some(ExemplarFilter::TraceBased)
+        );
+        assert_eq!(
+            ExemplarFilter::parse("always_off"),
+            Some(ExemplarFilter::AlwaysOff)
+        );
+        assert_eq!(ExemplarFilter::parse("sometimes"), None);
+    }
+}
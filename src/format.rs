@@ -0,0 +1,303 @@
+use std::fmt;
+
+use opentelemetry::trace::{SpanId, TraceContextExt, TraceId};
+use serde::de::{Deserialize, Deserializer, MapAccess, Visitor};
+use serde::ser::{SerializeMap, Serializer};
+use serde_json::value::RawValue;
+use tracing::{Event, Subscriber};
+use tracing_opentelemetry::OtelData;
+use tracing_subscriber::fmt::format::{DefaultFields, Format, Full, Writer};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::Dsn;
+
+/// Event format that adds `trace_id`, `span_id` and optionally the Uptrace
+/// trace URL to events emitted inside a span exported by
+/// [`Uptrace::tracing_layer`](crate::Uptrace::tracing_layer).
+///
+/// Text output gets `trace_id=.. span_id=..` appended to the line. If the
+/// inner format writes a JSON object (which also needs JSON fields on the
+/// layer), the object is parsed and written again with `trace_id`, `span_id`
+/// and `trace_url` members. The inner format is written through a buffer, so
+/// ANSI colors must be enabled on it explicitly with [`Format::with_ansi`].
+///
+/// ```no_run
+/// use tracing_subscriber::fmt::format::Format;
+/// use uptrace::format::TraceIdFormat;
+///
+/// tracing_subscriber::fmt()
+///     .json()
+///     .event_format(TraceIdFormat::new(Format::default().json()))
+///     .init();
+/// ```
+#[derive(Debug, Clone)]
+pub struct TraceIdFormat<F = Format<Full>> {
+    inner: F,
+    dsn: Option<Dsn>,
+}
+
+impl Default for TraceIdFormat {
+    fn default() -> Self {
+        Self::new(Format::default())
+    }
+}
+
+impl<F> TraceIdFormat<F> {
+    pub fn new(inner: F) -> Self {
+        Self { inner, dsn: None }
+    }
+
+    /// Also add a `trace_url` pointing to the trace in the Uptrace UI.
+    pub fn with_trace_url(mut self, dsn: &Dsn) -> Self {
+        self.dsn = Some(dsn.clone());
+        self
+    }
+}
+
+impl<S, N, F> FormatEvent<S, N> for TraceIdFormat<F>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    F: FormatEvent<S, N>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let (trace_id, span_id) = match current_ids(ctx) {
+            Some(ids) => ids,
+            None => return self.inner.format_event(ctx, writer, event),
+        };
+
+        let mut buf = String::new();
+        self.inner.format_event(ctx, Writer::new(&mut buf), event)?;
+
+        let line = buf.trim_end_matches('\n');
+        let newlines = &buf[line.len()..];
+        let trace_url = self.dsn.as_ref().map(|dsn| dsn.trace_url(trace_id));
+        let fields = [
+            ("trace_id", Some(trace_id.to_string())),
+            ("span_id", Some(span_id.to_string())),
+            ("trace_url", trace_url),
+        ];
+
+        match serde_json::from_str::<JsonObject<'_>>(line) {
+            Ok(object) => writer.write_str(&object.with_fields(&fields)?)?,
+            Err(_) => {
+                writer.write_str(line)?;
+                for (key, value) in &fields {
+                    if let Some(value) = value {
+                        write!(writer, " {}={}", key, value)?;
+                    }
+                }
+            }
+        }
+
+        writer.write_str(newlines)
+    }
+}
+
+/// Members of a JSON object in their original order, with the values left
+/// as they were written.
+struct JsonObject<'a>(Vec<(String, &'a RawValue)>);
+
+impl JsonObject<'_> {
+    /// Serializes the object with the given fields added, replacing the
+    /// members with the same keys.
+    fn with_fields(&self, fields: &[(&str, Option<String>)]) -> Result<String, fmt::Error> {
+        let mut buf = Vec::new();
+        let mut serializer = serde_json::Serializer::new(&mut buf);
+        let mut map = serializer.serialize_map(None).map_err(|_| fmt::Error)?;
+        for (key, value) in &self.0 {
+            if fields
+                .iter()
+                .any(|(field, value)| field == key && value.is_some())
+            {
+                continue;
+            }
+            map.serialize_entry(key, value).map_err(|_| fmt::Error)?;
+        }
+        for (key, value) in fields {
+            if let Some(value) = value {
+                map.serialize_entry(key, value).map_err(|_| fmt::Error)?;
+            }
+        }
+        map.end().map_err(|_| fmt::Error)?;
+        String::from_utf8(buf).map_err(|_| fmt::Error)
+    }
+}
+
+impl<'de> Deserialize<'de> for JsonObject<'de> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ObjectVisitor;
+
+        impl<'de> Visitor<'de> for ObjectVisitor {
+            type Value = JsonObject<'de>;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a JSON object")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut members = Vec::new();
+                while let Some(member) = map.next_entry()? {
+                    members.push(member);
+                }
+                Ok(JsonObject(members))
+            }
+        }
+
+        deserializer.deserialize_map(ObjectVisitor)
+    }
+}
+
+/// Returns the ids of the OpenTelemetry span backing the current `tracing` span.
+fn current_ids<S, N>(ctx: &FmtContext<'_, S, N>) -> Option<(TraceId, SpanId)>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    let span = ctx.parent_span()?;
+    let extensions = span.extensions();
+    let data = extensions.get::<OtelData>()?;
+
+    let span_id = data.builder.span_id?;
+    let trace_id = match data.builder.trace_id {
+        Some(trace_id) => trace_id,
+        None => data.parent_cx.span().span_context().trace_id(),
+    };
+    if trace_id == TraceId::INVALID {
+        return None;
+    }
+
+    Some((trace_id, span_id))
+}
+
+/// Shorthand for the fmt layer used by [`Uptrace::init_tracing_subscriber`](crate::Uptrace::init_tracing_subscriber).
+pub(crate) type FmtLayer<S> = tracing_subscriber::fmt::Layer<S, DefaultFields, TraceIdFormat>;
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    use tracing_subscriber::fmt::format::Format;
+    use tracing_subscriber::fmt::MakeWriter;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::{JsonObject, TraceIdFormat};
+    use crate::{Dsn, Uptrace};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Buffer {
+        type Writer = Self;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    impl Buffer {
+        fn lines(&self) -> Vec<String> {
+            let buf = self.0.lock().unwrap();
+            String::from_utf8(buf.clone())
+                .unwrap()
+                .lines()
+                .map(String::from)
+                .collect()
+        }
+    }
+
+    fn log(subscriber: impl tracing::Subscriber + Send + Sync, buffer: &Buffer) -> Vec<String> {
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("outside");
+            tracing::info_span!("my_span").in_scope(|| tracing::info!("inside"));
+        });
+
+        buffer.lines()
+    }
+
+    #[test]
+    fn text() {
+        let dsn = Dsn::try_from("https://token@api.uptrace.dev/1".to_string()).unwrap();
+        let uptrace = Uptrace::disabled();
+        let buffer = Buffer::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(uptrace.tracing_layer())
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .event_format(TraceIdFormat::default().with_trace_url(&dsn))
+                    .with_writer(buffer.clone()),
+            );
+        let lines = log(subscriber, &buffer);
+
+        assert_eq!(lines.len(), 2);
+        assert!(!lines[0].contains("trace_id="));
+        assert!(lines[1].contains(" trace_id="));
+        assert!(lines[1].contains(" span_id="));
+        assert!(lines[1].contains(" trace_url=https://app.uptrace.dev/traces/"));
+    }
+
+    #[test]
+    fn json() {
+        let dsn = Dsn::try_from("https://token@api.uptrace.dev/1".to_string()).unwrap();
+        let uptrace = Uptrace::disabled();
+        let buffer = Buffer::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(uptrace.tracing_layer())
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .event_format(TraceIdFormat::new(Format::default().json()).with_trace_url(&dsn))
+                    .with_writer(buffer.clone()),
+            );
+        let lines = log(subscriber, &buffer);
+
+        assert_eq!(lines.len(), 2);
+        assert!(!lines[0].contains("trace_id"));
+        let object: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_str(&lines[1]).unwrap();
+        let trace_id = object["trace_id"].as_str().unwrap();
+        assert_eq!(trace_id.len(), 32);
+        assert_eq!(object["span_id"].as_str().unwrap().len(), 16);
+        assert_eq!(
+            object["trace_url"],
+            format!("https://app.uptrace.dev/traces/{trace_id}")
+        );
+        assert_eq!(object["fields"]["message"], "inside");
+        assert!(lines[1].starts_with("{\"timestamp\":"), "{}", lines[1]);
+    }
+
+    #[test]
+    fn json_object() {
+        let object: JsonObject<'_> =
+            serde_json::from_str(r#"{"message":"a \"quoted\" word","trace_id":"x","n":[1,2]}"#)
+                .unwrap();
+        let fields = [
+            ("trace_id", Some("1".to_string())),
+            ("trace_url", Some("https://host/\"q\"".to_string())),
+            ("span_id", None),
+        ];
+
+        assert_eq!(
+            object.with_fields(&fields).unwrap(),
+            r#"{"message":"a \"quoted\" word","n":[1,2],"trace_id":"1","trace_url":"https://host/\"q\""}"#
+        );
+        assert!(serde_json::from_str::<JsonObject<'_>>("INFO not json").is_err());
+    }
+}
@@ -0,0 +1,658 @@
+//! Instrumentation for gRPC servers and clients built on `tonic`.
+//!
+//! ```no_run
+//! # fn setup(uptrace: &uptrace::Uptrace, channel: tonic::transport::Channel) {
+//! use uptrace::grpc::{GrpcClientLayer, GrpcServerLayer};
+//!
+//! let server = tonic::transport::Server::builder().layer(GrpcServerLayer::new(uptrace));
+//! let channel = tower::ServiceBuilder::new()
+//!     .layer(GrpcClientLayer::new(uptrace))
+//!     .service(channel);
+//! # }
+//! ```
+//!
+//! The span ends, and the duration is recorded, once the `grpc-status` is
+//! known: right away for trailers-only responses, which carry it in the
+//! headers, and otherwise when the response body yields the trailers or
+//! reports its end with `is_end_stream`. A body dropped before its trailers
+//! ends the span with `CANCELLED`. A call whose inner service fails gets the
+//! `UNKNOWN` code and the error as an `exception` event.
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Instant;
+
+use http::{HeaderMap, Request, Response};
+use http_body::{Body, Frame, SizeHint};
+use opentelemetry::metrics::Histogram;
+use opentelemetry::propagation::Injector;
+use opentelemetry::trace::{FutureExt, SpanKind, Status, TraceContextExt, Tracer as _};
+use opentelemetry::{global, Context, KeyValue};
+use opentelemetry_sdk::trace::SdkTracer;
+use tonic::metadata::{MetadataKey, MetadataMap, MetadataValue};
+use tonic::Code;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::exception::record_display_error;
+use crate::headers::{HeaderExtractor, HeaderInjector};
+use crate::propagation;
+use crate::Uptrace;
+
+/// Tracer and duration histogram shared by the server and client layers.
+#[derive(Clone)]
+struct RpcInstruments {
+    tracer: SdkTracer,
+    duration: Histogram<f64>,
+    kind: SpanKind,
+}
+
+impl RpcInstruments {
+    fn new(uptrace: &Uptrace, kind: SpanKind) -> Self {
+        let (name, description) = match kind {
+            SpanKind::Server => ("rpc.server.duration", "Duration of inbound RPCs"),
+            _ => ("rpc.client.duration", "Duration of outbound RPCs"),
+        };
+        let duration = uptrace
+            .meter()
+            .f64_histogram(name)
+            .with_description(description)
+            .with_unit("s")
+            .build();
+
+        Self {
+            tracer: uptrace.tracer().clone(),
+            duration,
+            kind,
+        }
+    }
+
+    /// Starts a span named after the `/package.Service/Method` request path.
+    fn start(&self, path: &str, parent_cx: &Context) -> (Context, Vec<KeyValue>) {
+        let name = path.trim_start_matches('/');
+        let (service, method) = name.split_once('/').unwrap_or((name, ""));
+        let attrs = vec![
+            KeyValue::new("rpc.system", "grpc"),
+            KeyValue::new("rpc.service", service.to_string()),
+            KeyValue::new("rpc.method", method.to_string()),
+        ];
+
+        let span = self
+            .tracer
+            .span_builder(name.to_string())
+            .with_kind(self.kind.clone())
+            .with_attributes(attrs.clone())
+            .start_with_context(&self.tracer, parent_cx);
+        (parent_cx.with_span(span), attrs)
+    }
+
+    fn finish(&self, cx: &Context, mut attrs: Vec<KeyValue>, start: Instant, code: Code) {
+        let span = cx.span();
+        let code_attr = KeyValue::new("rpc.grpc.status_code", code as i64);
+        span.set_attribute(code_attr.clone());
+        if code != Code::Ok {
+            span.set_status(Status::error(format!("{:?}", code)));
+        }
+        span.end();
+
+        attrs.push(code_attr);
+        self.duration.record(start.elapsed().as_secs_f64(), &attrs);
+    }
+}
+
+/// Returns the gRPC status code carried by the headers of a trailers-only
+/// response or by the trailers.
+fn status_code(headers: &HeaderMap) -> Option<Code> {
+    headers
+        .get("grpc-status")
+        .map(|value| Code::from_bytes(value.as_bytes()))
+}
+
+/// Span and start time of a call whose status isn't known yet.
+struct Call {
+    instruments: RpcInstruments,
+    cx: Context,
+    attrs: Vec<KeyValue>,
+    start: Instant,
+}
+
+impl Call {
+    fn finish(self, code: Code) {
+        self.instruments
+            .finish(&self.cx, self.attrs, self.start, code);
+    }
+}
+
+/// Response body returned by [`GrpcService`] that ends the call's span once
+/// the trailers with the `grpc-status` are read.
+pub struct GrpcBody<B> {
+    inner: Pin<Box<B>>,
+    call: Option<Call>,
+    /// Code used if the body ends without trailers.
+    fallback: Code,
+}
+
+impl<B> GrpcBody<B> {
+    fn finish(&mut self, code: Code) {
+        if let Some(call) = self.call.take() {
+            call.finish(code);
+        }
+    }
+}
+
+impl<B: Body> GrpcBody<B> {
+    /// Ends the call if the body has no frames left, because the caller may
+    /// never poll it again.
+    fn finish_if_end_stream(&mut self) {
+        if self.inner.is_end_stream() {
+            let code = self.fallback;
+            self.finish(code);
+        }
+    }
+}
+
+impl<B: Body> Body for GrpcBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = self.inner.as_mut().poll_frame(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(trailers) = frame.trailers_ref() {
+                    let code = status_code(trailers).unwrap_or(self.fallback);
+                    self.finish(code);
+                } else {
+                    self.finish_if_end_stream();
+                }
+            }
+            Poll::Ready(Some(Err(_))) => self.finish(Code::Unknown),
+            Poll::Ready(None) => {
+                let code = self.fallback;
+                self.finish(code);
+            }
+            Poll::Pending => {}
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl<B> Drop for GrpcBody<B> {
+    fn drop(&mut self) {
+        self.finish(Code::Cancelled);
+    }
+}
+
+impl<B> fmt::Debug for GrpcBody<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GrpcBody")
+            .field("finished", &self.call.is_none())
+            .finish()
+    }
+}
+
+/// Tower layer for `tonic` servers that creates a server span and records the
+/// `rpc.server.duration` histogram, in seconds, for every call, continuing the context
+/// propagated in the request metadata.
+#[derive(Clone)]
+pub struct GrpcServerLayer {
+    instruments: RpcInstruments,
+}
+
+impl GrpcServerLayer {
+    /// Creates a layer using the tracer and meter configured by `uptrace`.
+    pub fn new(uptrace: &Uptrace) -> Self {
+        Self {
+            instruments: RpcInstruments::new(uptrace, SpanKind::Server),
+        }
+    }
+}
+
+/// Tower layer for `tonic` channels that creates a client span and records the
+/// `rpc.client.duration` histogram, in seconds, for every call, propagating the context in
+/// the request metadata.
+#[derive(Clone)]
+pub struct GrpcClientLayer {
+    instruments: RpcInstruments,
+}
+
+impl GrpcClientLayer {
+    /// Creates a layer using the tracer and meter configured by `uptrace`.
+    pub fn new(uptrace: &Uptrace) -> Self {
+        Self {
+            instruments: RpcInstruments::new(uptrace, SpanKind::Client),
+        }
+    }
+}
+
+impl fmt::Debug for GrpcServerLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GrpcServerLayer").finish()
+    }
+}
+
+impl fmt::Debug for GrpcClientLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GrpcClientLayer").finish()
+    }
+}
+
+impl<S> Layer<S> for GrpcServerLayer {
+    type Service = GrpcService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        GrpcService {
+            inner,
+            instruments: self.instruments.clone(),
+        }
+    }
+}
+
+impl<S> Layer<S> for GrpcClientLayer {
+    type Service = GrpcService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        GrpcService {
+            inner,
+            instruments: self.instruments.clone(),
+        }
+    }
+}
+
+/// Service created by [`GrpcServerLayer`] and [`GrpcClientLayer`].
+#[derive(Clone)]
+pub struct GrpcService<S> {
+    inner: S,
+    instruments: RpcInstruments,
+}
+
+impl<S: fmt::Debug> fmt::Debug for GrpcService<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GrpcService")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for GrpcService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+    S::Error: fmt::Display,
+    ResBody: Body,
+{
+    type Response = Response<GrpcBody<ResBody>>;
+    type Error = S::Error;
+    type Future = BoxFuture<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        let parent_cx = match self.instruments.kind {
+            SpanKind::Server => global::get_text_map_propagator(|propagator| {
+                propagator.extract(&HeaderExtractor(req.headers()))
+            }),
+            _ => propagation::current_context(),
+        };
+
+        let (cx, attrs) = self.instruments.start(req.uri().path(), &parent_cx);
+        match self.instruments.kind {
+            SpanKind::Server => {
+                req.extensions_mut().insert(cx.clone());
+            }
+            _ => global::get_text_map_propagator(|propagator| {
+                propagator.inject_context(&cx, &mut HeaderInjector(req.headers_mut()))
+            }),
+        }
+
+        let future = {
+            let _guard = cx.clone().attach();
+            self.inner.call(req)
+        };
+        let instruments = self.instruments.clone();
+        let start = Instant::now();
+        Box::pin(async move {
+            let result = future.with_context(cx.clone()).await;
+            let call = Call {
+                instruments,
+                cx,
+                attrs,
+                start,
+            };
+            match result {
+                Ok(response) => {
+                    let (parts, body) = response.into_parts();
+                    let mut body = GrpcBody {
+                        inner: Box::pin(body),
+                        call: Some(call),
+                        fallback: if parts.status.is_success() {
+                            Code::Ok
+                        } else {
+                            Code::Unknown
+                        },
+                    };
+                    if let Some(code) = status_code(&parts.headers) {
+                        body.finish(code);
+                    } else {
+                        body.finish_if_end_stream();
+                    }
+                    Ok(Response::from_parts(parts, body))
+                }
+                Err(err) => {
+                    record_display_error(&mut call.cx.span(), &err);
+                    call.finish(Code::Unknown);
+                    Err(err)
+                }
+            }
+        })
+    }
+}
+
+struct MetadataInjector<'a>(&'a mut MetadataMap);
+
+impl<'a> Injector for MetadataInjector<'a> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(key), Ok(value)) = (
+            MetadataKey::from_bytes(key.as_bytes()),
+            MetadataValue::try_from(value.as_str()),
+        ) {
+            self.0.insert(key, value);
+        }
+    }
+}
+
+/// Client interceptor that propagates the current context in the request
+/// metadata without creating a span, for use with `with_interceptor`.
+#[allow(clippy::result_large_err)] // signature required by tonic's Interceptor
+pub fn inject_context<T>(mut req: tonic::Request<T>) -> Result<tonic::Request<T>, tonic::Status> {
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(
+            &propagation::current_context(),
+            &mut MetadataInjector(req.metadata_mut()),
+        )
+    });
+    Ok(req)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use bytes::Bytes;
+    use http::{HeaderMap, Request, Response};
+    use http_body_util::{BodyExt, Empty, Full};
+    use opentelemetry::trace::{Status, TraceContextExt};
+    use opentelemetry::{global, Context, KeyValue, Value};
+    use opentelemetry_sdk::metrics::InMemoryMetricExporter;
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use tonic::Code;
+    use tower::{ServiceBuilder, ServiceExt};
+    use tracing::Instrument;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::{status_code, GrpcClientLayer, GrpcServerLayer};
+    use crate::testing::{self, Collect};
+    use crate::Uptrace;
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[tokio::test]
+    async fn server_continues_trace() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let uptrace = Uptrace::disabled();
+        let service = ServiceBuilder::new()
+            .layer(GrpcServerLayer::new(&uptrace))
+            .service_fn(|req: Request<()>| async move {
+                let cx = req.extensions().get::<Context>().unwrap();
+                assert_eq!(
+                    cx.span().span_context().trace_id().to_string(),
+                    "4bf92f3577b34da6a3ce929d0e0e4736"
+                );
+                Ok::<_, Infallible>(Response::new(Empty::<Bytes>::new()))
+            });
+
+        let req = Request::post("/helloworld.Greeter/SayHello")
+            .header("traceparent", TRACEPARENT)
+            .body(())
+            .unwrap();
+        service.oneshot(req).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn client_injects_context() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let uptrace = Uptrace::disabled();
+        let service = ServiceBuilder::new()
+            .layer(GrpcClientLayer::new(&uptrace))
+            .service_fn(|req: Request<()>| async move {
+                assert!(req.headers().contains_key("traceparent"));
+                Ok::<_, Infallible>(Response::new(Empty::<Bytes>::new()))
+            });
+
+        let req = Request::post("http://localhost:50051/helloworld.Greeter/SayHello")
+            .body(())
+            .unwrap();
+        service.oneshot(req).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn client_parent_tracing_span() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let spans = Collect::default();
+        let metrics = InMemoryMetricExporter::default();
+        let uptrace = testing::uptrace(&spans, &metrics);
+        let subscriber = tracing_subscriber::registry().with(uptrace.tracing_layer());
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let service = ServiceBuilder::new()
+            .layer(GrpcClientLayer::new(&uptrace))
+            .service_fn(|req: Request<()>| async move {
+                assert!(req.headers().contains_key("traceparent"));
+                let response = Response::builder()
+                    .header("grpc-status", "0")
+                    .body(Empty::<Bytes>::new())
+                    .unwrap();
+                Ok::<_, Infallible>(response)
+            });
+
+        let req = Request::post("http://localhost:50051/helloworld.Greeter/SayHello")
+            .body(())
+            .unwrap();
+        service
+            .oneshot(req)
+            .instrument(tracing::info_span!("parent"))
+            .await
+            .unwrap();
+
+        let spans = spans.spans();
+        let client = spans
+            .iter()
+            .find(|span| span.name == "helloworld.Greeter/SayHello")
+            .unwrap();
+        let parent = spans.iter().find(|span| span.name == "parent").unwrap();
+        assert_eq!(client.parent_span_id, parent.span_context.span_id());
+        assert_eq!(
+            client.span_context.trace_id(),
+            parent.span_context.trace_id()
+        );
+    }
+
+    #[tokio::test]
+    async fn trailers_only_error() {
+        let spans = Collect::default();
+        let metrics = InMemoryMetricExporter::default();
+        let uptrace = testing::uptrace(&spans, &metrics);
+        let service = ServiceBuilder::new()
+            .layer(GrpcServerLayer::new(&uptrace))
+            .service_fn(|_: Request<()>| async move {
+                let response = Response::builder()
+                    .header("grpc-status", "5")
+                    .body(Empty::<Bytes>::new())
+                    .unwrap();
+                Ok::<_, Infallible>(response)
+            });
+
+        let req = Request::post("/helloworld.Greeter/SayHello")
+            .body(())
+            .unwrap();
+        let response = service.oneshot(req).await.unwrap();
+
+        // The status is in the headers, so the span ends before the body is read.
+        let ended = spans.spans();
+        assert_eq!(ended.len(), 1);
+        assert_eq!(ended[0].status, Status::error("NotFound"));
+        drop(response);
+        assert_eq!(spans.spans().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn trailers_error() {
+        let spans = Collect::default();
+        let metrics = InMemoryMetricExporter::default();
+        let uptrace = testing::uptrace(&spans, &metrics);
+        let service = ServiceBuilder::new()
+            .layer(GrpcClientLayer::new(&uptrace))
+            .service_fn(|_: Request<()>| async move {
+                let mut trailers = HeaderMap::new();
+                trailers.insert("grpc-status", "13".parse().unwrap());
+                let body = Empty::<Bytes>::new().with_trailers(async { Some(Ok(trailers)) });
+                Ok::<_, Infallible>(Response::new(body))
+            });
+
+        let req = Request::post("http://localhost:50051/helloworld.Greeter/SayHello")
+            .body(())
+            .unwrap();
+        let response = service.oneshot(req).await.unwrap();
+        assert!(spans.spans().is_empty());
+
+        let collected = response.into_body().collect().await.unwrap();
+        assert!(collected.trailers().is_some());
+        let ended = spans.spans();
+        assert_eq!(ended.len(), 1);
+        assert_eq!(ended[0].name, "helloworld.Greeter/SayHello");
+        assert_eq!(ended[0].status, Status::error("Internal"));
+        let code = ended[0]
+            .attributes
+            .iter()
+            .find(|kv| kv.key.as_str() == "rpc.grpc.status_code")
+            .map(|kv| kv.value.clone());
+        assert_eq!(code, Some(Value::I64(13)));
+
+        let (unit, points) = testing::histogram(&uptrace, &metrics, "rpc.client.duration");
+        assert_eq!(unit, "s");
+        assert_eq!(points.len(), 1);
+        assert!(points[0]
+            .0
+            .contains(&KeyValue::new("rpc.grpc.status_code", 13)));
+    }
+
+    #[tokio::test]
+    async fn dropped_body_cancels() {
+        let spans = Collect::default();
+        let metrics = InMemoryMetricExporter::default();
+        let uptrace = testing::uptrace(&spans, &metrics);
+        let service = ServiceBuilder::new()
+            .layer(GrpcServerLayer::new(&uptrace))
+            .service_fn(|_: Request<()>| async move {
+                Ok::<_, Infallible>(Response::new(Full::new(Bytes::from_static(b"message"))))
+            });
+
+        let req = Request::post("/helloworld.Greeter/SayHello")
+            .body(())
+            .unwrap();
+        drop(service.oneshot(req).await.unwrap());
+        assert_eq!(spans.spans()[0].status, Status::error("Cancelled"));
+    }
+
+    #[tokio::test]
+    async fn end_stream_body() {
+        let spans = Collect::default();
+        let metrics = InMemoryMetricExporter::default();
+        let uptrace = testing::uptrace(&spans, &metrics);
+        let service = ServiceBuilder::new()
+            .layer(GrpcServerLayer::new(&uptrace))
+            .service_fn(|_: Request<()>| async move {
+                Ok::<_, Infallible>(Response::new(Empty::<Bytes>::new()))
+            });
+
+        // An empty body may never be polled, so the call ends with the response.
+        let req = Request::post("/helloworld.Greeter/SayHello")
+            .body(())
+            .unwrap();
+        let response = service.clone().oneshot(req).await.unwrap();
+        assert_eq!(spans.spans().len(), 1);
+        assert_eq!(spans.spans()[0].status, Status::Unset);
+        drop(response);
+        assert_eq!(spans.spans().len(), 1);
+
+        // A body that reaches its end after the last frame ends the call then.
+        let service = ServiceBuilder::new()
+            .layer(GrpcServerLayer::new(&uptrace))
+            .service_fn(|_: Request<()>| async move {
+                Ok::<_, Infallible>(Response::new(Full::new(Bytes::from_static(b"message"))))
+            });
+        let req = Request::post("/helloworld.Greeter/SayHello")
+            .body(())
+            .unwrap();
+        let mut body = service.oneshot(req).await.unwrap().into_body();
+        let frame = body.frame().await.unwrap().unwrap();
+        assert_eq!(frame.into_data().unwrap(), "message");
+        assert_eq!(spans.spans().len(), 2);
+        assert_eq!(spans.spans()[1].status, Status::Unset);
+        drop(body);
+        assert_eq!(spans.spans().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn inner_error() {
+        let spans = Collect::default();
+        let metrics = InMemoryMetricExporter::default();
+        let uptrace = testing::uptrace(&spans, &metrics);
+        let service = ServiceBuilder::new()
+            .layer(GrpcClientLayer::new(&uptrace))
+            .service_fn(|_: Request<()>| async move {
+                Err::<Response<Empty<Bytes>>, _>("connection refused")
+            });
+
+        let req = Request::post("http://localhost:50051/helloworld.Greeter/SayHello")
+            .body(())
+            .unwrap();
+        assert!(service.oneshot(req).await.is_err());
+
+        let spans = spans.spans();
+        assert_eq!(spans[0].status, Status::error("connection refused"));
+        let event = spans[0].events.iter().next().unwrap();
+        assert_eq!(event.name, "exception");
+        assert!(event
+            .attributes
+            .contains(&KeyValue::new("exception.type", "&str")));
+        assert!(spans[0]
+            .attributes
+            .contains(&KeyValue::new("rpc.grpc.status_code", Code::Unknown as i64)));
+    }
+
+    #[test]
+    fn grpc_status() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(status_code(&headers), None);
+        headers.insert("grpc-status", "5".parse().unwrap());
+        assert_eq!(status_code(&headers), Some(Code::NotFound));
+    }
+}
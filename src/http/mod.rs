@@ -0,0 +1,39 @@
+//! Instrumentation for HTTP servers and clients built on `tower`.
+
+use http::HeaderMap;
+use opentelemetry::KeyValue;
+
+pub mod client;
+#[cfg(feature = "reqwest")]
+pub mod reqwest;
+pub mod server;
+
+pub(crate) fn flavor(version: http::Version) -> &'static str {
+    match version {
+        http::Version::HTTP_09 => "0.9",
+        http::Version::HTTP_10 => "1.0",
+        http::Version::HTTP_2 => "2.0",
+        http::Version::HTTP_3 => "3.0",
+        _ => "1.1",
+    }
+}
+
+/// Request attributes of client spans, using the stable HTTP semantic
+/// conventions.
+pub(crate) fn request_attributes(
+    method: &http::Method,
+    version: http::Version,
+    headers: &HeaderMap,
+) -> Vec<KeyValue> {
+    let mut attrs = vec![
+        KeyValue::new("http.request.method", method.as_str().to_string()),
+        KeyValue::new("network.protocol.version", flavor(version)),
+    ];
+    if let Some(user_agent) = headers
+        .get(http::header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+    {
+        attrs.push(KeyValue::new("user_agent.original", user_agent.to_string()));
+    }
+    attrs
+}
@@ -0,0 +1,344 @@
+//! Server-side HTTP instrumentation.
+//!
+//! ```no_run
+//! # fn service(uptrace: &uptrace::Uptrace) {
+//! use uptrace::http::server::HttpServerLayer;
+//!
+//! let layer = HttpServerLayer::new(uptrace)
+//!     .with_route(|parts| parts.uri.path().starts_with("/posts/").then(|| "/posts/:id".into()))
+//!     .with_trace_url_header();
+//! # }
+//! ```
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Instant;
+
+use http::header::HeaderValue;
+use http::{Request, Response};
+use opentelemetry::metrics::Histogram;
+use opentelemetry::propagation::TextMapPropagator;
+use opentelemetry::trace::{FutureExt, SpanKind, Status, TraceContextExt, Tracer as _};
+use opentelemetry::{global, Context, KeyValue};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracer;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use super::flavor;
+use crate::exception::record_display_error;
+use crate::headers::{HeaderExtractor, HeaderInjector};
+use crate::{Dsn, Uptrace};
+
+/// Response header with the URL of the trace in the Uptrace UI.
+pub const TRACE_URL_HEADER: &str = "x-uptrace-trace-url";
+
+/// Returns the route template of a request, e.g. `/posts/:id`.
+pub type RouteFn = Arc<dyn Fn(&http::request::Parts) -> Option<String> + Send + Sync>;
+
+/// Tower layer that creates a server span and records the
+/// `http.server.request.duration` histogram, in seconds, for every request.
+/// Both use the stable HTTP semantic conventions, e.g. `http.request.method`
+/// and `http.response.status_code`.
+///
+/// The context propagated by the client is extracted with the global text map
+/// propagator, the span context is stored in the request extensions and is
+/// returned to the client in the `traceparent` response header.
+#[derive(Clone)]
+pub struct HttpServerLayer {
+    tracer: SdkTracer,
+    duration: Histogram<f64>,
+    route_fn: Option<RouteFn>,
+    dsn: Option<Dsn>,
+    trace_url_header: bool,
+}
+
+impl HttpServerLayer {
+    /// Creates a layer using the tracer and meter configured by `uptrace`.
+    pub fn new(uptrace: &Uptrace) -> Self {
+        let duration = uptrace
+            .meter()
+            .f64_histogram("http.server.request.duration")
+            .with_description("Duration of inbound HTTP requests")
+            .with_unit("s")
+            .build();
+
+        Self {
+            tracer: uptrace.tracer().clone(),
+            duration,
+            route_fn: None,
+            dsn: uptrace.dsn().cloned(),
+            trace_url_header: false,
+        }
+    }
+
+    /// Set the function returning the route template used for the span name and
+    /// the `http.route` attribute. Without it spans are named after the method only.
+    ///
+    /// With axum, return the `MatchedPath` extension and add the layer with `route_layer`.
+    pub fn with_route<F>(mut self, route_fn: F) -> Self
+    where
+        F: Fn(&http::request::Parts) -> Option<String> + Send + Sync + 'static,
+    {
+        self.route_fn = Some(Arc::new(route_fn));
+        self
+    }
+
+    /// Also return the URL of the trace in the [`TRACE_URL_HEADER`] response header.
+    pub fn with_trace_url_header(mut self) -> Self {
+        self.trace_url_header = true;
+        self
+    }
+}
+
+impl fmt::Debug for HttpServerLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HttpServerLayer")
+            .field("route_fn", &self.route_fn.is_some())
+            .field("trace_url_header", &self.trace_url_header)
+            .finish()
+    }
+}
+
+impl<S> Layer<S> for HttpServerLayer {
+    type Service = HttpServerService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        HttpServerService {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+/// Service created by [`HttpServerLayer`].
+#[derive(Clone, Debug)]
+pub struct HttpServerService<S> {
+    inner: S,
+    layer: HttpServerLayer,
+}
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for HttpServerService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+    S::Error: fmt::Display,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let parent_cx = global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(req.headers()))
+        });
+
+        let (mut parts, body) = req.into_parts();
+        let route = self.layer.route_fn.as_ref().and_then(|f| f(&parts));
+        let scheme = parts.uri.scheme_str().unwrap_or("http").to_string();
+
+        let mut metric_attrs = vec![
+            KeyValue::new("http.request.method", parts.method.as_str().to_string()),
+            KeyValue::new("url.scheme", scheme),
+        ];
+        let mut attrs = metric_attrs.clone();
+        attrs.push(KeyValue::new("url.path", parts.uri.path().to_string()));
+        attrs.push(KeyValue::new(
+            "network.protocol.version",
+            flavor(parts.version),
+        ));
+        if let Some(host) = parts
+            .headers
+            .get(http::header::HOST)
+            .and_then(|value| value.to_str().ok())
+        {
+            let host = match host.rsplit_once(':') {
+                Some((host, port)) if port.parse::<u16>().is_ok() => host,
+                _ => host,
+            };
+            attrs.push(KeyValue::new("server.address", host.to_string()));
+        }
+        if let Some(user_agent) = parts
+            .headers
+            .get(http::header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+        {
+            attrs.push(KeyValue::new("user_agent.original", user_agent.to_string()));
+        }
+
+        let name = match &route {
+            Some(route) => {
+                attrs.push(KeyValue::new("http.route", route.clone()));
+                metric_attrs.push(KeyValue::new("http.route", route.clone()));
+                format!("{} {}", parts.method, route)
+            }
+            None => parts.method.to_string(),
+        };
+
+        let span = self
+            .layer
+            .tracer
+            .span_builder(name)
+            .with_kind(SpanKind::Server)
+            .with_attributes(attrs)
+            .start_with_context(&self.layer.tracer, &parent_cx);
+        let cx = parent_cx.with_span(span);
+
+        parts.extensions.insert(cx.clone());
+        let start = Instant::now();
+        let future = {
+            let _guard = cx.clone().attach();
+            self.inner.call(Request::from_parts(parts, body))
+        };
+
+        let layer = self.layer.clone();
+        Box::pin(async move {
+            let mut result = future.with_context(cx.clone()).await;
+            let mut span = cx.span();
+
+            match &mut result {
+                Ok(response) => {
+                    let status = response.status();
+                    let status_attr =
+                        KeyValue::new("http.response.status_code", status.as_u16() as i64);
+                    span.set_attribute(status_attr.clone());
+                    metric_attrs.push(status_attr);
+                    if status.is_server_error() {
+                        span.set_status(Status::error(status.to_string()));
+                    }
+
+                    TraceContextPropagator::new()
+                        .inject_context(&cx, &mut HeaderInjector(response.headers_mut()));
+                    if layer.trace_url_header {
+                        add_trace_url_header(&layer, &cx, response);
+                    }
+                }
+                Err(err) => record_display_error(&mut span, err),
+            }
+            span.end();
+
+            layer
+                .duration
+                .record(start.elapsed().as_secs_f64(), &metric_attrs);
+            result
+        })
+    }
+}
+
+fn add_trace_url_header<B>(layer: &HttpServerLayer, cx: &Context, response: &mut Response<B>) {
+    let dsn = match &layer.dsn {
+        Some(dsn) => dsn,
+        None => return,
+    };
+
+    let trace_id = cx.span().span_context().trace_id();
+    if let Ok(value) = HeaderValue::from_str(&dsn.trace_url(trace_id)) {
+        response.headers_mut().insert(TRACE_URL_HEADER, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use http::{Request, Response, StatusCode};
+    use opentelemetry::trace::{SpanId, SpanKind, Status, TraceContextExt};
+    use opentelemetry::{global, Context, KeyValue, Value};
+    use opentelemetry_sdk::metrics::InMemoryMetricExporter;
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use opentelemetry_sdk::trace::SpanData;
+    use tower::{ServiceBuilder, ServiceExt};
+
+    use super::HttpServerLayer;
+    use crate::testing::{self, Collect};
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    fn attr(span: &SpanData, key: &str) -> Option<Value> {
+        span.attributes
+            .iter()
+            .find(|kv| kv.key.as_str() == key)
+            .map(|kv| kv.value.clone())
+    }
+
+    #[tokio::test]
+    async fn server_span() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let spans = Collect::default();
+        let metrics = InMemoryMetricExporter::default();
+        let uptrace = testing::uptrace(&spans, &metrics);
+        let service = ServiceBuilder::new()
+            .layer(HttpServerLayer::new(&uptrace).with_route(|_| Some("/posts/:id".into())))
+            .service_fn(|req: Request<()>| async move {
+                let cx = req.extensions().get::<Context>().unwrap();
+                assert!(cx.span().span_context().is_valid());
+                assert_eq!(
+                    Context::current().span().span_context().span_id(),
+                    cx.span().span_context().span_id()
+                );
+                let status = match req.uri().path() {
+                    "/posts/500" => StatusCode::INTERNAL_SERVER_ERROR,
+                    _ => StatusCode::OK,
+                };
+                Ok::<_, Infallible>(Response::builder().status(status).body(()).unwrap())
+            });
+
+        let req = Request::get("/posts/123")
+            .header("traceparent", TRACEPARENT)
+            .body(())
+            .unwrap();
+        let response = service.clone().oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let traceparent = response.headers()["traceparent"].to_str().unwrap();
+        assert!(traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+        assert!(!traceparent.contains("00f067aa0ba902b7"));
+
+        let req = Request::get("/posts/500").body(()).unwrap();
+        let response = service.oneshot(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let spans = spans.spans();
+        assert_eq!(spans.len(), 2);
+        let span = &spans[0];
+        assert_eq!(span.name, "GET /posts/:id");
+        assert_eq!(span.span_kind, SpanKind::Server);
+        assert_eq!(
+            span.parent_span_id,
+            SpanId::from_hex("00f067aa0ba902b7").unwrap()
+        );
+        assert_eq!(span.status, Status::Unset);
+        assert_eq!(attr(span, "http.route"), Some("/posts/:id".into()));
+        assert_eq!(attr(span, "http.request.method"), Some("GET".into()));
+        assert_eq!(attr(span, "url.path"), Some("/posts/123".into()));
+        assert_eq!(attr(span, "http.response.status_code"), Some(200.into()));
+        assert_eq!(spans[1].status, Status::error("500 Internal Server Error"));
+        assert_eq!(
+            attr(&spans[1], "http.response.status_code"),
+            Some(500.into())
+        );
+
+        let (unit, mut points) =
+            testing::histogram(&uptrace, &metrics, "http.server.request.duration");
+        assert_eq!(unit, "s");
+        points.sort_by_key(|(attributes, _)| format!("{attributes:?}"));
+        let attributes = |status: i64| {
+            vec![
+                KeyValue::new("http.request.method", "GET"),
+                KeyValue::new("http.response.status_code", status),
+                KeyValue::new("http.route", "/posts/:id"),
+                KeyValue::new("url.scheme", "http"),
+            ]
+        };
+        assert_eq!(points, vec![(attributes(200), 1), (attributes(500), 1)]);
+    }
+}
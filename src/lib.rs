@@ -5,12 +5,13 @@
 //! use uptrace::UptraceBuilder;
 //! use opentelemetry::{global, trace::{Tracer, Span}, KeyValue};
 //!
-//! fn main() -> Result<(), Box<dyn std::error::Error>> {
-//!     // You can also start to tracing and metrics.
-//!     UptraceBuilder::new()
+//! #[tokio::main]
+//! async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//!     // Configures traces, metrics and logs.
+//!     let uptrace = UptraceBuilder::new()
 //!         .with_dsn("http://project2_secret_token@localhost:14317/2")
 //!         .with_service_name("lol")
-//!         .configure_opentelemetry()?;
+//!         .build()?;
 //!
 //!     let tracer = global::tracer("rust-service");
 //!     let mut span = tracer.start("my_span");
@@ -19,36 +20,121 @@
 //!     span.end();
 //!
 //!     println!("{:?}", span.span_context().trace_id().to_string());
-//!     global::shutdown_tracer_provider();
+//!     uptrace.shutdown_with_timeout(std::time::Duration::from_secs(5)).await?;
 //!     Ok(())
 //! }
 //! ```
 //!
+//! # Tracing
+//!
+//! [`Uptrace::tracing_layer`] exports spans created with the `tracing` crate,
+//! and [`Uptrace::init_tracing_subscriber`] installs it together with a fmt layer:
+//!
+//! ```no_run
+//! use uptrace::UptraceBuilder;
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//!     let uptrace = UptraceBuilder::new()
+//!         .with_service_name("myservice")
+//!         .build()?;
+//!     uptrace.init_tracing_subscriber()?;
+//!
+//!     tracing::info_span!("my_span").in_scope(|| tracing::info!("hello"));
+//!     Ok(())
+//! }
+//! ```
+//!
+//! Upgrading from a version built on `opentelemetry` 0.19? See `MIGRATION.md`
+//! in the repository for the breaking changes.
+//!
 //! [uptrace]: https://uptrace.dev/
 
+use std::sync::Arc;
 use std::time::Duration;
 
 pub mod dsn;
 pub use dsn::Dsn;
 
 pub mod error;
-pub use error::Error;
-
-use opentelemetry::sdk;
-use opentelemetry::sdk::export::metrics::aggregation::delta_temporality_selector;
-use opentelemetry::sdk::metrics::controllers::BasicController;
-use opentelemetry::sdk::metrics::selectors;
-use opentelemetry::sdk::resource::{
-    EnvResourceDetector, SdkProvidedResourceDetector, TelemetryResourceDetector,
-};
-use opentelemetry::sdk::{runtime, Resource};
+pub use error::{Error, ErrorKind};
+
+pub mod error_handler;
+use error_handler::ErrorHandler;
+
+#[cfg(feature = "config-file")]
+pub mod config;
+
+pub mod db;
+
+mod cardinality;
+use cardinality::CardinalityLimits;
+
+mod exemplars;
+
+pub mod exception;
+pub use exception::{ResultExt, SpanExt};
+
+pub mod format;
+
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(any(feature = "http", feature = "grpc"))]
+mod headers;
+#[cfg(feature = "http")]
+pub mod http;
+
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+
+pub mod propagation;
+pub use propagation::Propagator;
+
+pub mod limits;
+use limits::{LimitsSpanProcessor, LogLimits, SpanLimits};
+
+pub mod logs;
+
+mod meter;
+
+pub mod metrics;
+use metrics::{ExemplarFilter, MetricsConfig};
+
+mod panic;
+pub use panic::install_panic_hook;
+
+pub mod redact;
+use redact::{RedactingSpanProcessor, Redactor};
+
+pub mod runtime_metrics;
+
+pub mod span_metrics;
+use span_metrics::{SpanMetricsConfig, SpanMetricsProcessor};
+
+#[cfg(feature = "system-metrics")]
+pub mod system_metrics;
+
+#[cfg(test)]
+mod testing;
+
+mod uptrace;
+use uptrace::GlobalConfig;
+pub use uptrace::Uptrace;
+
+use opentelemetry::metrics::Meter;
+use opentelemetry::trace::{SpanId, TraceId};
 use opentelemetry::{global, KeyValue};
 use opentelemetry_otlp::{
-    ExportConfig, Protocol, SpanExporter, SpanExporterBuilder, WithExportConfig,
+    LogExporter, MetricExporter, SpanExporter, WithExportConfig, WithTonicConfig,
+};
+use opentelemetry_sdk::logs::SdkLoggerProvider;
+use opentelemetry_sdk::metrics::SdkMeterProvider;
+use opentelemetry_sdk::trace::{
+    BatchConfig, BatchConfigBuilder, BatchSpanProcessor, IdGenerator, Sampler, SdkTracerProvider,
 };
+use opentelemetry_sdk::Resource;
 use tonic::metadata::MetadataMap;
-
-use opentelemetry::trace::TracerProvider;
+use tonic::transport::{Channel, ClientTlsConfig};
 
 pub struct UptraceBuilder {
     dsn: String,
@@ -56,31 +142,54 @@ pub struct UptraceBuilder {
     service_name: Option<String>,
     service_version: Option<String>,
     deployment_environment: Option<String>,
+    resource_attributes: Vec<KeyValue>,
 
     tracing_disabled: bool,
     metrics_disabled: bool,
+    logs_disabled: bool,
+
+    sampler: Option<Sampler>,
+    id_generator: Option<Box<dyn IdGenerator>>,
+    batch_config: Option<BatchConfig>,
+    span_limits: Option<SpanLimits>,
+    span_metrics: Option<SpanMetricsConfig>,
+    log_limits: Option<LogLimits>,
+    metrics: MetricsConfig,
 
-    trace_config: sdk::trace::Config,
-    batch_config: sdk::trace::BatchConfig,
+    error_handler: ErrorHandler,
+    error_rate_limit: Duration,
+
+    propagators: Option<Vec<Propagator>>,
+    redactor: Option<Redactor>,
 }
 
 impl Default for UptraceBuilder {
     fn default() -> Self {
         Self {
-            dsn: std::env::var("UPTRACE_DSN").ok().unwrap(),
+            dsn: std::env::var("UPTRACE_DSN").unwrap_or_default(),
 
-            trace_config: sdk::trace::Config::default(),
-            batch_config: sdk::trace::BatchConfig::default()
-                .with_max_queue_size(30000)
-                .with_max_export_batch_size(10000)
-                .with_scheduled_delay(Duration::from_millis(5000)),
+            sampler: None,
+            id_generator: None,
+            batch_config: None,
+            span_limits: None,
+            span_metrics: None,
+            log_limits: None,
+            metrics: MetricsConfig::default(),
 
             service_name: None,
             service_version: None,
             deployment_environment: None,
+            resource_attributes: Vec::new(),
 
             metrics_disabled: false,
             tracing_disabled: false,
+            logs_disabled: false,
+
+            error_handler: Arc::new(error_handler::stderr_handler),
+            error_rate_limit: error_handler::DEFAULT_RATE_LIMIT,
+
+            propagators: None,
+            redactor: None,
         }
     }
 }
@@ -90,20 +199,45 @@ impl UptraceBuilder {
         Default::default()
     }
 
+    /// Create a builder configured by a YAML or TOML file, see [`config`] for
+    /// the format. The builder methods called afterwards override the file.
+    #[cfg(feature = "config-file")]
+    pub fn from_config_file<P: AsRef<std::path::Path>>(path: P) -> Result<Self, Error> {
+        config::load(path.as_ref(), Self::default())
+    }
+
+    /// Create a builder configured by the file named by the
+    /// [`UPTRACE_CONFIG_FILE`](config::UPTRACE_CONFIG_FILE) env var, or the
+    /// default builder if it is unset.
+    #[cfg(feature = "config-file")]
+    pub fn from_config_env() -> Result<Self, Error> {
+        match std::env::var_os(config::UPTRACE_CONFIG_FILE) {
+            Some(path) => Self::from_config_file(path),
+            None => Ok(Self::default()),
+        }
+    }
+
     pub fn with_dsn<T: Into<String>>(mut self, dsn: T) -> Self {
         self.dsn = dsn.into();
         self
     }
 
-    /// Set the trace provider configuration.
-    pub fn with_trace_config(mut self, trace_config: sdk::trace::Config) -> Self {
-        self.trace_config = trace_config;
+    /// Set the sampler. Defaults to the `OTEL_TRACES_SAMPLER` env var or, if it
+    /// is unset, to sampling every trace that doesn't have a sampled-out parent.
+    pub fn with_sampler(mut self, sampler: Sampler) -> Self {
+        self.sampler = Some(sampler);
+        self
+    }
+
+    /// Set the generator of trace and span ids. Defaults to random ids.
+    pub fn with_id_generator<T: IdGenerator + 'static>(mut self, id_generator: T) -> Self {
+        self.id_generator = Some(Box::new(id_generator));
         self
     }
 
     /// Set the batch span processor configuration, and it will override the env vars.
-    pub fn with_batch_config(mut self, batch_config: sdk::trace::BatchConfig) -> Self {
-        self.batch_config = batch_config;
+    pub fn with_batch_config(mut self, batch_config: BatchConfig) -> Self {
+        self.batch_config = Some(batch_config);
         self
     }
 
@@ -125,6 +259,17 @@ impl UptraceBuilder {
         self
     }
 
+    /// Add attributes to the resource shared by traces, metrics and logs.
+    /// The service name, version and deployment environment set with the
+    /// builder take precedence over the same keys here.
+    pub fn with_resource_attributes<I: IntoIterator<Item = KeyValue>>(
+        mut self,
+        attributes: I,
+    ) -> Self {
+        self.resource_attributes.extend(attributes);
+        self
+    }
+
     pub fn with_tracing_disabled(mut self) -> Self {
         self.tracing_disabled = true;
         self
@@ -135,89 +280,362 @@ impl UptraceBuilder {
         self
     }
 
-    pub fn configure_opentelemetry<R: sdk::trace::TraceRuntime>(
-        mut self,
-        runtime: R,
-    ) -> Result<(), Error> {
+    pub fn with_logs_disabled(mut self) -> Self {
+        self.logs_disabled = true;
+        self
+    }
+
+    /// Set the metrics configuration, e.g. views and histogram aggregation.
+    pub fn with_metrics(mut self, metrics: MetricsConfig) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Set the handler for errors reported by the OpenTelemetry SDK, e.g. failed exports.
+    /// By default errors are written to stderr.
+    ///
+    /// The SDK reports errors as `tracing` events, which only reach the handler
+    /// through the [`Uptrace::error_layer`]. The handler is told once if the
+    /// layer was never added to a subscriber.
+    pub fn with_error_handler<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&error_handler::OtelError) + Send + Sync + 'static,
+    {
+        self.error_handler = Arc::new(handler);
+        self
+    }
+
+    /// Report errors as `tracing` events instead of writing them to stderr.
+    /// See [`error_handler::tracing_handler`].
+    pub fn with_tracing_error_handler(self) -> Self {
+        self.with_error_handler(error_handler::tracing_handler)
+    }
+
+    /// Set the minimum interval between two reported errors; the errors in between are dropped.
+    /// Defaults to 10 seconds, `Duration::ZERO` disables rate limiting.
+    pub fn with_error_rate_limit(mut self, interval: Duration) -> Self {
+        self.error_rate_limit = interval;
+        self
+    }
+
+    /// Set the propagators installed as the global text map propagator.
+    /// Defaults to the `OTEL_PROPAGATORS` env var or, if it is unset, to tracecontext and baggage.
+    pub fn with_propagators<I: IntoIterator<Item = Propagator>>(mut self, propagators: I) -> Self {
+        self.propagators = Some(propagators.into_iter().collect());
+        self
+    }
+
+    /// Set the span limits. Defaults to [`SpanLimits::from_env`].
+    pub fn with_span_limits(mut self, span_limits: SpanLimits) -> Self {
+        self.span_limits = Some(span_limits);
+        self
+    }
+
+    /// Derive request rate, error rate and duration metrics from the ended
+    /// spans, see [`span_metrics`]. Disabled by default.
+    pub fn with_span_metrics(mut self, config: SpanMetricsConfig) -> Self {
+        self.span_metrics = Some(config);
+        self
+    }
+
+    /// Set the log record limits. Defaults to [`LogLimits::from_env`].
+    pub fn with_log_limits(mut self, log_limits: LogLimits) -> Self {
+        self.log_limits = Some(log_limits);
+        self
+    }
+
+    /// Redact span, span event and log record attributes with the given rules
+    /// before they are exported.
+    pub fn with_redactor(mut self, redactor: Redactor) -> Self {
+        self.redactor = Some(redactor);
+        self
+    }
+
+    pub fn configure_opentelemetry(self) -> Result<(), Error> {
+        self.build().map(|_| ())
+    }
+
+    /// Configure OpenTelemetry and return a handle to the configured pipelines.
+    ///
+    /// The providers, the propagator and the error handler are installed
+    /// globally, see [`Uptrace::install_global`]. Must be called within a
+    /// tokio runtime, which the gRPC exporters run on.
+    pub fn build(self) -> Result<Uptrace, Error> {
+        let uptrace = self.build_without_global()?;
+        uptrace.install_global();
+        Ok(uptrace)
+    }
+
+    /// Configure the pipelines without touching the OpenTelemetry globals.
+    ///
+    /// Spans, metrics and logs are only exported through the returned handle,
+    /// e.g. [`Uptrace::tracer`], [`Uptrace::meter`] and [`Uptrace::logs_layer`],
+    /// which lets libraries and tests run isolated pipelines side by side. Call
+    /// [`Uptrace::install_global`] to install them globally later.
+    pub fn build_without_global(mut self) -> Result<Uptrace, Error> {
         if std::env::var("UPTRACE_DISABLED").is_ok() {
-            return Ok(());
+            return Ok(Uptrace::disabled());
         }
 
         let dsn = Dsn::try_from(self.dsn.clone())?;
         if dsn.is_disabled() {
-            return Ok(());
+            return Ok(Uptrace::disabled());
         }
+        let runtime = match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => runtime,
+            Err(err) => return Err(Error::Runtime(Box::new(err))),
+        };
 
-        if !self.tracing_disabled {
-            self.init_tracer(&dsn, runtime)?;
+        // Metrics go first so that the span and log processing can register
+        // their counters with the meter provider.
+        let meter_provider = if !self.metrics_disabled {
+            Some(self.build_meter_provider(&dsn)?)
+        } else {
+            None
+        };
+        let meter = match &meter_provider {
+            Some(provider) => uptrace::scope_meter(provider),
+            None => uptrace::scope_meter(&SdkMeterProvider::default()),
+        };
+        if meter_provider.is_some() && self.metrics.runtime_metrics {
+            runtime_metrics::register(&meter, &runtime);
         }
-
-        if !self.metrics_disabled {
-            self.init_metrics(&dsn)?;
+        #[cfg(feature = "system-metrics")]
+        if let Some(config) = self
+            .metrics
+            .system_metrics
+            .filter(|_| meter_provider.is_some())
+        {
+            system_metrics::register(&meter, &runtime, &config)?;
         }
 
-        Ok(())
+        let tracer_provider = if !self.tracing_disabled {
+            Some(self.build_tracer_provider(&dsn, &meter)?)
+        } else {
+            None
+        };
+        let logger_provider = if !self.logs_disabled {
+            Some(self.build_logger_provider(&dsn, &meter)?)
+        } else {
+            None
+        };
+
+        let globals = GlobalConfig {
+            error_handler: self.error_handler.clone(),
+            error_rate_limit: self.error_rate_limit,
+            propagators: self.propagators(),
+        };
+        Ok(Uptrace::new(
+            dsn,
+            tracer_provider,
+            meter_provider,
+            logger_provider,
+            globals,
+        ))
     }
 }
 
 impl UptraceBuilder {
-    pub fn init_tracer<R: sdk::trace::TraceRuntime>(
+    /// Builds the tracer provider and installs it as the global tracer provider.
+    pub fn init_tracer(&mut self, dsn: &Dsn) -> Result<SdkTracerProvider, Error> {
+        let meter = uptrace::scope_meter(global::meter_provider().as_ref());
+        let provider = self.build_tracer_provider(dsn, &meter)?;
+        global::set_tracer_provider(provider.clone());
+        Ok(provider)
+    }
+
+    /// Builds the meter provider and installs it as the global meter provider.
+    pub fn init_metrics(&mut self, dsn: &Dsn) -> Result<SdkMeterProvider, Error> {
+        let provider = self.build_meter_provider(dsn)?;
+        global::set_meter_provider(provider.clone());
+        Ok(provider.sdk_provider().clone())
+    }
+
+    fn build_tracer_provider(
         &mut self,
         dsn: &Dsn,
-        runtime: R,
-    ) -> Result<sdk::trace::Tracer, Error> {
-        let mut metadata = MetadataMap::with_capacity(1);
-        metadata.insert("uptrace-dsn", self.dsn.parse().unwrap());
-
-        let exporter_builder: SpanExporterBuilder = opentelemetry_otlp::new_exporter()
-            .tonic()
-            .with_endpoint(dsn.otlp_grpc_addr())
-            .with_timeout(Duration::from_secs(5))
-            .with_metadata(metadata)
-            .into();
-        let span_exporter = exporter_builder
-            .build_span_exporter()
+        meter: &Meter,
+    ) -> Result<SdkTracerProvider, Error> {
+        let exporter = SpanExporter::builder().with_tonic();
+        let exporter = self
+            .tonic_config(exporter, dsn, Duration::from_secs(5))?
+            .build()
             .map_err(|e| Error::TraceBuildError(Box::new(e)))?;
-        let resource = self.build_resource();
 
-        Ok(build_batch_with_exporter(
-            span_exporter,
-            self.trace_config.with_resource(resource),
-            runtime,
-            self.batch_config,
-        ))
+        let batch_config = self
+            .batch_config
+            .take()
+            .unwrap_or_else(|| default_batch_config().build());
+        let span_limits = self.span_limits.unwrap_or_else(SpanLimits::from_env);
+
+        let processor = BatchSpanProcessor::builder(exporter)
+            .with_batch_config(batch_config)
+            .build();
+        let processor = LimitsSpanProcessor::new(processor, span_limits, meter);
+
+        let mut builder = SdkTracerProvider::builder()
+            .with_resource(self.build_resource()?)
+            .with_span_limits(span_limits.into());
+        builder = match self.redactor.clone() {
+            Some(redactor) => {
+                builder.with_span_processor(RedactingSpanProcessor::new(processor, redactor))
+            }
+            None => builder.with_span_processor(processor),
+        };
+        if let Some(config) = self.span_metrics.clone() {
+            // The dimensions are taken from the redacted span attributes.
+            let processor = SpanMetricsProcessor::new(config, meter);
+            builder = match self.redactor.clone() {
+                Some(redactor) => {
+                    builder.with_span_processor(RedactingSpanProcessor::new(processor, redactor))
+                }
+                None => builder.with_span_processor(processor),
+            };
+        }
+        if let Some(sampler) = self.sampler.take() {
+            builder = builder.with_sampler(sampler);
+        }
+        if let Some(id_generator) = self.id_generator.take() {
+            builder = builder.with_id_generator(BoxedIdGenerator(id_generator));
+        }
+        Ok(builder.build())
     }
 
-    pub fn init_metrics(&mut self, dsn: &Dsn) -> Result<BasicController, Error> {
-        let mut metadata = MetadataMap::with_capacity(1);
-        metadata.insert("uptrace-dsn", self.dsn.parse().unwrap());
+    fn build_meter_provider(&mut self, dsn: &Dsn) -> Result<meter::MeterProvider, Error> {
+        let mut builder = SdkMeterProvider::builder().with_resource(self.build_resource()?);
+        let mut reservoirs = None;
+
+        if self.metrics.otlp_export {
+            let filter = self.metrics.exemplar_filter();
+            if filter == ExemplarFilter::AlwaysOff {
+                let exporter = MetricExporter::builder().with_tonic();
+                let exporter = self
+                    .tonic_config(exporter, dsn, self.metrics.timeout)?
+                    .with_temporality(self.metrics.temporality)
+                    .build()
+                    .map_err(|e| Error::MetricsBuildError(Box::new(e)))?;
+                builder = builder.with_reader(self.metrics.reader(exporter)?);
+            } else {
+                // The SDK exporter can't carry exemplars, so the request is
+                // built and sent by the exemplars exporter instead.
+                let exemplar_reservoirs =
+                    Arc::new(exemplars::Reservoirs::new(filter, &self.metrics));
+                let exporter = exemplars::MetricExporter::new(
+                    self.tonic_channel(dsn, self.metrics.timeout)?,
+                    self.build_metadata()?,
+                    exemplars::compression_from_env()?,
+                    self.metrics.temporality,
+                    exemplar_reservoirs.clone(),
+                    tokio::runtime::Handle::try_current()
+                        .map_err(|e| Error::Runtime(Box::new(e)))?,
+                );
+                builder = builder.with_reader(self.metrics.reader(exporter)?);
+                reservoirs = Some(exemplar_reservoirs);
+            }
+        }
+        #[cfg(feature = "prometheus")]
+        let mut prometheus_addr = None;
+        #[cfg(feature = "prometheus")]
+        if let Some(config) = &self.metrics.prometheus {
+            let (reader, addr) = prometheus::serve(config)?;
+            builder = builder.with_reader(reader);
+            prometheus_addr = Some(addr);
+        }
 
-        let export_config = ExportConfig {
-            endpoint: dsn.otlp_grpc_addr(),
-            timeout: Duration::from_secs(10),
-            protocol: Protocol::Grpc,
+        let provider = self.metrics.apply(builder)?.build();
+        let limits = CardinalityLimits::new(&self.metrics, &uptrace::scope_meter(&provider));
+        let provider = meter::MeterProvider::new(provider, reservoirs, limits.map(Arc::new));
+        #[cfg(feature = "prometheus")]
+        let provider = match prometheus_addr {
+            Some(addr) => provider.with_prometheus_addr(addr),
+            None => provider,
         };
-        let exporter = opentelemetry_otlp::new_exporter()
-            .tonic()
-            .with_export_config(export_config)
-            .with_metadata(metadata);
-
-        let ctrl = opentelemetry_otlp::new_pipeline()
-            .metrics(
-                selectors::simple::inexpensive(),
-                delta_temporality_selector(),
-                runtime::Tokio,
-            )
-            .with_exporter(exporter)
-            .with_period(Duration::from_secs(15))
-            .with_timeout(Duration::from_secs(5))
+        Ok(provider)
+    }
+
+    fn build_logger_provider(
+        &mut self,
+        dsn: &Dsn,
+        meter: &Meter,
+    ) -> Result<logs::LoggerProvider, Error> {
+        let exporter = LogExporter::builder().with_tonic();
+        let exporter = self
+            .tonic_config(exporter, dsn, Duration::from_secs(10))?
             .build()
-            .map_err(|e| Error::MetricsBuildError(Box::new(e)))?;
+            .map_err(|e| Error::LogsBuildError(Box::new(e)))?;
+
+        let provider = SdkLoggerProvider::builder()
+            .with_resource(self.build_resource()?)
+            .with_batch_exporter(exporter)
+            .build();
+        Ok(logs::LoggerProvider::new(
+            provider,
+            self.log_limits.unwrap_or_else(LogLimits::from_env),
+            self.redactor.clone(),
+            meter,
+        ))
+    }
+
+    /// Points the exporter at the DSN, with TLS for https endpoints.
+    fn tonic_config<B>(&self, builder: B, dsn: &Dsn, timeout: Duration) -> Result<B, Error>
+    where
+        B: WithExportConfig + WithTonicConfig,
+    {
+        let endpoint = dsn.otlp_grpc_addr();
+        let https = endpoint.starts_with("https:");
+        let mut builder = builder
+            .with_endpoint(endpoint)
+            .with_timeout(timeout)
+            .with_metadata(self.build_metadata()?);
+        if https {
+            builder = builder.with_tls_config(ClientTlsConfig::new().with_native_roots());
+        }
+        Ok(builder)
+    }
 
-        Ok(ctrl)
+    /// Connects lazily to the DSN, with TLS for https endpoints.
+    fn tonic_channel(&self, dsn: &Dsn, timeout: Duration) -> Result<Channel, Error> {
+        let endpoint = dsn.otlp_grpc_addr();
+        let https = endpoint.starts_with("https:");
+        let mut endpoint = Channel::from_shared(endpoint)
+            .map_err(|e| Error::MetricsBuildError(Box::new(e)))?
+            .timeout(timeout);
+        if https {
+            endpoint = endpoint
+                .tls_config(ClientTlsConfig::new().with_native_roots())
+                .map_err(|e| Error::Tls(Box::new(e)))?;
+        }
+        Ok(endpoint.connect_lazy())
+    }
+
+    fn build_metadata(&self) -> Result<MetadataMap, Error> {
+        let dsn = self.dsn.parse().map_err(|e| Error::InvalidDsn {
+            dsn: dsn::mask_token(&self.dsn),
+            reason: format!("{}", e),
+        })?;
+
+        let mut metadata = MetadataMap::with_capacity(1);
+        metadata.insert("uptrace-dsn", dsn);
+        Ok(metadata)
     }
 
-    fn build_resource(&self) -> Resource {
+    /// Returns the explicit propagators, the ones from `OTEL_PROPAGATORS`
+    /// or the defaults, in this order of precedence.
+    fn propagators(&self) -> Vec<Propagator> {
+        match &self.propagators {
+            Some(propagators) => propagators.clone(),
+            None => match std::env::var(propagation::OTEL_PROPAGATORS) {
+                Ok(value) => propagation::parse_propagators(&value),
+                Err(_) => Propagator::DEFAULT.to_vec(),
+            },
+        }
+    }
+
+    fn build_resource(&self) -> Result<Resource, Error> {
+        if let Ok(value) = std::env::var("OTEL_RESOURCE_ATTRIBUTES") {
+            check_resource_attributes(&value)?;
+        }
+
         let mut kv = vec![];
 
         if let Ok(host) = hostname::get() {
@@ -226,10 +644,8 @@ impl UptraceBuilder {
                 host.to_str().unwrap_or_default().to_string(),
             ));
         }
-
-        if let Some(service_name) = self.service_name.clone() {
-            kv.push(KeyValue::new("service.name", service_name));
-        }
+        // Later attributes override the earlier ones with the same key.
+        kv.extend(self.resource_attributes.iter().cloned());
 
         if let Some(service_version) = self.service_version.clone() {
             kv.push(KeyValue::new("service.version", service_version));
@@ -242,35 +658,68 @@ impl UptraceBuilder {
             ));
         }
 
-        Resource::from_detectors(
-            Duration::from_secs(0),
-            vec![
-                Box::new(SdkProvidedResourceDetector),
-                Box::new(EnvResourceDetector::new()),
-                Box::new(TelemetryResourceDetector),
-            ],
-        )
-        .merge(&mut Resource::new(kv.into_iter()))
+        // The default detectors read OTEL_SERVICE_NAME and OTEL_RESOURCE_ATTRIBUTES
+        // and add the telemetry.sdk.* attributes.
+        let builder = Resource::builder().with_attributes(kv);
+        Ok(match self.service_name.clone() {
+            Some(service_name) => builder.with_service_name(service_name).build(),
+            None => builder.build(),
+        })
+    }
+}
+
+/// Checks that `OTEL_RESOURCE_ATTRIBUTES` only has `key=value` entries; the
+/// SDK detector silently skips the others.
+fn check_resource_attributes(value: &str) -> Result<(), Error> {
+    for entry in value.split_terminator(',') {
+        match entry.split_once('=') {
+            Some((key, _)) if !key.trim().is_empty() => {}
+            _ => {
+                return Err(Error::ResourceDetection(
+                    format!("invalid OTEL_RESOURCE_ATTRIBUTES entry {entry:?}, expected key=value")
+                        .into(),
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Batch span processor defaults, tuned for Uptrace.
+fn default_batch_config() -> BatchConfigBuilder {
+    BatchConfigBuilder::default()
+        .with_max_queue_size(30000)
+        .with_max_export_batch_size(10000)
+        .with_scheduled_delay(Duration::from_millis(5000))
+}
+
+/// Forwards to a boxed id generator, which the provider builder doesn't accept.
+#[derive(Debug)]
+struct BoxedIdGenerator(Box<dyn IdGenerator>);
+
+impl IdGenerator for BoxedIdGenerator {
+    fn new_trace_id(&self) -> TraceId {
+        self.0.new_trace_id()
+    }
+
+    fn new_span_id(&self) -> SpanId {
+        self.0.new_span_id()
     }
 }
 
-fn build_batch_with_exporter<R: sdk::trace::TraceRuntime>(
-    exporter: SpanExporter,
-    trace_config: sdk::trace::Config,
-    runtime: R,
-    batch_config: sdk::trace::BatchConfig,
-) -> sdk::trace::Tracer {
-    let batch_processor = sdk::trace::BatchSpanProcessor::builder(exporter, runtime)
-        .with_batch_config(batch_config)
-        .build();
-
-    let provider_builder = sdk::trace::TracerProvider::builder();
-    let provider = provider_builder
-        .with_span_processor(batch_processor)
-        .with_config(trace_config)
-        .build();
-
-    let tracer = provider.versioned_tracer("uptrace-rust", Some(env!("CARGO_PKG_VERSION")), None);
-    let _ = global::set_tracer_provider(provider);
-    tracer
+#[cfg(test)]
+mod tests {
+    use super::check_resource_attributes;
+    use crate::ErrorKind;
+
+    #[test]
+    fn resource_attributes() {
+        assert!(check_resource_attributes("service.name=api, team = core").is_ok());
+        assert!(check_resource_attributes("").is_ok());
+
+        let err = check_resource_attributes("service.name=api,core").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ResourceDetection);
+        let err = check_resource_attributes("=api").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ResourceDetection);
+    }
 }
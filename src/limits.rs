@@ -0,0 +1,780 @@
+//! Limits on the number and size of span attributes, events and links, and of
+//! log record attributes.
+//!
+//! Defaults can be overridden with the standard environment variables:
+//!
+//! - `OTEL_ATTRIBUTE_COUNT_LIMIT`, `OTEL_ATTRIBUTE_VALUE_LENGTH_LIMIT`
+//! - `OTEL_SPAN_ATTRIBUTE_COUNT_LIMIT`, `OTEL_SPAN_ATTRIBUTE_VALUE_LENGTH_LIMIT`
+//! - `OTEL_SPAN_EVENT_COUNT_LIMIT`, `OTEL_SPAN_LINK_COUNT_LIMIT`
+//! - `OTEL_EVENT_ATTRIBUTE_COUNT_LIMIT`, `OTEL_LINK_ATTRIBUTE_COUNT_LIMIT`
+//! - `OTEL_LOGRECORD_ATTRIBUTE_COUNT_LIMIT`, `OTEL_LOGRECORD_ATTRIBUTE_VALUE_LENGTH_LIMIT`
+//!
+//! Span and log record specific variables take precedence over the generic
+//! `OTEL_ATTRIBUTE_*` ones.
+
+use std::borrow::Cow;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use opentelemetry::logs::{AnyValue, LogRecord, Logger, LoggerProvider, Severity};
+use opentelemetry::metrics::{Counter, Meter};
+use opentelemetry::trace::{SpanId, TraceFlags, TraceId};
+use opentelemetry::{Array, Context, InstrumentationScope, Key, KeyValue, StringValue, Value};
+use opentelemetry_sdk::error::OTelSdkResult;
+use opentelemetry_sdk::trace::{Span, SpanData, SpanProcessor};
+use opentelemetry_sdk::Resource;
+
+use crate::error_handler::{self, OtelError};
+
+/// Default maximum length of string attribute values, in bytes.
+pub const DEFAULT_ATTRIBUTE_VALUE_LENGTH_LIMIT: usize = 8192;
+const DEFAULT_COUNT_LIMIT: u32 = 128;
+
+/// Span limits applied by the tracer provider configured by
+/// [`UptraceBuilder`](crate::UptraceBuilder).
+///
+/// Attributes, events and links over the count limits are dropped by the SDK
+/// and reported as dropped counts in the exported span; [`LimitsSpanProcessor`]
+/// enforces the event and link attribute limits again for spans of providers
+/// configured with larger ones. String values over
+/// [`max_attribute_value_length`](SpanLimits::max_attribute_value_length) are
+/// truncated on a character boundary when the span ends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpanLimits {
+    pub max_attributes_per_span: u32,
+    pub max_events_per_span: u32,
+    pub max_links_per_span: u32,
+    pub max_attributes_per_event: u32,
+    pub max_attributes_per_link: u32,
+    /// Maximum length of string values in bytes, `None` for no limit.
+    pub max_attribute_value_length: Option<usize>,
+}
+
+impl Default for SpanLimits {
+    fn default() -> Self {
+        Self {
+            max_attributes_per_span: DEFAULT_COUNT_LIMIT,
+            max_events_per_span: DEFAULT_COUNT_LIMIT,
+            max_links_per_span: DEFAULT_COUNT_LIMIT,
+            max_attributes_per_event: DEFAULT_COUNT_LIMIT,
+            max_attributes_per_link: DEFAULT_COUNT_LIMIT,
+            max_attribute_value_length: Some(DEFAULT_ATTRIBUTE_VALUE_LENGTH_LIMIT),
+        }
+    }
+}
+
+impl SpanLimits {
+    /// Returns the default limits overridden by the `OTEL_*_LIMIT` env vars.
+    pub fn from_env() -> Self {
+        Self::from_lookup(|name| std::env::var(name).ok())
+    }
+
+    fn from_lookup<F: Fn(&str) -> Option<String>>(lookup: F) -> Self {
+        let var = |names: &[&str]| lookup_limit(&lookup, names);
+
+        let mut limits = Self::default();
+        if let Some(n) = var(&[
+            "OTEL_SPAN_ATTRIBUTE_COUNT_LIMIT",
+            "OTEL_ATTRIBUTE_COUNT_LIMIT",
+        ]) {
+            limits.max_attributes_per_span = n;
+        }
+        if let Some(n) = var(&["OTEL_SPAN_EVENT_COUNT_LIMIT"]) {
+            limits.max_events_per_span = n;
+        }
+        if let Some(n) = var(&["OTEL_SPAN_LINK_COUNT_LIMIT"]) {
+            limits.max_links_per_span = n;
+        }
+        if let Some(n) = var(&[
+            "OTEL_EVENT_ATTRIBUTE_COUNT_LIMIT",
+            "OTEL_ATTRIBUTE_COUNT_LIMIT",
+        ]) {
+            limits.max_attributes_per_event = n;
+        }
+        if let Some(n) = var(&[
+            "OTEL_LINK_ATTRIBUTE_COUNT_LIMIT",
+            "OTEL_ATTRIBUTE_COUNT_LIMIT",
+        ]) {
+            limits.max_attributes_per_link = n;
+        }
+        if let Some(n) = var(&[
+            "OTEL_SPAN_ATTRIBUTE_VALUE_LENGTH_LIMIT",
+            "OTEL_ATTRIBUTE_VALUE_LENGTH_LIMIT",
+        ]) {
+            limits.max_attribute_value_length = Some(n as usize);
+        }
+        limits
+    }
+
+    pub fn with_max_attributes_per_span(mut self, n: u32) -> Self {
+        self.max_attributes_per_span = n;
+        self
+    }
+
+    pub fn with_max_events_per_span(mut self, n: u32) -> Self {
+        self.max_events_per_span = n;
+        self
+    }
+
+    pub fn with_max_links_per_span(mut self, n: u32) -> Self {
+        self.max_links_per_span = n;
+        self
+    }
+
+    pub fn with_max_attributes_per_event(mut self, n: u32) -> Self {
+        self.max_attributes_per_event = n;
+        self
+    }
+
+    pub fn with_max_attributes_per_link(mut self, n: u32) -> Self {
+        self.max_attributes_per_link = n;
+        self
+    }
+
+    /// Set the maximum length of string values in bytes, `None` for no limit.
+    pub fn with_max_attribute_value_length(mut self, n: Option<usize>) -> Self {
+        self.max_attribute_value_length = n;
+        self
+    }
+}
+
+impl From<SpanLimits> for opentelemetry_sdk::trace::SpanLimits {
+    fn from(limits: SpanLimits) -> Self {
+        Self {
+            max_events_per_span: limits.max_events_per_span,
+            max_attributes_per_span: limits.max_attributes_per_span,
+            max_links_per_span: limits.max_links_per_span,
+            max_attributes_per_event: limits.max_attributes_per_event,
+            max_attributes_per_link: limits.max_attributes_per_link,
+        }
+    }
+}
+
+/// Log record limits applied by the logger provider configured by
+/// [`UptraceBuilder`](crate::UptraceBuilder).
+///
+/// Attributes over the count limit are dropped and string values over
+/// [`max_attribute_value_length`](LogLimits::max_attribute_value_length) are
+/// truncated on a character boundary when they are added to the record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogLimits {
+    pub max_attributes_per_log_record: u32,
+    /// Maximum length of string values in bytes, `None` for no limit.
+    pub max_attribute_value_length: Option<usize>,
+}
+
+impl Default for LogLimits {
+    fn default() -> Self {
+        Self {
+            max_attributes_per_log_record: DEFAULT_COUNT_LIMIT,
+            max_attribute_value_length: Some(DEFAULT_ATTRIBUTE_VALUE_LENGTH_LIMIT),
+        }
+    }
+}
+
+impl LogLimits {
+    /// Returns the default limits overridden by the `OTEL_*_LIMIT` env vars.
+    pub fn from_env() -> Self {
+        Self::from_lookup(|name| std::env::var(name).ok())
+    }
+
+    fn from_lookup<F: Fn(&str) -> Option<String>>(lookup: F) -> Self {
+        let var = |names: &[&str]| lookup_limit(&lookup, names);
+
+        let mut limits = Self::default();
+        if let Some(n) = var(&[
+            "OTEL_LOGRECORD_ATTRIBUTE_COUNT_LIMIT",
+            "OTEL_ATTRIBUTE_COUNT_LIMIT",
+        ]) {
+            limits.max_attributes_per_log_record = n;
+        }
+        if let Some(n) = var(&[
+            "OTEL_LOGRECORD_ATTRIBUTE_VALUE_LENGTH_LIMIT",
+            "OTEL_ATTRIBUTE_VALUE_LENGTH_LIMIT",
+        ]) {
+            limits.max_attribute_value_length = Some(n as usize);
+        }
+        limits
+    }
+
+    pub fn with_max_attributes_per_log_record(mut self, n: u32) -> Self {
+        self.max_attributes_per_log_record = n;
+        self
+    }
+
+    /// Set the maximum length of string values in bytes, `None` for no limit.
+    pub fn with_max_attribute_value_length(mut self, n: Option<usize>) -> Self {
+        self.max_attribute_value_length = n;
+        self
+    }
+}
+
+/// Returns the limit from the first env var in `names` that is set.
+fn lookup_limit<F: Fn(&str) -> Option<String>>(lookup: &F, names: &[&str]) -> Option<u32> {
+    names
+        .iter()
+        .find_map(|name| lookup(name).map(|value| (*name, value)))
+        .and_then(|(name, value)| parse_limit(name, &value))
+}
+
+fn parse_limit(name: &str, value: &str) -> Option<u32> {
+    match u32::from_str(value.trim()) {
+        Ok(n) => Some(n),
+        Err(_) => {
+            error_handler::handle_error(OtelError::internal(
+                "Limits.InvalidEnvVar",
+                format!("invalid {}: {:?}, using the default", name, value),
+            ));
+            None
+        }
+    }
+}
+
+/// Truncates `s` to at most `max_len` bytes on a character boundary.
+/// Returns `None` if `s` is short enough.
+fn truncate(s: &StringValue, max_len: usize) -> Option<StringValue> {
+    let s = s.as_str();
+    if s.len() <= max_len {
+        return None;
+    }
+    let mut end = max_len;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    Some(s[..end].to_string().into())
+}
+
+/// Returns the truncated value and the number of truncated strings.
+fn truncate_value(value: &Value, max_len: usize) -> Option<(Value, u64)> {
+    match value {
+        Value::String(s) => truncate(s, max_len).map(|s| (Value::String(s), 1)),
+        Value::Array(Array::String(values)) => {
+            let mut truncated = 0;
+            let values = values
+                .iter()
+                .map(|s| match truncate(s, max_len) {
+                    Some(s) => {
+                        truncated += 1;
+                        s
+                    }
+                    None => s.clone(),
+                })
+                .collect();
+            (truncated > 0).then_some((Value::Array(Array::String(values)), truncated))
+        }
+        _ => None,
+    }
+}
+
+/// Like [`truncate_value`] for log record values, including nested lists and maps.
+fn truncate_any_value(value: &AnyValue, max_len: usize) -> Option<(AnyValue, u64)> {
+    match value {
+        AnyValue::String(s) => truncate(s, max_len).map(|s| (AnyValue::String(s), 1)),
+        AnyValue::ListAny(values) => {
+            let mut truncated = 0;
+            let values = values
+                .iter()
+                .map(|value| match truncate_any_value(value, max_len) {
+                    Some((value, n)) => {
+                        truncated += n;
+                        value
+                    }
+                    None => value.clone(),
+                })
+                .collect();
+            (truncated > 0).then(|| (AnyValue::ListAny(Box::new(values)), truncated))
+        }
+        AnyValue::Map(map) => {
+            let mut truncated = 0;
+            let map = map
+                .iter()
+                .map(|(key, value)| match truncate_any_value(value, max_len) {
+                    Some((value, n)) => {
+                        truncated += n;
+                        (key.clone(), value)
+                    }
+                    None => (key.clone(), value.clone()),
+                })
+                .collect();
+            (truncated > 0).then(|| (AnyValue::Map(Box::new(map)), truncated))
+        }
+        _ => None,
+    }
+}
+
+fn truncate_attributes(attrs: &mut [KeyValue], max_len: usize) -> u64 {
+    let mut count = 0;
+    for kv in attrs {
+        if let Some((value, n)) = truncate_value(&kv.value, max_len) {
+            kv.value = value;
+            count += n;
+        }
+    }
+    count
+}
+
+/// Drops the attributes over `max` and returns how many were dropped.
+fn drop_attributes(attrs: &mut Vec<KeyValue>, max: u32) -> u32 {
+    let max = max as usize;
+    if attrs.len() <= max {
+        return 0;
+    }
+    let dropped = attrs.len() - max;
+    attrs.truncate(max);
+    dropped as u32
+}
+
+/// Span processor that enforces [`SpanLimits::max_attribute_value_length`] and
+/// the event and link attribute count limits, and counts truncated attributes
+/// and dropped attributes, events and links with the
+/// `uptrace.span.truncated_attributes` and `uptrace.span.dropped` counters.
+#[derive(Debug)]
+pub struct LimitsSpanProcessor<P> {
+    inner: P,
+    limits: SpanLimits,
+    truncated: Counter<u64>,
+    dropped: Counter<u64>,
+}
+
+impl<P: SpanProcessor> LimitsSpanProcessor<P> {
+    /// Creates the processor, registering its counters with the given meter.
+    pub fn new(inner: P, limits: SpanLimits, meter: &Meter) -> Self {
+        Self {
+            inner,
+            limits,
+            truncated: meter
+                .u64_counter("uptrace.span.truncated_attributes")
+                .with_description("Number of span attribute values truncated to the length limit")
+                .build(),
+            dropped: meter
+                .u64_counter("uptrace.span.dropped")
+                .with_description("Number of span attributes, events and links dropped by limits")
+                .build(),
+        }
+    }
+
+    /// Drops the event and link attributes over the limits, updating their
+    /// dropped counts, and returns the total dropped counts of events and links.
+    fn drop_attributes(&self, span: &mut SpanData) -> (u32, u32) {
+        let mut event_attributes = 0;
+        for event in span.events.events.iter_mut() {
+            event.dropped_attributes_count +=
+                drop_attributes(&mut event.attributes, self.limits.max_attributes_per_event);
+            event_attributes += event.dropped_attributes_count;
+        }
+        let mut link_attributes = 0;
+        for link in span.links.links.iter_mut() {
+            link.dropped_attributes_count +=
+                drop_attributes(&mut link.attributes, self.limits.max_attributes_per_link);
+            link_attributes += link.dropped_attributes_count;
+        }
+        (event_attributes, link_attributes)
+    }
+
+    fn truncate(&self, span: &mut SpanData, max_len: usize) -> u64 {
+        let mut count = truncate_attributes(&mut span.attributes, max_len);
+        for event in span.events.events.iter_mut() {
+            count += truncate_attributes(&mut event.attributes, max_len);
+        }
+        for link in span.links.links.iter_mut() {
+            count += truncate_attributes(&mut link.attributes, max_len);
+        }
+        count
+    }
+}
+
+impl<P: SpanProcessor> SpanProcessor for LimitsSpanProcessor<P> {
+    fn on_start(&self, span: &mut Span, cx: &Context) {
+        self.inner.on_start(span, cx)
+    }
+
+    fn on_end(&self, mut span: SpanData) {
+        let (event_attributes, link_attributes) = self.drop_attributes(&mut span);
+        let dropped = [
+            ("attribute", span.dropped_attributes_count),
+            ("event", span.events.dropped_count),
+            ("link", span.links.dropped_count),
+            ("event_attribute", event_attributes),
+            ("link_attribute", link_attributes),
+        ];
+        for (kind, count) in dropped {
+            if count > 0 {
+                self.dropped
+                    .add(count as u64, &[KeyValue::new("type", kind)]);
+            }
+        }
+
+        if let Some(max_len) = self.limits.max_attribute_value_length {
+            let truncated = self.truncate(&mut span, max_len);
+            if truncated > 0 {
+                self.truncated.add(truncated, &[]);
+            }
+        }
+
+        self.inner.on_end(span)
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource)
+    }
+}
+
+#[derive(Debug)]
+struct LogProcessing {
+    limits: LogLimits,
+    truncated: Counter<u64>,
+    dropped: Counter<u64>,
+}
+
+/// Logger provider that enforces the [`LogLimits`] as attributes are added to
+/// the records of the inner provider, and counts truncated and dropped
+/// attributes with the `uptrace.log.truncated_attributes` and
+/// `uptrace.log.dropped` counters.
+#[derive(Debug, Clone)]
+pub struct LimitsLoggerProvider<P> {
+    inner: P,
+    processing: Arc<LogProcessing>,
+}
+
+impl<P: LoggerProvider> LimitsLoggerProvider<P> {
+    /// Creates the provider, registering its counters with the given meter.
+    pub fn new(inner: P, limits: LogLimits, meter: &Meter) -> Self {
+        let processing = LogProcessing {
+            limits,
+            truncated: meter
+                .u64_counter("uptrace.log.truncated_attributes")
+                .with_description("Number of log attribute values truncated to the length limit")
+                .build(),
+            dropped: meter
+                .u64_counter("uptrace.log.dropped")
+                .with_description("Number of log attributes dropped by limits")
+                .build(),
+        };
+        Self {
+            inner,
+            processing: Arc::new(processing),
+        }
+    }
+
+    /// Returns the inner provider.
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+}
+
+impl<P: LoggerProvider> LoggerProvider for LimitsLoggerProvider<P> {
+    type Logger = LimitsLogger<P::Logger>;
+
+    fn logger_with_scope(&self, scope: InstrumentationScope) -> Self::Logger {
+        LimitsLogger {
+            inner: self.inner.logger_with_scope(scope),
+            processing: self.processing.clone(),
+        }
+    }
+}
+
+/// Logger created by [`LimitsLoggerProvider`].
+#[derive(Debug)]
+pub struct LimitsLogger<L> {
+    inner: L,
+    processing: Arc<LogProcessing>,
+}
+
+impl<L: Logger> Logger for LimitsLogger<L> {
+    type LogRecord = LimitsLogRecord<L::LogRecord>;
+
+    fn create_log_record(&self) -> Self::LogRecord {
+        LimitsLogRecord {
+            inner: self.inner.create_log_record(),
+            processing: self.processing.clone(),
+            attributes: 0,
+            truncated: 0,
+            dropped: 0,
+        }
+    }
+
+    fn emit(&self, record: Self::LogRecord) {
+        if record.dropped > 0 {
+            let attrs = [KeyValue::new("type", "attribute")];
+            self.processing.dropped.add(record.dropped, &attrs);
+        }
+        if record.truncated > 0 {
+            self.processing.truncated.add(record.truncated, &[]);
+        }
+        self.inner.emit(record.inner)
+    }
+}
+
+/// Log record created by [`LimitsLogger`].
+#[derive(Debug)]
+pub struct LimitsLogRecord<R> {
+    inner: R,
+    processing: Arc<LogProcessing>,
+    attributes: u32,
+    truncated: u64,
+    dropped: u64,
+}
+
+impl<R: LogRecord> LogRecord for LimitsLogRecord<R> {
+    fn set_event_name(&mut self, name: &'static str) {
+        self.inner.set_event_name(name)
+    }
+
+    fn set_target<T>(&mut self, target: T)
+    where
+        T: Into<Cow<'static, str>>,
+    {
+        self.inner.set_target(target)
+    }
+
+    fn set_timestamp(&mut self, timestamp: SystemTime) {
+        self.inner.set_timestamp(timestamp)
+    }
+
+    fn set_observed_timestamp(&mut self, timestamp: SystemTime) {
+        self.inner.set_observed_timestamp(timestamp)
+    }
+
+    fn set_severity_text(&mut self, text: &'static str) {
+        self.inner.set_severity_text(text)
+    }
+
+    fn set_severity_number(&mut self, number: Severity) {
+        self.inner.set_severity_number(number)
+    }
+
+    fn set_body(&mut self, body: AnyValue) {
+        self.inner.set_body(body)
+    }
+
+    fn add_attributes<I, K, V>(&mut self, attributes: I)
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<Key>,
+        V: Into<AnyValue>,
+    {
+        for (key, value) in attributes {
+            self.add_attribute(key, value);
+        }
+    }
+
+    fn add_attribute<K, V>(&mut self, key: K, value: V)
+    where
+        K: Into<Key>,
+        V: Into<AnyValue>,
+    {
+        let limits = &self.processing.limits;
+        if self.attributes >= limits.max_attributes_per_log_record {
+            self.dropped += 1;
+            return;
+        }
+        self.attributes += 1;
+
+        let mut value = value.into();
+        if let Some(max_len) = limits.max_attribute_value_length {
+            if let Some((truncated, n)) = truncate_any_value(&value, max_len) {
+                value = truncated;
+                self.truncated += n;
+            }
+        }
+        self.inner.add_attribute(key, value)
+    }
+
+    fn set_trace_context(
+        &mut self,
+        trace_id: TraceId,
+        span_id: SpanId,
+        trace_flags: Option<TraceFlags>,
+    ) {
+        self.inner.set_trace_context(trace_id, span_id, trace_flags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use opentelemetry::logs::{AnyValue, LogRecord as _, Logger as _, LoggerProvider as _};
+    use opentelemetry::metrics::MeterProvider;
+    use opentelemetry::trace::{
+        Span as _, SpanContext, SpanId, TraceFlags, TraceId, TraceState, Tracer,
+        TracerProvider as _,
+    };
+    use opentelemetry::{Key, KeyValue, Value};
+    use opentelemetry_sdk::logs::{InMemoryLogExporter, SdkLoggerProvider};
+    use opentelemetry_sdk::metrics::SdkMeterProvider;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+
+    use super::{LimitsLoggerProvider, LimitsSpanProcessor, LogLimits, SpanLimits};
+    use crate::testing::Collect;
+
+    fn env(vars: &[(&'static str, &'static str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<&str, &str> = vars.iter().copied().collect();
+        move |name| vars.get(name).map(|v| v.to_string())
+    }
+
+    #[test]
+    fn from_env() {
+        let limits = SpanLimits::from_lookup(env(&[
+            ("OTEL_ATTRIBUTE_COUNT_LIMIT", "64"),
+            ("OTEL_EVENT_ATTRIBUTE_COUNT_LIMIT", "16"),
+            ("OTEL_SPAN_EVENT_COUNT_LIMIT", "invalid"),
+            ("OTEL_ATTRIBUTE_VALUE_LENGTH_LIMIT", "1024"),
+        ]));
+
+        assert_eq!(limits.max_attributes_per_span, 64);
+        assert_eq!(limits.max_attributes_per_event, 16);
+        assert_eq!(limits.max_attributes_per_link, 64);
+        assert_eq!(limits.max_events_per_span, 128);
+        assert_eq!(limits.max_attribute_value_length, Some(1024));
+    }
+
+    #[test]
+    fn log_limits_from_env() {
+        let limits = LogLimits::from_lookup(env(&[
+            ("OTEL_ATTRIBUTE_COUNT_LIMIT", "64"),
+            ("OTEL_LOGRECORD_ATTRIBUTE_COUNT_LIMIT", "32"),
+            ("OTEL_ATTRIBUTE_VALUE_LENGTH_LIMIT", "1024"),
+        ]));
+
+        assert_eq!(limits.max_attributes_per_log_record, 32);
+        assert_eq!(limits.max_attribute_value_length, Some(1024));
+    }
+
+    #[test]
+    fn limits() {
+        let limits = SpanLimits::default()
+            .with_max_attributes_per_span(2)
+            .with_max_attribute_value_length(Some(5));
+        let spans = Collect::default();
+        let provider = SdkTracerProvider::builder()
+            .with_span_limits(limits.into())
+            .with_span_processor(LimitsSpanProcessor::new(
+                spans.clone(),
+                limits,
+                &SdkMeterProvider::default().meter("test"),
+            ))
+            .build();
+
+        let mut span = provider.tracer("test").start("SELECT");
+        span.set_attribute(KeyValue::new("db.statement", "SELECT ü FROM t"));
+        span.set_attribute(KeyValue::new("db.system", "mysql"));
+        span.set_attribute(KeyValue::new("db.name", "test"));
+        span.add_event("query", vec![KeyValue::new("message", "123456")]);
+        span.end();
+
+        let spans = spans.spans();
+        let span = &spans[0];
+        assert_eq!(span.attributes.len(), 2);
+        assert_eq!(span.dropped_attributes_count, 1);
+        let statement = span
+            .attributes
+            .iter()
+            .find(|kv| kv.key == Key::new("db.statement"))
+            .unwrap();
+        assert_eq!(statement.value, Value::from("SELEC"));
+        let event = span.events.iter().next().unwrap();
+        assert_eq!(event.attributes[0].value, Value::from("12345"));
+    }
+
+    #[test]
+    fn event_and_link_attributes() {
+        // The SDK keeps up to 128 attributes, so the processor drops the rest.
+        let limits = SpanLimits::default()
+            .with_max_attributes_per_event(1)
+            .with_max_attributes_per_link(1)
+            .with_max_attribute_value_length(Some(5));
+        let spans = Collect::default();
+        let provider = SdkTracerProvider::builder()
+            .with_span_processor(LimitsSpanProcessor::new(
+                spans.clone(),
+                limits,
+                &SdkMeterProvider::default().meter("test"),
+            ))
+            .build();
+
+        let linked = SpanContext::new(
+            TraceId::from(1),
+            SpanId::from(1),
+            TraceFlags::SAMPLED,
+            false,
+            TraceState::NONE,
+        );
+        let mut span = provider.tracer("test").start("SELECT");
+        span.add_link(
+            linked,
+            vec![
+                KeyValue::new("db.statement", "SELECT 1"),
+                KeyValue::new("db.system", "mysql"),
+            ],
+        );
+        span.add_event(
+            "query",
+            vec![KeyValue::new("a", "1"), KeyValue::new("b", "2")],
+        );
+        span.end();
+
+        let spans = spans.spans();
+        let link = spans[0].links.iter().next().unwrap();
+        assert_eq!(
+            link.attributes,
+            vec![KeyValue::new("db.statement", "SELEC")]
+        );
+        assert_eq!(link.dropped_attributes_count, 1);
+        let event = spans[0].events.iter().next().unwrap();
+        assert_eq!(event.attributes, vec![KeyValue::new("a", "1")]);
+        assert_eq!(event.dropped_attributes_count, 1);
+    }
+
+    #[test]
+    fn logger_provider() {
+        let exporter = InMemoryLogExporter::default();
+        let sdk_provider = SdkLoggerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let provider = LimitsLoggerProvider::new(
+            sdk_provider,
+            LogLimits::default()
+                .with_max_attributes_per_log_record(2)
+                .with_max_attribute_value_length(Some(5)),
+            &SdkMeterProvider::default().meter("test"),
+        );
+
+        let logger = provider.logger("test");
+        let mut record = logger.create_log_record();
+        record.add_attributes([
+            ("list", AnyValue::ListAny(Box::new(vec!["aaaaaaa".into()]))),
+            ("name", AnyValue::from("opentelemetry")),
+            ("event_id", AnyValue::from(20)),
+        ]);
+        logger.emit(record);
+
+        let logs = exporter.get_emitted_logs().unwrap();
+        let attrs: Vec<_> = logs[0].record.attributes_iter().cloned().collect();
+        assert_eq!(
+            attrs,
+            vec![
+                (
+                    Key::new("list"),
+                    AnyValue::ListAny(Box::new(vec!["aaaaa".into()]))
+                ),
+                (Key::new("name"), AnyValue::from("opent")),
+            ]
+        );
+    }
+
+    #[test]
+    fn char_boundary() {
+        let s = "aü".into();
+        assert_eq!(super::truncate(&s, 2).unwrap().as_str(), "a");
+    }
+}
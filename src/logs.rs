@@ -0,0 +1,113 @@
+//! Log records exported through the logger provider configured by
+//! [`UptraceBuilder`](crate::UptraceBuilder).
+//!
+//! The SDK log processors can't modify attributes that were already added to
+//! a record, so [`LoggerProvider`] wraps the SDK provider and applies the
+//! [`Redactor`] and the [`LogLimits`] while the attributes are being added.
+//! Attributes are redacted before they are truncated, so that truncation
+//! can't cut a value the redaction patterns would match.
+
+use opentelemetry::metrics::Meter;
+use opentelemetry::InstrumentationScope;
+use opentelemetry_sdk::logs::{SdkLogRecord, SdkLogger, SdkLoggerProvider};
+
+use crate::limits::{LimitsLogRecord, LimitsLogger, LimitsLoggerProvider, LogLimits};
+use crate::redact::{RedactingLogRecord, RedactingLogger, RedactingLoggerProvider, Redactor};
+
+/// Logger provider that redacts and limits log record attributes before
+/// passing the records to the SDK provider.
+///
+/// Dropped and truncated attributes are counted with the
+/// `uptrace.log.dropped` and `uptrace.log.truncated_attributes` counters.
+#[derive(Debug, Clone)]
+pub struct LoggerProvider {
+    inner: RedactingLoggerProvider<LimitsLoggerProvider<SdkLoggerProvider>>,
+}
+
+/// Logger created by [`LoggerProvider`].
+pub type Logger = RedactingLogger<LimitsLogger<SdkLogger>>;
+
+/// Log record created by [`Logger`].
+pub type LogRecord = RedactingLogRecord<LimitsLogRecord<SdkLogRecord>>;
+
+impl LoggerProvider {
+    /// Wraps the SDK provider, registering the counters with the given meter.
+    pub fn new(
+        inner: SdkLoggerProvider,
+        limits: LogLimits,
+        redactor: Option<Redactor>,
+        meter: &Meter,
+    ) -> Self {
+        let limited = LimitsLoggerProvider::new(inner, limits, meter);
+        Self {
+            inner: RedactingLoggerProvider::with_optional(limited, redactor),
+        }
+    }
+
+    /// Returns the SDK provider, e.g. to flush or shut it down.
+    pub fn sdk_provider(&self) -> &SdkLoggerProvider {
+        self.inner.inner().inner()
+    }
+}
+
+impl opentelemetry::logs::LoggerProvider for LoggerProvider {
+    type Logger = Logger;
+
+    fn logger_with_scope(&self, scope: InstrumentationScope) -> Self::Logger {
+        self.inner.logger_with_scope(scope)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::logs::AnyValue;
+    use opentelemetry::metrics::MeterProvider;
+    use opentelemetry::Key;
+    use opentelemetry_appender_tracing::layer::OpenTelemetryTracingBridge;
+    use opentelemetry_sdk::logs::{InMemoryLogExporter, SdkLoggerProvider};
+    use opentelemetry_sdk::metrics::SdkMeterProvider;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::LoggerProvider;
+    use crate::limits::LogLimits;
+    use crate::redact::Redactor;
+
+    #[test]
+    fn redact_and_limit() {
+        let exporter = InMemoryLogExporter::default();
+        let sdk_provider = SdkLoggerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let provider = LoggerProvider::new(
+            sdk_provider,
+            LogLimits::default()
+                .with_max_attributes_per_log_record(2)
+                .with_max_attribute_value_length(Some(10)),
+            Some(Redactor::recommended()),
+            &SdkMeterProvider::default().meter("test"),
+        );
+        let subscriber =
+            tracing_subscriber::registry().with(OpenTelemetryTracingBridge::new(&provider));
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::error!(
+                user_email = "otel@opentelemetry.io",
+                user_name = "opentelemetry",
+                event_id = 20,
+                "signup by otel@opentelemetry.io"
+            );
+        });
+
+        let logs = exporter.get_emitted_logs().unwrap();
+        let record = &logs[0].record;
+        assert_eq!(record.body(), Some(&AnyValue::from("signup by [REDACTED]")));
+        let attrs: Vec<_> = record.attributes_iter().cloned().collect();
+        assert_eq!(
+            attrs,
+            vec![
+                (Key::new("user_email"), AnyValue::from("[REDACTED]")),
+                (Key::new("user_name"), AnyValue::from("openteleme")),
+            ]
+        );
+    }
+}
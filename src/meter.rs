@@ -0,0 +1,183 @@
+//! Meter provider wrapping the SDK provider to add what the SDK doesn't do:
+//! [exemplars](crate::exemplars) and [cardinality limits](crate::cardinality)
+//! with overflow reporting.
+
+#[cfg(feature = "prometheus")]
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use opentelemetry::metrics::{
+    AsyncInstrumentBuilder, Counter, Gauge, Histogram, HistogramBuilder, InstrumentBuilder,
+    InstrumentProvider, Meter, ObservableCounter, ObservableGauge, ObservableUpDownCounter,
+    UpDownCounter,
+};
+use opentelemetry::InstrumentationScope;
+use opentelemetry_sdk::metrics::{InstrumentKind, SdkMeterProvider};
+
+use crate::cardinality::{self, CardinalityLimits, Limited, Limiter};
+use crate::exemplars::Reservoirs;
+
+/// Meter provider that records exemplars and enforces cardinality limits for
+/// the instruments it creates before passing the measurements to the SDK
+/// provider.
+#[derive(Debug, Clone)]
+pub(crate) struct MeterProvider {
+    inner: SdkMeterProvider,
+    reservoirs: Option<Arc<Reservoirs>>,
+    limits: Option<Arc<CardinalityLimits>>,
+    #[cfg(feature = "prometheus")]
+    prometheus_addr: Option<SocketAddr>,
+}
+
+impl MeterProvider {
+    /// Wraps the SDK provider; without reservoirs and limits the meters are
+    /// the SDK ones.
+    pub(crate) fn new(
+        inner: SdkMeterProvider,
+        reservoirs: Option<Arc<Reservoirs>>,
+        limits: Option<Arc<CardinalityLimits>>,
+    ) -> Self {
+        Self {
+            inner,
+            reservoirs,
+            limits,
+            #[cfg(feature = "prometheus")]
+            prometheus_addr: None,
+        }
+    }
+
+    /// Records the address the Prometheus endpoint is bound to.
+    #[cfg(feature = "prometheus")]
+    pub(crate) fn with_prometheus_addr(mut self, addr: SocketAddr) -> Self {
+        self.prometheus_addr = Some(addr);
+        self
+    }
+
+    pub(crate) fn sdk_provider(&self) -> &SdkMeterProvider {
+        &self.inner
+    }
+
+    #[cfg(feature = "prometheus")]
+    pub(crate) fn prometheus_addr(&self) -> Option<SocketAddr> {
+        self.prometheus_addr
+    }
+}
+
+impl opentelemetry::metrics::MeterProvider for MeterProvider {
+    fn meter_with_scope(&self, scope: InstrumentationScope) -> Meter {
+        if self.reservoirs.is_none() && self.limits.is_none() {
+            return self.inner.meter_with_scope(scope);
+        }
+        Meter::new(Arc::new(UptraceMeter {
+            inner: self.inner.meter_with_scope(scope.clone()),
+            scope,
+            reservoirs: self.reservoirs.clone(),
+            limits: self.limits.clone(),
+        }))
+    }
+}
+
+struct UptraceMeter {
+    inner: Meter,
+    scope: InstrumentationScope,
+    reservoirs: Option<Arc<Reservoirs>>,
+    limits: Option<Arc<CardinalityLimits>>,
+}
+
+impl UptraceMeter {
+    fn limiter(&self, name: &str, kind: InstrumentKind) -> Option<Arc<Limiter>> {
+        self.limits.as_ref()?.limiter(name, kind)
+    }
+}
+
+macro_rules! forward_sync {
+    ($($method:ident: $instrument:ident<$t:ty>, $kind:ident, $record:ident;)*) => {
+        $(
+            fn $method(&self, builder: InstrumentBuilder<'_, $instrument<$t>>) -> $instrument<$t> {
+                let limiter = self.limiter(&builder.name, InstrumentKind::$kind);
+                let mut inner = self.inner.$method(builder.name);
+                inner.description = builder.description;
+                inner.unit = builder.unit;
+                let instrument = inner.build();
+                match limiter {
+                    Some(limiter) => $instrument::new(Limited::new(limiter, move |value, attrs| {
+                        instrument.$record(value, attrs)
+                    })),
+                    None => instrument,
+                }
+            }
+        )*
+    };
+}
+
+macro_rules! forward_async {
+    ($($method:ident: $instrument:ident<$t:ty>, $kind:ident;)*) => {
+        $(
+            fn $method(
+                &self,
+                builder: AsyncInstrumentBuilder<'_, $instrument<$t>, $t>,
+            ) -> $instrument<$t> {
+                let limiter = self.limiter(&builder.name, InstrumentKind::$kind);
+                let mut inner = self.inner.$method(builder.name);
+                inner.description = builder.description;
+                inner.unit = builder.unit;
+                inner.callbacks = match limiter {
+                    Some(limiter) => cardinality::callbacks(builder.callbacks, limiter),
+                    None => builder.callbacks,
+                };
+                inner.build()
+            }
+        )*
+    };
+}
+
+macro_rules! forward_histogram {
+    ($($method:ident: $t:ty;)*) => {
+        $(
+            fn $method(&self, builder: HistogramBuilder<'_, Histogram<$t>>) -> Histogram<$t> {
+                let name = builder.name.clone();
+                let mut inner = self.inner.$method(builder.name);
+                inner.description = builder.description;
+                inner.unit = builder.unit;
+                inner.boundaries = builder.boundaries;
+                let mut histogram = inner.build();
+                if let Some(reservoirs) = &self.reservoirs {
+                    histogram = reservoirs.histogram(&self.scope, &name, histogram);
+                }
+                match self.limiter(&name, InstrumentKind::Histogram) {
+                    Some(limiter) => Histogram::new(Limited::new(limiter, move |value, attrs| {
+                        histogram.record(value, attrs)
+                    })),
+                    None => histogram,
+                }
+            }
+        )*
+    };
+}
+
+impl InstrumentProvider for UptraceMeter {
+    forward_sync! {
+        u64_counter: Counter<u64>, Counter, add;
+        f64_counter: Counter<f64>, Counter, add;
+        i64_up_down_counter: UpDownCounter<i64>, UpDownCounter, add;
+        f64_up_down_counter: UpDownCounter<f64>, UpDownCounter, add;
+        u64_gauge: Gauge<u64>, Gauge, record;
+        f64_gauge: Gauge<f64>, Gauge, record;
+        i64_gauge: Gauge<i64>, Gauge, record;
+    }
+
+    forward_async! {
+        u64_observable_counter: ObservableCounter<u64>, ObservableCounter;
+        f64_observable_counter: ObservableCounter<f64>, ObservableCounter;
+        i64_observable_up_down_counter: ObservableUpDownCounter<i64>, ObservableUpDownCounter;
+        f64_observable_up_down_counter: ObservableUpDownCounter<f64>, ObservableUpDownCounter;
+        u64_observable_gauge: ObservableGauge<u64>, ObservableGauge;
+        i64_observable_gauge: ObservableGauge<i64>, ObservableGauge;
+        f64_observable_gauge: ObservableGauge<f64>, ObservableGauge;
+    }
+
+    forward_histogram! {
+        f64_histogram: f64;
+        u64_histogram: u64;
+    }
+}
@@ -0,0 +1,697 @@
+//! Configuration of the metrics pipeline built by
+//! [`UptraceBuilder`](crate::UptraceBuilder).
+//!
+//! Metrics are exported with delta temporality every 15 seconds by default,
+//! which is what Uptrace recommends; see [`MetricsConfig::with_temporality`] if
+//! the metrics pass through a collector that needs cumulative temporality.
+//!
+//! [`View`]s customize the streams produced by instruments: they can rename an
+//! instrument, keep only some of its attributes, drop it altogether or change
+//! its aggregation, e.g. to use explicit histogram buckets or base-2
+//! exponential histograms, which Uptrace supports natively.
+//!
+//! ```
+//! use uptrace::metrics::{Aggregation, MetricsConfig, View};
+//!
+//! let config = MetricsConfig::default()
+//!     .with_view(
+//!         View::new("http.server.request.duration")
+//!             .with_explicit_buckets(vec![0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5]),
+//!     )
+//!     .with_view(View::new("db.client.*").with_allowed_attribute_keys(["db.system"]))
+//!     .with_view(View::new("debug.*").with_aggregation(Aggregation::Drop))
+//!     .with_exponential_histograms(true);
+//! ```
+
+use std::borrow::Cow;
+use std::sync::Weak;
+use std::time::Duration;
+
+use opentelemetry::Key;
+use opentelemetry_sdk::error::OTelSdkResult;
+use opentelemetry_sdk::metrics::data::ResourceMetrics;
+use opentelemetry_sdk::metrics::exporter::PushMetricExporter;
+use opentelemetry_sdk::metrics::reader::MetricReader;
+use opentelemetry_sdk::metrics::{
+    Instrument, MeterProviderBuilder, PeriodicReader, Pipeline, Stream, StreamBuilder,
+};
+
+#[cfg(feature = "prometheus")]
+use crate::prometheus::PrometheusConfig;
+#[cfg(feature = "system-metrics")]
+use crate::system_metrics::SystemMetricsConfig;
+use crate::Error;
+
+pub use crate::exemplars::{ExemplarFilter, OTEL_METRICS_EXEMPLAR_FILTER};
+
+pub use opentelemetry_sdk::metrics::{Aggregation, InstrumentKind, Temporality};
+
+/// Default interval between two exports.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(15);
+/// Default timeout of an export.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Attribute set to `true` on the series that collects the measurements over
+/// a [cardinality limit](MetricsConfig::with_cardinality_limit).
+pub const OVERFLOW_KEY: &str = "otel.metric.overflow";
+
+/// Default maximum number of buckets of exponential histograms.
+pub const DEFAULT_EXPONENTIAL_MAX_SIZE: u32 = 160;
+/// Default maximum scale of exponential histograms.
+pub const DEFAULT_EXPONENTIAL_MAX_SCALE: i8 = 20;
+
+/// Metrics configuration, see [`UptraceBuilder::with_metrics`](crate::UptraceBuilder::with_metrics).
+#[derive(Debug, Clone)]
+pub struct MetricsConfig {
+    pub(crate) temporality: Temporality,
+    temporality_overrides: Vec<(InstrumentKind, Temporality)>,
+    interval: Duration,
+    pub(crate) timeout: Duration,
+    views: Vec<View>,
+    exponential_histograms: bool,
+    pub(crate) runtime_metrics: bool,
+    pub(crate) otlp_export: bool,
+    exemplar_filter: Option<ExemplarFilter>,
+    cardinality_limit: Option<usize>,
+    #[cfg(feature = "prometheus")]
+    pub(crate) prometheus: Option<PrometheusConfig>,
+    #[cfg(feature = "system-metrics")]
+    pub(crate) system_metrics: Option<SystemMetricsConfig>,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            temporality: Temporality::Delta,
+            temporality_overrides: Vec::new(),
+            interval: DEFAULT_INTERVAL,
+            timeout: DEFAULT_TIMEOUT,
+            views: Vec::new(),
+            exponential_histograms: false,
+            runtime_metrics: true,
+            otlp_export: true,
+            exemplar_filter: None,
+            cardinality_limit: None,
+            #[cfg(feature = "prometheus")]
+            prometheus: None,
+            #[cfg(feature = "system-metrics")]
+            system_metrics: None,
+        }
+    }
+}
+
+impl MetricsConfig {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Set the temporality preference. Defaults to [`Temporality::Delta`].
+    ///
+    /// The preference is resolved per instrument kind:
+    ///
+    /// | Instrument kind                   | Delta      | Cumulative | LowMemory  |
+    /// |-----------------------------------|------------|------------|------------|
+    /// | Counter, Histogram                | delta      | cumulative | delta      |
+    /// | ObservableCounter                 | delta      | cumulative | cumulative |
+    /// | UpDownCounter, ObservableUpDownCounter | cumulative | cumulative | cumulative |
+    ///
+    /// Gauges report the last value and don't have a temporality.
+    ///
+    /// Use [`MetricsConfig::with_temporality_for`] to override the table for
+    /// some instrument kinds.
+    pub fn with_temporality(mut self, temporality: Temporality) -> Self {
+        self.temporality = temporality;
+        self
+    }
+
+    /// Set the temporality of the instruments of the given kind, overriding
+    /// the preference set with [`MetricsConfig::with_temporality`], e.g. to
+    /// export histograms as cumulative and counters as delta.
+    ///
+    /// `temporality` must be [`Temporality::Delta`] or [`Temporality::Cumulative`];
+    /// [`Temporality::LowMemory`] is only a preference and is exported as
+    /// cumulative. Overrides only apply to the OTLP export: the Prometheus
+    /// endpoint is always cumulative.
+    pub fn with_temporality_for(mut self, kind: InstrumentKind, temporality: Temporality) -> Self {
+        self.temporality_overrides.retain(|(k, _)| *k != kind);
+        self.temporality_overrides.push((kind, temporality));
+        self
+    }
+
+    /// Set the interval between two exports. Defaults to 15 seconds.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Set the timeout of an export. Defaults to 5 seconds.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Add a view. When several views match an instrument, the first one added wins.
+    pub fn with_view(mut self, view: View) -> Self {
+        self.views.push(view);
+        self
+    }
+
+    /// Aggregate all histograms that don't have an explicit aggregation set by
+    /// a view as base-2 exponential histograms. Disabled by default.
+    pub fn with_exponential_histograms(mut self, enabled: bool) -> Self {
+        self.exponential_histograms = enabled;
+        self
+    }
+
+    /// Collect the process and tokio runtime metrics, see
+    /// [`runtime_metrics`](crate::runtime_metrics). Enabled by default.
+    pub fn with_runtime_metrics(mut self, enabled: bool) -> Self {
+        self.runtime_metrics = enabled;
+        self
+    }
+
+    /// Push the metrics to Uptrace over OTLP. Enabled by default; disable it to
+    /// only expose the metrics on the Prometheus endpoint, see `with_prometheus`
+    /// with the `prometheus` feature.
+    pub fn with_otlp_export(mut self, enabled: bool) -> Self {
+        self.otlp_export = enabled;
+        self
+    }
+
+    /// Set the filter selecting the histogram measurements recorded as
+    /// exemplars, which link the data points exported over OTLP to traces.
+    /// Defaults to `OTEL_METRICS_EXEMPLAR_FILTER` or [`ExemplarFilter::AlwaysOff`].
+    ///
+    /// Exemplars are recorded for the instruments created with
+    /// [`Uptrace::meter`](crate::Uptrace::meter) or the global meter provider.
+    /// The SDK's OTLP exporter can't carry them, so enabling them exports the
+    /// metrics with an exporter of this crate, which compresses requests as
+    /// configured with `OTEL_EXPORTER_OTLP_COMPRESSION` and retries the
+    /// exports that fail with a transient error.
+    pub fn with_exemplar_filter(mut self, filter: ExemplarFilter) -> Self {
+        self.exemplar_filter = Some(filter);
+        self
+    }
+
+    /// Limit the number of distinct attribute sets of every instrument; see
+    /// [`View::with_cardinality_limit`] to set the limit of some instruments.
+    ///
+    /// Once an instrument reaches its limit, the measurements with new
+    /// attribute sets are recorded with the single [`OVERFLOW_KEY`] attribute.
+    /// The first overflow of an instrument is reported to the
+    /// [error handler](crate::UptraceBuilder::with_error_handler) and all of
+    /// them are counted with the `uptrace.metric.overflow` counter. An
+    /// attribute set counts towards the limit from its first measurement until
+    /// the process exits.
+    ///
+    /// Limits apply to the instruments created with
+    /// [`Uptrace::meter`](crate::Uptrace::meter) or the global meter provider.
+    /// Without a limit, the SDK collapses the attribute sets over 2000 into
+    /// the overflow series without reporting it.
+    pub fn with_cardinality_limit(mut self, limit: usize) -> Self {
+        self.cardinality_limit = Some(limit);
+        self
+    }
+
+    pub(crate) fn has_cardinality_limits(&self) -> bool {
+        self.cardinality_limit.is_some()
+            || self
+                .views
+                .iter()
+                .any(|view| view.cardinality_limit.is_some())
+    }
+
+    /// Returns the cardinality limit of the instrument and the attribute keys
+    /// kept by its view.
+    pub(crate) fn cardinality_limit(
+        &self,
+        name: &str,
+        kind: InstrumentKind,
+    ) -> Option<(usize, Option<&[Key]>)> {
+        let view = self.views.iter().find(|view| view.matches(name, kind));
+        let limit = view
+            .and_then(|view| view.cardinality_limit)
+            .or(self.cardinality_limit)?;
+        Some((
+            limit,
+            view.and_then(|view| view.allowed_attribute_keys.as_deref()),
+        ))
+    }
+
+    /// Returns the name of the exported stream, which the first matching view
+    /// may rename.
+    pub(crate) fn stream_name<'a>(&'a self, name: &'a str, kind: InstrumentKind) -> &'a str {
+        self.views
+            .iter()
+            .find(|view| view.matches(name, kind))
+            .and_then(|view| view.name.as_deref())
+            .unwrap_or(name)
+    }
+
+    pub(crate) fn exemplar_filter(&self) -> ExemplarFilter {
+        self.exemplar_filter
+            .unwrap_or_else(ExemplarFilter::from_env)
+    }
+
+    /// Serve the metrics on a Prometheus scrape endpoint, see
+    /// [`prometheus`](crate::prometheus). Disabled by default.
+    #[cfg(feature = "prometheus")]
+    pub fn with_prometheus(mut self, config: PrometheusConfig) -> Self {
+        self.prometheus = Some(config);
+        self
+    }
+
+    /// Collect host and container metrics, see
+    /// [`system_metrics`](crate::system_metrics). Disabled by default.
+    #[cfg(feature = "system-metrics")]
+    pub fn with_system_metrics(mut self, config: SystemMetricsConfig) -> Self {
+        self.system_metrics = Some(config);
+        self
+    }
+
+    /// Returns the reader exporting the metrics at the configured interval,
+    /// with the configured temporality overrides.
+    pub(crate) fn reader<E: PushMetricExporter>(
+        &self,
+        exporter: E,
+    ) -> Result<TemporalityReader<PeriodicReader<E>>, Error> {
+        if self.interval.is_zero() {
+            return Err(Error::MetricsBuildError(
+                "export interval must be greater than zero".into(),
+            ));
+        }
+        let reader = PeriodicReader::builder(exporter)
+            .with_interval(self.interval)
+            .build();
+        Ok(TemporalityReader {
+            inner: reader,
+            overrides: self.temporality_overrides.clone(),
+        })
+    }
+
+    /// Registers the views with the meter provider builder.
+    ///
+    /// The views are combined into a single SDK view, because the SDK creates
+    /// a separate stream for every view that matches an instrument.
+    pub(crate) fn apply(
+        &self,
+        builder: MeterProviderBuilder,
+    ) -> Result<MeterProviderBuilder, Error> {
+        if self.views.is_empty() && !self.exponential_histograms && self.cardinality_limit.is_none()
+        {
+            return Ok(builder);
+        }
+        if self.cardinality_limit == Some(0) {
+            return Err(Error::MetricsBuildError(
+                "cardinality limit must be greater than zero".into(),
+            ));
+        }
+        for view in &self.views {
+            view.validate()?;
+        }
+
+        let views = self.views.clone();
+        let exponential_histograms = self.exponential_histograms;
+        let cardinality_limit = self.cardinality_limit;
+        Ok(builder.with_view(move |instrument: &Instrument| {
+            let view = views
+                .iter()
+                .find(|view| view.matches(instrument.name(), instrument.kind()));
+            let default_aggregation = (exponential_histograms
+                && instrument.kind() == InstrumentKind::Histogram)
+                .then(exponential_aggregation);
+            if view.is_none() && default_aggregation.is_none() && cardinality_limit.is_none() {
+                return None;
+            }
+
+            let mut stream = Stream::builder();
+            if let Some(aggregation) = default_aggregation {
+                stream = stream.with_aggregation(aggregation);
+            }
+            // The limits are enforced before the measurements reach the SDK,
+            // so the SDK must not collapse attribute sets under them.
+            if let Some(limit) = cardinality_limit {
+                stream = stream.with_cardinality_limit(limit);
+            }
+            if let Some(view) = view {
+                stream = view.stream(stream);
+            }
+            // Views are validated before they are registered.
+            stream.build().ok()
+        }))
+    }
+}
+
+/// Reader that returns the overridden temporality of some instrument kinds;
+/// the SDK only lets exporters pick one preference for all of them.
+#[derive(Debug)]
+pub(crate) struct TemporalityReader<R> {
+    inner: R,
+    overrides: Vec<(InstrumentKind, Temporality)>,
+}
+
+impl<R: MetricReader> MetricReader for TemporalityReader<R> {
+    fn register_pipeline(&self, pipeline: Weak<Pipeline>) {
+        self.inner.register_pipeline(pipeline)
+    }
+
+    fn collect(&self, rm: &mut ResourceMetrics) -> OTelSdkResult {
+        self.inner.collect(rm)
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn temporality(&self, kind: InstrumentKind) -> Temporality {
+        match self.overrides.iter().find(|(k, _)| *k == kind) {
+            Some((_, Temporality::Delta)) => Temporality::Delta,
+            Some(_) => Temporality::Cumulative,
+            None => self.inner.temporality(kind),
+        }
+    }
+}
+
+fn exponential_aggregation() -> Aggregation {
+    Aggregation::Base2ExponentialHistogram {
+        max_size: DEFAULT_EXPONENTIAL_MAX_SIZE,
+        max_scale: DEFAULT_EXPONENTIAL_MAX_SCALE,
+        record_min_max: true,
+    }
+}
+
+/// Customizes the stream produced by the instruments it matches.
+///
+/// Instruments are matched by name: either exactly, or by prefix when the
+/// name ends with `*`, e.g. `http.*`. A single `*` matches every instrument.
+#[derive(Debug, Clone)]
+pub struct View {
+    instrument_name: String,
+    instrument_kind: Option<InstrumentKind>,
+    name: Option<Cow<'static, str>>,
+    description: Option<Cow<'static, str>>,
+    allowed_attribute_keys: Option<Vec<Key>>,
+    aggregation: Option<Aggregation>,
+    cardinality_limit: Option<usize>,
+}
+
+impl View {
+    /// Create a view matching the instruments with the given name or name pattern.
+    pub fn new<T: Into<String>>(instrument_name: T) -> Self {
+        Self {
+            instrument_name: instrument_name.into(),
+            instrument_kind: None,
+            name: None,
+            description: None,
+            allowed_attribute_keys: None,
+            aggregation: None,
+            cardinality_limit: None,
+        }
+    }
+
+    /// Only match instruments of the given kind.
+    pub fn with_instrument_kind(mut self, kind: InstrumentKind) -> Self {
+        self.instrument_kind = Some(kind);
+        self
+    }
+
+    /// Rename the stream. Should only be used with views matching a single instrument.
+    pub fn with_name<T: Into<Cow<'static, str>>>(mut self, name: T) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn with_description<T: Into<Cow<'static, str>>>(mut self, description: T) -> Self {
+        self.description = Some(description.into());
+        self
+    }
+
+    /// Keep only the attributes with the given keys; an empty list drops all attributes.
+    pub fn with_allowed_attribute_keys<I>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<Key>,
+    {
+        self.allowed_attribute_keys = Some(keys.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Set the aggregation, e.g. [`Aggregation::Drop`] to drop the instrument.
+    pub fn with_aggregation(mut self, aggregation: Aggregation) -> Self {
+        self.aggregation = Some(aggregation);
+        self
+    }
+
+    /// Aggregate as a histogram with the given bucket boundaries, which must
+    /// be sorted and finite.
+    pub fn with_explicit_buckets(self, boundaries: Vec<f64>) -> Self {
+        self.with_aggregation(Aggregation::ExplicitBucketHistogram {
+            boundaries,
+            record_min_max: true,
+        })
+    }
+
+    /// Limit the number of distinct attribute sets of the matched instruments,
+    /// overriding [`MetricsConfig::with_cardinality_limit`]. Attribute sets are
+    /// compared after the [allowed keys](Self::with_allowed_attribute_keys)
+    /// are applied.
+    pub fn with_cardinality_limit(mut self, limit: usize) -> Self {
+        self.cardinality_limit = Some(limit);
+        self
+    }
+
+    /// Aggregate as a base-2 exponential histogram with the default size and scale.
+    pub fn with_exponential_histogram(self) -> Self {
+        self.with_aggregation(exponential_aggregation())
+    }
+
+    fn matches(&self, name: &str, kind: InstrumentKind) -> bool {
+        if self.instrument_kind.is_some_and(|k| k != kind) {
+            return false;
+        }
+        match self.instrument_name.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == self.instrument_name,
+        }
+    }
+
+    fn stream(&self, mut stream: StreamBuilder) -> StreamBuilder {
+        if let Some(name) = &self.name {
+            stream = stream.with_name(name.clone());
+        }
+        if let Some(description) = &self.description {
+            stream = stream.with_description(description.clone());
+        }
+        if let Some(keys) = &self.allowed_attribute_keys {
+            // Keep the overflow series of the cardinality limits apart.
+            let overflow = Key::from_static_str(OVERFLOW_KEY);
+            stream = stream.with_allowed_attribute_keys(keys.iter().cloned().chain([overflow]));
+        }
+        if let Some(aggregation) = &self.aggregation {
+            stream = stream.with_aggregation(aggregation.clone());
+        }
+        if let Some(limit) = self.cardinality_limit {
+            stream = stream.with_cardinality_limit(limit);
+        }
+        stream
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        self.stream(Stream::builder())
+            .build()
+            .map(|_| ())
+            .map_err(|err| {
+                Error::MetricsBuildError(
+                    format!("invalid view {:?}: {}", self.instrument_name, err).into(),
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use opentelemetry::metrics::MeterProvider;
+    use opentelemetry::KeyValue;
+    use opentelemetry_sdk::metrics::data::{
+        AggregatedMetrics, Metric, MetricData, ResourceMetrics,
+    };
+    use opentelemetry_sdk::metrics::{InMemoryMetricExporterBuilder, SdkMeterProvider};
+
+    use super::{Aggregation, InstrumentKind, MetricsConfig, Temporality, View};
+    use crate::ErrorKind;
+
+    fn collect(
+        config: MetricsConfig,
+        record: impl FnOnce(&SdkMeterProvider),
+    ) -> Vec<ResourceMetrics> {
+        let exporter = InMemoryMetricExporterBuilder::new()
+            .with_temporality(config.temporality)
+            .build();
+        let builder =
+            SdkMeterProvider::builder().with_reader(config.reader(exporter.clone()).unwrap());
+        let provider = config.apply(builder).unwrap().build();
+        record(&provider);
+        provider.force_flush().unwrap();
+        exporter.get_finished_metrics().unwrap()
+    }
+
+    fn metrics(resource_metrics: &[ResourceMetrics]) -> Vec<&Metric> {
+        resource_metrics
+            .iter()
+            .flat_map(|rm| rm.scope_metrics())
+            .flat_map(|sm| sm.metrics())
+            .collect()
+    }
+
+    #[test]
+    fn views() {
+        let config = MetricsConfig::new()
+            .with_view(View::new("latency").with_explicit_buckets(vec![10.0, 100.0]))
+            .with_view(View::new("requests").with_name("http.requests"))
+            .with_view(View::new("requests").with_name("ignored"))
+            .with_view(View::new("debug.*").with_aggregation(Aggregation::Drop))
+            .with_view(View::new("errors").with_allowed_attribute_keys(["code"]));
+
+        let exported = collect(config, |provider| {
+            let meter = provider.meter("test");
+            meter.f64_histogram("latency").build().record(50.0, &[]);
+            meter.u64_counter("requests").build().add(1, &[]);
+            meter.u64_counter("debug.calls").build().add(1, &[]);
+            meter.u64_counter("errors").build().add(
+                1,
+                &[KeyValue::new("code", 500), KeyValue::new("user", "john")],
+            );
+        });
+        let metrics = metrics(&exported);
+
+        let mut names: Vec<_> = metrics.iter().map(|m| m.name().to_string()).collect();
+        names.sort();
+        assert_eq!(names, ["errors", "http.requests", "latency"]);
+
+        for metric in &metrics {
+            match (metric.name(), metric.data()) {
+                ("latency", AggregatedMetrics::F64(MetricData::Histogram(hist))) => {
+                    let dp = hist.data_points().next().unwrap();
+                    assert_eq!(dp.bounds().collect::<Vec<_>>(), [10.0, 100.0]);
+                    assert_eq!(dp.bucket_counts().collect::<Vec<_>>(), [0, 1, 0]);
+                }
+                ("errors", AggregatedMetrics::U64(MetricData::Sum(sum))) => {
+                    let dp = sum.data_points().next().unwrap();
+                    let attrs: Vec<_> = dp.attributes().cloned().collect();
+                    assert_eq!(attrs, [KeyValue::new("code", 500)]);
+                }
+                ("http.requests", _) => {}
+                (name, data) => panic!("unexpected metric {name}: {data:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn exponential_histograms() {
+        let config = MetricsConfig::new()
+            .with_view(View::new("size").with_explicit_buckets(vec![1.0]))
+            .with_exponential_histograms(true);
+
+        let exported = collect(config, |provider| {
+            let meter = provider.meter("test");
+            meter.f64_histogram("latency").build().record(50.0, &[]);
+            meter.f64_histogram("size").build().record(5.0, &[]);
+        });
+        let metrics = metrics(&exported);
+
+        for metric in &metrics {
+            match (metric.name(), metric.data()) {
+                ("latency", AggregatedMetrics::F64(MetricData::ExponentialHistogram(_))) => {}
+                ("size", AggregatedMetrics::F64(MetricData::Histogram(_))) => {}
+                (name, data) => panic!("unexpected metric {name}: {data:?}"),
+            }
+        }
+        assert_eq!(metrics.len(), 2);
+    }
+
+    #[test]
+    fn invalid_view() {
+        let config = MetricsConfig::new()
+            .with_view(View::new("latency").with_explicit_buckets(vec![2.0, 1.0]));
+        let err = config.apply(SdkMeterProvider::builder()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Build);
+        let cause = std::error::Error::source(&err).unwrap().to_string();
+        assert!(cause.contains("\"latency\""), "{cause}");
+
+        let config = MetricsConfig::new().with_cardinality_limit(0);
+        let err = config.apply(SdkMeterProvider::builder()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Build);
+    }
+
+    fn sum_temporality(metrics: &[&Metric], name: &str) -> Temporality {
+        let metric = metrics.iter().find(|m| m.name() == name).unwrap();
+        match metric.data() {
+            AggregatedMetrics::I64(MetricData::Sum(sum)) => sum.temporality(),
+            AggregatedMetrics::U64(MetricData::Sum(sum)) => sum.temporality(),
+            data => panic!("unexpected metric {name}: {data:?}"),
+        }
+    }
+
+    #[test]
+    fn temporality() {
+        let record = |provider: &SdkMeterProvider| {
+            let meter = provider.meter("test");
+            meter.i64_up_down_counter("queue").build().add(1, &[]);
+            meter
+                .u64_observable_counter("cpu")
+                .with_callback(|o| o.observe(1, &[]))
+                .build();
+        };
+        let cases = [
+            (Temporality::Delta, Temporality::Delta),
+            (Temporality::Cumulative, Temporality::Cumulative),
+            (Temporality::LowMemory, Temporality::Cumulative),
+        ];
+        for (preference, observable) in cases {
+            let exported = collect(MetricsConfig::new().with_temporality(preference), record);
+            let metrics = metrics(&exported);
+            assert_eq!(sum_temporality(&metrics, "queue"), Temporality::Cumulative);
+            assert_eq!(
+                sum_temporality(&metrics, "cpu"),
+                observable,
+                "{preference:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn temporality_for() {
+        let config = MetricsConfig::new()
+            .with_temporality_for(InstrumentKind::Histogram, Temporality::Cumulative)
+            .with_temporality_for(InstrumentKind::UpDownCounter, Temporality::Delta);
+        let exported = collect(config, |provider| {
+            let meter = provider.meter("test");
+            meter.u64_counter("requests").build().add(1, &[]);
+            meter.i64_up_down_counter("queue").build().add(1, &[]);
+            meter.f64_histogram("latency").build().record(1.0, &[]);
+        });
+        let metrics = metrics(&exported);
+        assert_eq!(sum_temporality(&metrics, "requests"), Temporality::Delta);
+        assert_eq!(sum_temporality(&metrics, "queue"), Temporality::Delta);
+        let latency = metrics.iter().find(|m| m.name() == "latency").unwrap();
+        let AggregatedMetrics::F64(MetricData::Histogram(histogram)) = latency.data() else {
+            panic!("unexpected metric latency: {:?}", latency.data());
+        };
+        assert_eq!(histogram.temporality(), Temporality::Cumulative);
+    }
+
+    #[test]
+    fn zero_interval() {
+        let config = MetricsConfig::new().with_interval(Duration::ZERO);
+        let err = config
+            .reader(InMemoryMetricExporterBuilder::new().build())
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Build);
+    }
+}
@@ -0,0 +1,272 @@
+use std::backtrace::Backtrace;
+use std::panic::PanicHookInfo;
+use std::sync::{mpsc, Mutex, Once};
+use std::time::Duration;
+
+use opentelemetry::trace::{Span, SpanKind, Status, TraceContextExt, Tracer};
+use opentelemetry::{Context, KeyValue};
+use opentelemetry_sdk::logs::SdkLoggerProvider;
+use opentelemetry_sdk::metrics::SdkMeterProvider;
+use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// How long the panic hook waits for spans, metrics and logs to be exported.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Tracer and provider of the last [`Uptrace`](crate::Uptrace) installed globally.
+static TRACER: Mutex<Option<(SdkTracer, SdkTracerProvider)>> = Mutex::new(None);
+
+/// Logger provider of the last [`Uptrace`](crate::Uptrace) installed globally.
+static LOGGER_PROVIDER: Mutex<Option<SdkLoggerProvider>> = Mutex::new(None);
+
+/// Meter provider of the last [`Uptrace`](crate::Uptrace) installed globally.
+static METER_PROVIDER: Mutex<Option<SdkMeterProvider>> = Mutex::new(None);
+
+static INSTALL: Once = Once::new();
+
+pub(crate) fn register_tracer(tracer: &SdkTracer, provider: &SdkTracerProvider) {
+    if let Ok(mut current) = TRACER.lock() {
+        *current = Some((tracer.clone(), provider.clone()));
+    }
+}
+
+pub(crate) fn register_meter_provider(provider: &SdkMeterProvider) {
+    if let Ok(mut current) = METER_PROVIDER.lock() {
+        *current = Some(provider.clone());
+    }
+}
+
+pub(crate) fn register_logger_provider(provider: &SdkLoggerProvider) {
+    if let Ok(mut current) = LOGGER_PROVIDER.lock() {
+        *current = Some(provider.clone());
+    }
+}
+
+/// Installs a panic hook that records panics before running the previously
+/// installed hook.
+///
+/// The panic message, location and backtrace are recorded as an `exception`
+/// event on a new `panic` span that is ended right away. The span is a child
+/// of the active OpenTelemetry span or else of the current `tracing` span, and
+/// the parent gets the event and the error status too. The panic is also
+/// logged with `tracing::error!` and all providers are flushed, waiting up to
+/// 5 seconds for the export to finish. Calling this more than once has no
+/// effect.
+///
+/// ```no_run
+/// # fn main() -> Result<(), uptrace::Error> {
+/// let uptrace = uptrace::UptraceBuilder::new().build()?;
+/// uptrace.init_tracing_subscriber()?;
+/// uptrace::install_panic_hook();
+/// # Ok(())
+/// # }
+/// ```
+pub fn install_panic_hook() {
+    INSTALL.call_once(|| {
+        let prev = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            handle_panic(info);
+            prev(info);
+        }));
+    });
+}
+
+fn handle_panic(info: &PanicHookInfo<'_>) {
+    let message = payload_message(info);
+    let location = info.location();
+    let stacktrace = Backtrace::force_capture().to_string();
+
+    let tracer = TRACER.lock().ok().and_then(|tracer| tracer.clone());
+    if let Some((tracer, _)) = &tracer {
+        let mut attrs = vec![
+            KeyValue::new("exception.type", "panic"),
+            KeyValue::new("exception.message", message.clone()),
+            KeyValue::new("exception.stacktrace", stacktrace),
+        ];
+        if let Some(location) = location {
+            attrs.push(KeyValue::new("code.filepath", location.file().to_string()));
+            attrs.push(KeyValue::new("code.lineno", location.line() as i64));
+        }
+        record_panic(tracer, &message, attrs);
+    }
+
+    match location {
+        Some(location) => tracing::error!(
+            code.filepath = location.file(),
+            code.lineno = location.line(),
+            "panicked at {}: {}",
+            location,
+            message
+        ),
+        None => tracing::error!("panicked: {}", message),
+    }
+
+    let logger_provider = LOGGER_PROVIDER
+        .lock()
+        .ok()
+        .and_then(|provider| provider.clone());
+    let meter_provider = METER_PROVIDER
+        .lock()
+        .ok()
+        .and_then(|provider| provider.clone());
+    flush(
+        tracer.map(|(_, provider)| provider),
+        meter_provider,
+        logger_provider,
+    );
+}
+
+fn payload_message(info: &PanicHookInfo<'_>) -> String {
+    let payload = info.payload();
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "Box<dyn Any>".to_string()
+    }
+}
+
+fn record_panic<T>(tracer: &T, message: &str, attrs: Vec<KeyValue>)
+where
+    T: Tracer,
+    T::Span: Send + Sync + 'static,
+{
+    // The active span is usually never ended when the panic unwinds out of
+    // main, so the panic is also recorded on a span that can be exported now.
+    let cx = Context::current();
+    let active = cx.span();
+    let parent = if active.span_context().is_valid() && active.is_recording() {
+        active.add_event("exception", attrs.clone());
+        active.set_status(Status::error(message.to_string()));
+        cx.clone()
+    } else {
+        let current = tracing::Span::current();
+        current.add_event("exception", attrs.clone());
+        current.set_status(Status::error(message.to_string()));
+        current.context()
+    };
+
+    let mut span = tracer
+        .span_builder("panic")
+        .with_kind(SpanKind::Internal)
+        .start_with_context(tracer, &parent);
+    span.add_event("exception", attrs);
+    span.set_status(Status::error(message.to_string()));
+    span.end();
+}
+
+/// Flushes spans, metrics and logs on another thread so that a panic on a
+/// runtime thread can't deadlock the exporters, giving up after [`FLUSH_TIMEOUT`].
+fn flush(
+    tracer_provider: Option<SdkTracerProvider>,
+    meter_provider: Option<SdkMeterProvider>,
+    logger_provider: Option<SdkLoggerProvider>,
+) {
+    if tracer_provider.is_none() && meter_provider.is_none() && logger_provider.is_none() {
+        return;
+    }
+
+    let (tx, rx) = mpsc::channel();
+    let spawned = std::thread::Builder::new()
+        .name("uptrace-panic-flush".to_string())
+        .spawn(move || {
+            if let Some(provider) = tracer_provider {
+                let _ = provider.force_flush();
+            }
+            if let Some(provider) = meter_provider {
+                let _ = provider.force_flush();
+            }
+            if let Some(provider) = logger_provider {
+                let _ = provider.force_flush();
+            }
+            let _ = tx.send(());
+        });
+    if spawned.is_ok() {
+        let _ = rx.recv_timeout(FLUSH_TIMEOUT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::trace::{Status, TraceContextExt, Tracer, TracerProvider as _};
+    use opentelemetry::{Context, KeyValue};
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::record_panic;
+    use crate::testing::Collect;
+
+    #[test]
+    fn new_span() {
+        let spans = Collect::default();
+        let provider = SdkTracerProvider::builder()
+            .with_span_processor(spans.clone())
+            .build();
+        let tracer = provider.tracer("test");
+
+        record_panic(
+            &tracer,
+            "boom",
+            vec![KeyValue::new("exception.message", "boom")],
+        );
+
+        let spans = spans.spans();
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].name, "panic");
+        assert_eq!(spans[0].status, Status::error("boom"));
+        assert_eq!(spans[0].events.iter().next().unwrap().name, "exception");
+    }
+
+    #[test]
+    fn active_span() {
+        let spans = Collect::default();
+        let provider = SdkTracerProvider::builder()
+            .with_span_processor(spans.clone())
+            .build();
+        let tracer = provider.tracer("test");
+
+        let cx = Context::current_with_span(tracer.start("handler"));
+        {
+            let _guard = cx.clone().attach();
+            record_panic(&tracer, "boom", vec![]);
+        }
+        cx.span().end();
+
+        let spans = spans.spans();
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0].name, "panic");
+        assert_eq!(spans[0].parent_span_id, spans[1].span_context.span_id());
+        assert_eq!(spans[0].status, Status::error("boom"));
+        assert_eq!(spans[1].name, "handler");
+        assert_eq!(spans[1].status, Status::error("boom"));
+        for span in &spans {
+            assert_eq!(span.events.iter().next().unwrap().name, "exception");
+        }
+    }
+
+    #[test]
+    fn tracing_span() {
+        let spans = Collect::default();
+        let provider = SdkTracerProvider::builder()
+            .with_span_processor(spans.clone())
+            .build();
+        let tracer = provider.tracer("test");
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(tracer.clone()));
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("handler").in_scope(|| {
+                record_panic(&tracer, "boom", vec![]);
+            });
+        });
+
+        let spans = spans.spans();
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0].name, "panic");
+        assert_eq!(spans[0].parent_span_id, spans[1].span_context.span_id());
+        assert_eq!(spans[1].name, "handler");
+        assert_eq!(spans[1].status, Status::error("boom"));
+        assert_eq!(spans[1].events.iter().next().unwrap().name, "exception");
+    }
+}
@@ -0,0 +1,498 @@
+//! Prometheus scrape endpoint for the metrics pipeline.
+//!
+//! Enable the `prometheus` feature and add the endpoint to the metrics
+//! configuration. The same instruments are then served on `/metrics` in the
+//! Prometheus text exposition format, in addition to the OTLP export unless
+//! it is disabled with [`MetricsConfig::with_otlp_export`]:
+//!
+//! ```
+//! use uptrace::metrics::MetricsConfig;
+//! use uptrace::prometheus::PrometheusConfig;
+//!
+//! let config = MetricsConfig::default()
+//!     .with_prometheus(PrometheusConfig::default().with_addr(([127, 0, 0, 1], 9464).into()))
+//!     .with_otlp_export(false);
+//! ```
+//!
+//! Metrics are collected with cumulative temporality on every scrape.
+//! Counters get the `_total` suffix and the unit is appended to the names,
+//! e.g. `http_server_request_duration_seconds`. The resource attributes are
+//! exported as the labels of the `target_info` metric. Exponential
+//! histograms can't be represented in the text format and are skipped.
+//!
+//! [`MetricsConfig::with_otlp_export`]: crate::metrics::MetricsConfig::with_otlp_export
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Write as _};
+use std::net::SocketAddr;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use opentelemetry::{KeyValue, Value};
+use opentelemetry_sdk::error::OTelSdkResult;
+use opentelemetry_sdk::metrics::data::{AggregatedMetrics, Metric, MetricData, ResourceMetrics};
+use opentelemetry_sdk::metrics::reader::MetricReader;
+use opentelemetry_sdk::metrics::{InstrumentKind, ManualReader, Pipeline, Temporality};
+use opentelemetry_sdk::Resource;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::Error;
+
+/// Default address of the endpoint, the port registered for OpenTelemetry
+/// Prometheus exporters.
+pub const DEFAULT_ADDR: SocketAddr =
+    SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED), 9464);
+
+/// Time a client has to send the request headers.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Configuration of the Prometheus endpoint, see
+/// [`MetricsConfig::with_prometheus`](crate::metrics::MetricsConfig::with_prometheus).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrometheusConfig {
+    pub addr: SocketAddr,
+}
+
+impl Default for PrometheusConfig {
+    fn default() -> Self {
+        Self { addr: DEFAULT_ADDR }
+    }
+}
+
+impl PrometheusConfig {
+    /// Set the address to listen on. Defaults to `0.0.0.0:9464`.
+    pub fn with_addr(mut self, addr: SocketAddr) -> Self {
+        self.addr = addr;
+        self
+    }
+}
+
+/// Reader collecting the metrics when the endpoint is scraped.
+#[derive(Debug, Clone)]
+pub(crate) struct PrometheusReader {
+    reader: Arc<ManualReader>,
+}
+
+impl MetricReader for PrometheusReader {
+    fn register_pipeline(&self, pipeline: Weak<Pipeline>) {
+        self.reader.register_pipeline(pipeline)
+    }
+
+    fn collect(&self, rm: &mut ResourceMetrics) -> OTelSdkResult {
+        self.reader.collect(rm)
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.reader.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.reader.shutdown_with_timeout(timeout)
+    }
+
+    fn temporality(&self, kind: InstrumentKind) -> Temporality {
+        self.reader.temporality(kind)
+    }
+}
+
+/// Binds the endpoint and serves it on the current tokio runtime until the
+/// meter provider owning the returned reader is dropped. Returns the reader
+/// and the bound address.
+pub(crate) fn serve(config: &PrometheusConfig) -> Result<(PrometheusReader, SocketAddr), Error> {
+    let runtime =
+        tokio::runtime::Handle::try_current().map_err(|err| Error::Runtime(Box::new(err)))?;
+    let listener = std::net::TcpListener::bind(config.addr)
+        .and_then(|listener| {
+            listener.set_nonblocking(true)?;
+            Ok(listener)
+        })
+        .map_err(|err| {
+            Error::MetricsBuildError(
+                format!("can't listen on {} for Prometheus: {}", config.addr, err).into(),
+            )
+        })?;
+    let addr = listener
+        .local_addr()
+        .map_err(|err| Error::MetricsBuildError(Box::new(err)))?;
+
+    let reader = Arc::new(
+        ManualReader::builder()
+            .with_temporality(Temporality::Cumulative)
+            .build(),
+    );
+    let weak = Arc::downgrade(&reader);
+    runtime.spawn(async move {
+        let Ok(listener) = TcpListener::from_std(listener) else {
+            return;
+        };
+        while let Ok((stream, _)) = listener.accept().await {
+            let Some(reader) = weak.upgrade() else {
+                return;
+            };
+            tokio::spawn(handle(stream, reader));
+        }
+    });
+    Ok((PrometheusReader { reader }, addr))
+}
+
+async fn handle(mut stream: TcpStream, reader: Arc<ManualReader>) {
+    let read = async {
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => return None,
+                Ok(n) => request.extend_from_slice(&buf[..n]),
+            }
+        }
+        Some(request)
+    };
+    // Don't keep the connection open for clients that never finish the request.
+    let Ok(Some(request)) = tokio::time::timeout(READ_TIMEOUT, read).await else {
+        return;
+    };
+
+    let request_line = request.split(|&b| b == b'\r').next().unwrap_or_default();
+    let mut parts = request_line.split(|&b| b == b' ');
+    let response = match (parts.next(), parts.next()) {
+        (Some(b"GET"), Some(b"/metrics")) => {
+            let mut rm = ResourceMetrics::default();
+            match reader.collect(&mut rm) {
+                Ok(()) => response("200 OK", CONTENT_TYPE, &render(&rm)),
+                Err(err) => response(
+                    "503 Service Unavailable",
+                    "text/plain",
+                    &format!("{}\n", err),
+                ),
+            }
+        }
+        _ => response("404 Not Found", "text/plain", "Not Found\n"),
+    };
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+fn response(status: &str, content_type: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+/// A metric family: all the samples of a metric name, which must be contiguous.
+#[derive(Default)]
+struct Family {
+    kind: &'static str,
+    help: String,
+    samples: String,
+}
+
+/// Renders the metrics in the Prometheus text exposition format.
+pub(crate) fn render(rm: &ResourceMetrics) -> String {
+    let mut families: BTreeMap<String, Family> = BTreeMap::new();
+    for sm in rm.scope_metrics() {
+        let mut scope = vec![KeyValue::new(
+            "otel_scope_name",
+            sm.scope().name().to_string(),
+        )];
+        if let Some(version) = sm.scope().version() {
+            scope.push(KeyValue::new("otel_scope_version", version.to_string()));
+        }
+        for metric in sm.metrics() {
+            match metric.data() {
+                AggregatedMetrics::F64(data) => add_metric(&mut families, metric, data, &scope),
+                AggregatedMetrics::U64(data) => add_metric(&mut families, metric, data, &scope),
+                AggregatedMetrics::I64(data) => add_metric(&mut families, metric, data, &scope),
+            }
+        }
+    }
+
+    let mut out = String::new();
+    render_target_info(&mut out, rm.resource());
+    for (name, family) in families {
+        if !family.help.is_empty() {
+            let _ = writeln!(out, "# HELP {} {}", name, escape_help(&family.help));
+        }
+        let _ = writeln!(out, "# TYPE {} {}", name, family.kind);
+        out.push_str(&family.samples);
+    }
+    out
+}
+
+fn render_target_info(out: &mut String, resource: &Resource) {
+    if resource.is_empty() {
+        return;
+    }
+    let attrs: Vec<KeyValue> = resource
+        .iter()
+        .map(|(key, value)| KeyValue::new(key.clone(), value.clone()))
+        .collect();
+    out.push_str("# HELP target_info Target metadata\n# TYPE target_info gauge\n");
+    let _ = writeln!(out, "target_info{} 1", labels(&attrs, &[], None));
+}
+
+fn add_metric<T: Copy + Display>(
+    families: &mut BTreeMap<String, Family>,
+    metric: &Metric,
+    data: &MetricData<T>,
+    scope: &[KeyValue],
+) {
+    let base = metric_name(metric.name(), metric.unit());
+    let (name, kind) = match data {
+        MetricData::Sum(sum) if sum.is_monotonic() => (format!("{}_total", base), "counter"),
+        MetricData::Sum(_) | MetricData::Gauge(_) => (base, "gauge"),
+        MetricData::Histogram(_) => (base, "histogram"),
+        MetricData::ExponentialHistogram(_) => return,
+    };
+    let family = families.entry(name.clone()).or_default();
+    if family.kind.is_empty() {
+        family.kind = kind;
+        family.help = metric.description().to_string();
+    } else if family.kind != kind {
+        // Prometheus can't have two types under one name; keep the first.
+        return;
+    }
+
+    let out = &mut family.samples;
+    match data {
+        MetricData::Gauge(gauge) => {
+            for dp in gauge.data_points() {
+                let attrs: Vec<_> = dp.attributes().cloned().collect();
+                let _ = writeln!(
+                    out,
+                    "{}{} {}",
+                    name,
+                    labels(&attrs, scope, None),
+                    dp.value()
+                );
+            }
+        }
+        MetricData::Sum(sum) => {
+            for dp in sum.data_points() {
+                let attrs: Vec<_> = dp.attributes().cloned().collect();
+                let _ = writeln!(
+                    out,
+                    "{}{} {}",
+                    name,
+                    labels(&attrs, scope, None),
+                    dp.value()
+                );
+            }
+        }
+        MetricData::Histogram(hist) => {
+            for dp in hist.data_points() {
+                let attrs: Vec<_> = dp.attributes().cloned().collect();
+                let mut cumulative = 0;
+                let bounds = dp.bounds().map(|b| b.to_string());
+                let bounds = bounds.chain(std::iter::once("+Inf".to_string()));
+                for (le, count) in bounds.zip(dp.bucket_counts()) {
+                    cumulative += count;
+                    let labels = labels(&attrs, scope, Some(&le));
+                    let _ = writeln!(out, "{}_bucket{} {}", name, labels, cumulative);
+                }
+                let labels = labels(&attrs, scope, None);
+                let _ = writeln!(out, "{}_sum{} {}", name, labels, dp.sum());
+                let _ = writeln!(out, "{}_count{} {}", name, labels, dp.count());
+            }
+        }
+        MetricData::ExponentialHistogram(_) => {}
+    }
+}
+
+/// Converts the metric name and unit to a Prometheus metric name.
+fn metric_name(name: &str, unit: &str) -> String {
+    let mut name = sanitize(name);
+    let unit = match unit {
+        "" | "1" => None,
+        "s" => Some("seconds"),
+        "ms" => Some("milliseconds"),
+        "us" => Some("microseconds"),
+        "ns" => Some("nanoseconds"),
+        "By" => Some("bytes"),
+        "KiBy" => Some("kibibytes"),
+        "MiBy" => Some("mebibytes"),
+        // Other units, e.g. annotations like `{request}`, are dropped.
+        _ => None,
+    };
+    if let Some(unit) = unit {
+        if !name.ends_with(unit) {
+            name.push('_');
+            name.push_str(unit);
+        }
+    }
+    name
+}
+
+fn sanitize(name: &str) -> String {
+    let mut out: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if out.starts_with(|c: char| c.is_ascii_digit()) {
+        out.insert(0, '_');
+    }
+    out
+}
+
+/// Formats the attributes and scope labels. Keys that sanitize to the same
+/// label name, such as `a.b` and `a_b`, or a user attribute named like a scope
+/// label, are merged into one label whose values are joined with `;` in the
+/// order of the original keys.
+fn labels(attrs: &[KeyValue], scope: &[KeyValue], le: Option<&str>) -> String {
+    let mut merged: Vec<(String, Vec<(&str, String)>)> = Vec::new();
+    for kv in attrs.iter().chain(scope) {
+        let value = match &kv.value {
+            Value::String(s) => s.as_str().to_string(),
+            value => value.to_string(),
+        };
+        let name = sanitize(kv.key.as_str());
+        match merged
+            .iter_mut()
+            .find(|(merged_name, _)| *merged_name == name)
+        {
+            Some((_, values)) => values.push((kv.key.as_str(), value)),
+            None => merged.push((name, vec![(kv.key.as_str(), value)])),
+        }
+    }
+
+    let pairs = merged.into_iter().map(|(name, mut values)| {
+        values.sort_by(|a, b| a.0.cmp(b.0));
+        let values: Vec<_> = values.into_iter().map(|(_, value)| value).collect();
+        (name, values.join(";"))
+    });
+    let pairs = pairs.chain(le.map(|le| ("le".to_string(), le.to_string())));
+
+    let mut out = String::new();
+    for (i, (key, value)) in pairs.enumerate() {
+        out.push(if i == 0 { '{' } else { ',' });
+        let _ = write!(out, "{}=\"{}\"", key, escape_label(&value));
+    }
+    if !out.is_empty() {
+        out.push('}');
+    }
+    out
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn escape_help(help: &str) -> String {
+    help.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::metrics::MeterProvider;
+    use opentelemetry::{InstrumentationScope, KeyValue};
+    use opentelemetry_sdk::metrics::SdkMeterProvider;
+    use opentelemetry_sdk::Resource;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{labels, metric_name, serve, PrometheusConfig};
+
+    #[test]
+    fn names() {
+        assert_eq!(
+            metric_name("http.server.duration", "ms"),
+            "http_server_duration_milliseconds"
+        );
+        assert_eq!(
+            metric_name("process.memory.usage", "By"),
+            "process_memory_usage_bytes"
+        );
+        assert_eq!(metric_name("latency_seconds", "s"), "latency_seconds");
+        assert_eq!(metric_name("2xx-count", "{request}"), "_2xx_count");
+    }
+
+    #[test]
+    fn colliding_labels() {
+        let attrs = [
+            KeyValue::new("a_b", "2"),
+            KeyValue::new("a.b", "1"),
+            KeyValue::new("otel_scope_name", "user"),
+            KeyValue::new("path", "/\"x\""),
+        ];
+        let scope = [KeyValue::new("otel_scope_name", "my-lib")];
+        assert_eq!(
+            labels(&attrs, &scope, Some("0.5")),
+            r#"{a_b="1;2",otel_scope_name="user;my-lib",path="/\"x\"",le="0.5"}"#
+        );
+        assert_eq!(labels(&[], &[], None), "");
+    }
+
+    async fn get(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn scrape() {
+        let config = PrometheusConfig::default().with_addr(([127, 0, 0, 1], 0).into());
+        let (reader, addr) = serve(&config).unwrap();
+        let provider = SdkMeterProvider::builder()
+            .with_reader(reader)
+            .with_resource(
+                Resource::builder_empty()
+                    .with_service_name("my-service")
+                    .build(),
+            )
+            .build();
+
+        let scope = InstrumentationScope::builder("my-lib")
+            .with_version("1.0")
+            .build();
+        let meter = provider.meter_with_scope(scope);
+        let requests = meter
+            .u64_counter("http.requests")
+            .with_description("Requests")
+            .build();
+        requests.add(2, &[KeyValue::new("http.method", "GET")]);
+        requests.add(1, &[KeyValue::new("http.method", "GET")]);
+        meter
+            .f64_histogram("http.duration")
+            .with_unit("ms")
+            .with_boundaries(vec![10.0, 100.0])
+            .build()
+            .record(50.0, &[]);
+
+        let response = get(addr, "/metrics").await;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK"), "{head}");
+        assert!(head.contains("text/plain; version=0.0.4"), "{head}");
+
+        let scope = r#"otel_scope_name="my-lib",otel_scope_version="1.0""#;
+        let expected = [
+            "# TYPE target_info gauge".to_string(),
+            r#"target_info{service_name="my-service"} 1"#.to_string(),
+            "# HELP http_requests_total Requests".to_string(),
+            "# TYPE http_requests_total counter".to_string(),
+            format!(r#"http_requests_total{{http_method="GET",{scope}}} 3"#),
+            "# TYPE http_duration_milliseconds histogram".to_string(),
+            format!(r#"http_duration_milliseconds_bucket{{{scope},le="10"}} 0"#),
+            format!(r#"http_duration_milliseconds_bucket{{{scope},le="100"}} 1"#),
+            format!(r#"http_duration_milliseconds_bucket{{{scope},le="+Inf"}} 1"#),
+            format!(r#"http_duration_milliseconds_sum{{{scope}}} 50"#),
+            format!(r#"http_duration_milliseconds_count{{{scope}}} 1"#),
+        ];
+        for line in expected {
+            assert!(body.lines().any(|l| l == line), "{line} not in\n{body}");
+        }
+
+        let response = get(addr, "/").await;
+        assert!(response.starts_with("HTTP/1.1 404"), "{response}");
+    }
+}
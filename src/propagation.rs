@@ -0,0 +1,249 @@
+use std::str::FromStr;
+
+use opentelemetry::propagation::{TextMapCompositePropagator, TextMapPropagator};
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry::Context;
+use opentelemetry_sdk::propagation::{BaggagePropagator, TraceContextPropagator};
+use opentelemetry_zipkin::B3Encoding;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::error_handler::{self, OtelError};
+
+/// Env var with a comma-separated list of propagators, e.g. `tracecontext,baggage,b3`.
+pub const OTEL_PROPAGATORS: &str = "OTEL_PROPAGATORS";
+
+/// Context propagation formats supported by [`UptraceBuilder::with_propagators`].
+///
+/// [`UptraceBuilder::with_propagators`]: crate::UptraceBuilder::with_propagators
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Propagator {
+    /// W3C `traceparent` and `tracestate` headers.
+    TraceContext,
+    /// W3C `baggage` header.
+    Baggage,
+    /// Zipkin B3 single `b3` header.
+    B3,
+    /// Zipkin B3 multiple `X-B3-*` headers.
+    B3Multi,
+    /// Jaeger `uber-trace-id` header.
+    Jaeger,
+}
+
+impl Propagator {
+    /// Propagators used when none are configured: tracecontext and baggage.
+    pub const DEFAULT: [Propagator; 2] = [Propagator::TraceContext, Propagator::Baggage];
+
+    fn build(self) -> Box<dyn TextMapPropagator + Send + Sync> {
+        match self {
+            Propagator::TraceContext => Box::new(TraceContextPropagator::new()),
+            Propagator::Baggage => Box::new(BaggagePropagator::new()),
+            Propagator::B3 => Box::new(opentelemetry_zipkin::Propagator::with_encoding(
+                B3Encoding::SingleHeader,
+            )),
+            Propagator::B3Multi => Box::new(opentelemetry_zipkin::Propagator::with_encoding(
+                B3Encoding::MultipleHeader,
+            )),
+            Propagator::Jaeger => Box::new(opentelemetry_jaeger_propagator::Propagator::new()),
+        }
+    }
+}
+
+impl FromStr for Propagator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "tracecontext" => Ok(Propagator::TraceContext),
+            "baggage" => Ok(Propagator::Baggage),
+            "b3" => Ok(Propagator::B3),
+            "b3multi" => Ok(Propagator::B3Multi),
+            "jaeger" => Ok(Propagator::Jaeger),
+            other => Err(format!("unsupported propagator: {:?}", other)),
+        }
+    }
+}
+
+/// Parses the `OTEL_PROPAGATORS` value; `none` disables propagation.
+/// Unsupported entries are reported to the error handler and skipped.
+pub(crate) fn parse_propagators(value: &str) -> Vec<Propagator> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty() && *s != "none")
+        .filter_map(|s| match s.parse() {
+            Ok(propagator) => Some(propagator),
+            Err(err) => {
+                error_handler::handle_error(OtelError::internal("Propagators.Invalid", err));
+                None
+            }
+        })
+        .collect()
+}
+
+pub(crate) fn composite(propagators: &[Propagator]) -> TextMapCompositePropagator {
+    let mut unique: Vec<Propagator> = Vec::with_capacity(propagators.len());
+    for propagator in propagators {
+        if !unique.contains(propagator) {
+            unique.push(*propagator);
+        }
+    }
+
+    TextMapCompositePropagator::new(unique.into_iter().map(Propagator::build).collect())
+}
+
+/// Returns the active OpenTelemetry context if it has a span, otherwise the
+/// context of the current `tracing` span.
+pub(crate) fn current_context() -> Context {
+    let cx = Context::current();
+    if cx.span().span_context().is_valid() {
+        return cx;
+    }
+    tracing::Span::current().context()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use opentelemetry::propagation::TextMapPropagator;
+    use opentelemetry::trace::{
+        SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState,
+    };
+    use opentelemetry::Context;
+
+    use super::{composite, parse_propagators, Propagator};
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const SPAN_ID: &str = "00f067aa0ba902b7";
+
+    fn sampled_context() -> Context {
+        Context::new().with_remote_span_context(SpanContext::new(
+            TraceId::from_hex(TRACE_ID).unwrap(),
+            SpanId::from_hex(SPAN_ID).unwrap(),
+            TraceFlags::SAMPLED,
+            true,
+            TraceState::default(),
+        ))
+    }
+
+    fn inject(propagator: Propagator, cx: &Context) -> HashMap<String, String> {
+        let mut carrier = HashMap::new();
+        propagator.build().inject_context(cx, &mut carrier);
+        carrier
+    }
+
+    fn extract(propagator: Propagator, headers: &[(&str, &str)]) -> Context {
+        let carrier: HashMap<String, String> = headers
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        propagator.build().extract(&carrier)
+    }
+
+    #[test]
+    fn b3_inject() {
+        let carrier = inject(Propagator::B3, &sampled_context());
+        assert_eq!(carrier["b3"], format!("{}-{}-1", TRACE_ID, SPAN_ID));
+
+        let carrier = inject(Propagator::B3Multi, &sampled_context());
+        assert_eq!(carrier["x-b3-traceid"], TRACE_ID);
+        assert_eq!(carrier["x-b3-spanid"], SPAN_ID);
+        assert_eq!(carrier["x-b3-sampled"], "1");
+    }
+
+    #[test]
+    fn b3_extract() {
+        let cx = extract(
+            Propagator::B3,
+            &[("b3", "a3ce929d0e0e4736-00f067aa0ba902b7-1")],
+        );
+        let sc = cx.span().span_context().clone();
+        assert!(sc.is_valid());
+        assert!(sc.is_sampled());
+        assert!(sc.is_remote());
+        assert_eq!(
+            sc.trace_id().to_string(),
+            "0000000000000000a3ce929d0e0e4736"
+        );
+
+        let cx = extract(
+            Propagator::B3Multi,
+            &[
+                ("x-b3-traceid", TRACE_ID),
+                ("x-b3-spanid", SPAN_ID),
+                ("x-b3-sampled", "0"),
+            ],
+        );
+        let sc = cx.span().span_context().clone();
+        assert!(sc.is_valid());
+        assert!(!sc.is_sampled());
+
+        let cx = extract(Propagator::B3, &[("b3", "0")]);
+        assert!(!cx.span().span_context().is_valid());
+    }
+
+    #[test]
+    fn b3_deferred() {
+        let header = format!("{}-{}", TRACE_ID, SPAN_ID);
+        let cx = extract(Propagator::B3, &[("b3", &header)]);
+        let sc = cx.span().span_context().clone();
+        assert!(sc.is_valid());
+        assert!(!sc.is_sampled());
+        assert_ne!(sc.trace_flags(), TraceFlags::NOT_SAMPLED);
+
+        // The decision stays deferred downstream instead of becoming "0".
+        let carrier = inject(Propagator::B3, &cx);
+        assert_eq!(carrier["b3"], header);
+        let carrier = inject(Propagator::B3Multi, &cx);
+        assert!(!carrier.contains_key("x-b3-sampled"));
+    }
+
+    #[test]
+    fn jaeger() {
+        let carrier = inject(Propagator::Jaeger, &sampled_context());
+        assert_eq!(
+            carrier["uber-trace-id"],
+            format!("{}:{}:0:1", TRACE_ID, SPAN_ID)
+        );
+
+        let cx = extract(
+            Propagator::Jaeger,
+            &[(
+                "uber-trace-id",
+                &format!("{}%3A{}%3A0%3A3", TRACE_ID, SPAN_ID),
+            )],
+        );
+        let sc = cx.span().span_context().clone();
+        assert!(sc.is_valid());
+        assert!(sc.is_sampled());
+        assert_eq!(sc.span_id().to_string(), SPAN_ID);
+    }
+
+    #[test]
+    fn composite_round_trip() {
+        let propagator = composite(&[Propagator::TraceContext, Propagator::B3, Propagator::B3]);
+        let mut carrier = HashMap::new();
+        propagator.inject_context(&sampled_context(), &mut carrier);
+        assert!(carrier.contains_key("traceparent"));
+        assert!(carrier.contains_key("b3"));
+
+        carrier.remove("traceparent");
+        let cx = propagator.extract(&carrier);
+        assert_eq!(cx.span().span_context().trace_id().to_string(), TRACE_ID);
+    }
+
+    #[test]
+    fn env_propagators() {
+        assert_eq!(
+            parse_propagators("tracecontext, baggage,b3multi"),
+            vec![
+                Propagator::TraceContext,
+                Propagator::Baggage,
+                Propagator::B3Multi
+            ]
+        );
+        assert_eq!(parse_propagators("none"), vec![]);
+        assert_eq!(parse_propagators("jaeger,xray"), vec![Propagator::Jaeger]);
+    }
+}
@@ -0,0 +1,638 @@
+//! Redaction of sensitive attribute values before export.
+//!
+//! [`RedactingSpanProcessor`] applies a [`Redactor`] to ended spans and
+//! [`RedactingLoggerProvider`] to log records as they are built, because the
+//! SDK log processors can't modify attributes that were already added.
+//!
+//! ```
+//! use regex::Regex;
+//! use uptrace::redact::Redactor;
+//!
+//! let redactor = Redactor::recommended()
+//!     .with_deny_keys(["ssn"])
+//!     .with_pattern(Regex::new(r"sk_live_[0-9a-zA-Z]+").unwrap());
+//! ```
+
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use opentelemetry::logs::{AnyValue, LogRecord, Logger, LoggerProvider, Severity};
+use opentelemetry::trace::{SpanId, Status, TraceFlags, TraceId};
+use opentelemetry::{Array, Context, InstrumentationScope, Key, KeyValue, StringValue, Value};
+use opentelemetry_sdk::error::OTelSdkResult;
+use opentelemetry_sdk::trace::{Span, SpanData, SpanProcessor};
+use opentelemetry_sdk::Resource;
+use regex::Regex;
+
+const DEFAULT_REPLACEMENT: &str = "[REDACTED]";
+
+/// Rules that decide which attribute values are replaced before export.
+///
+/// - values of keys on the deny list are replaced entirely; a key matches if
+///   one of its `.`, `_` or `-` separated segments equals a deny list entry,
+///   so `password` matches `db.password` and `user_password`;
+/// - string values matching a pattern have the match replaced;
+/// - URL attributes have their query string and fragment removed, and query
+///   attributes such as `url.query` are replaced entirely;
+/// - keys on the allow list are never modified.
+///
+/// Keys are compared case-insensitively.
+#[derive(Debug, Clone)]
+pub struct Redactor {
+    deny_keys: HashSet<String>,
+    allow_keys: HashSet<String>,
+    url_keys: HashSet<String>,
+    query_keys: HashSet<String>,
+    patterns: Vec<Regex>,
+    replacement: Cow<'static, str>,
+}
+
+impl Default for Redactor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Redactor {
+    /// Creates a redactor that only strips query strings from URL attributes.
+    pub fn new() -> Self {
+        Self {
+            deny_keys: HashSet::new(),
+            allow_keys: HashSet::new(),
+            url_keys: ["http.url", "http.target", "url.full"]
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            query_keys: ["url.query"].iter().map(|s| s.to_string()).collect(),
+            patterns: Vec::new(),
+            replacement: Cow::Borrowed(DEFAULT_REPLACEMENT),
+        }
+    }
+
+    /// Creates a redactor that also denies common credential and PII keys and
+    /// scrubs emails, card numbers and bearer tokens from values.
+    pub fn recommended() -> Self {
+        Self::new()
+            .with_deny_keys([
+                "password",
+                "passwd",
+                "secret",
+                "token",
+                "apikey",
+                "authorization",
+                "cookie",
+                "email",
+            ])
+            .with_pattern(Regex::new(r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}").unwrap())
+            .with_pattern(Regex::new(r"\b(?:\d[ -]?){12,18}\d\b").unwrap())
+            .with_pattern(Regex::new(r"(?i)\bbearer\s+[A-Za-z0-9\-._~+/]+=*").unwrap())
+    }
+
+    /// Add keys whose values are always replaced.
+    pub fn with_deny_keys<I, S>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.deny_keys
+            .extend(keys.into_iter().map(|key| key.as_ref().to_lowercase()));
+        self
+    }
+
+    /// Add keys that are never redacted.
+    pub fn with_allow_keys<I, S>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.allow_keys
+            .extend(keys.into_iter().map(|key| key.as_ref().to_lowercase()));
+        self
+    }
+
+    /// Add keys whose values are URLs to strip the query string from.
+    pub fn with_url_keys<I, S>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.url_keys
+            .extend(keys.into_iter().map(|key| key.as_ref().to_lowercase()));
+        self
+    }
+
+    /// Add keys whose values are query strings, which are replaced entirely.
+    pub fn with_query_keys<I, S>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.query_keys
+            .extend(keys.into_iter().map(|key| key.as_ref().to_lowercase()));
+        self
+    }
+
+    /// Add a pattern whose matches are replaced in string values.
+    pub fn with_pattern(mut self, pattern: Regex) -> Self {
+        self.patterns.push(pattern);
+        self
+    }
+
+    /// Set the replacement for redacted values, `[REDACTED]` by default.
+    pub fn with_replacement<T: Into<Cow<'static, str>>>(mut self, replacement: T) -> Self {
+        self.replacement = replacement.into();
+        self
+    }
+
+    /// Returns the value to export for the given attribute.
+    pub fn redact_value(&self, key: &Key, value: Value) -> Value {
+        let key = key.as_str().to_lowercase();
+        let key = key.as_str();
+        if self.allow_keys.contains(key) {
+            return value;
+        }
+        if self.is_denied(key) || self.query_keys.contains(key) {
+            return Value::String(self.replacement.to_string().into());
+        }
+
+        let strip_url = self.url_keys.contains(key);
+        match value {
+            Value::String(s) => Value::String(self.redact_str(s, strip_url)),
+            Value::Array(Array::String(values)) => Value::Array(Array::String(
+                values
+                    .into_iter()
+                    .map(|s| self.redact_str(s, strip_url))
+                    .collect(),
+            )),
+            value => value,
+        }
+    }
+
+    /// Returns the log record value to export for the given attribute, or for
+    /// the body if `key` is `None`. The body is only scrubbed with the patterns.
+    pub fn redact_any_value(&self, key: Option<&Key>, value: AnyValue) -> AnyValue {
+        let key = key.map(|key| key.as_str().to_lowercase());
+        if let Some(key) = key.as_deref() {
+            if self.allow_keys.contains(key) {
+                return value;
+            }
+            if self.is_denied(key) || self.query_keys.contains(key) {
+                return AnyValue::String(self.replacement.to_string().into());
+            }
+        }
+
+        let strip_url = key.is_some_and(|key| self.url_keys.contains(&key));
+        match value {
+            AnyValue::String(s) => AnyValue::String(self.redact_str(s, strip_url)),
+            AnyValue::ListAny(values) => AnyValue::ListAny(Box::new(
+                values
+                    .into_iter()
+                    .map(|value| self.redact_any_value(None, value))
+                    .collect(),
+            )),
+            AnyValue::Map(map) => AnyValue::Map(Box::new(
+                map.into_iter()
+                    .map(|(key, value)| {
+                        let value = self.redact_any_value(Some(&key), value);
+                        (key, value)
+                    })
+                    .collect(),
+            )),
+            value => value,
+        }
+    }
+
+    /// Redacts the value of every attribute in place.
+    pub fn redact_attributes(&self, attrs: &mut [KeyValue]) {
+        for kv in attrs {
+            let value = std::mem::replace(&mut kv.value, Value::Bool(false));
+            kv.value = self.redact_value(&kv.key, value);
+        }
+    }
+
+    /// Expects a lowercase key.
+    fn is_denied(&self, key: &str) -> bool {
+        if self.deny_keys.is_empty() {
+            return false;
+        }
+        self.deny_keys.contains(key)
+            || key
+                .split(['.', '_', '-'])
+                .any(|segment| self.deny_keys.contains(segment))
+    }
+
+    fn redact_str(&self, s: StringValue, strip_url: bool) -> StringValue {
+        let mut redacted = Cow::Borrowed(s.as_str());
+        if strip_url {
+            if let Some(i) = redacted.find(['?', '#']) {
+                redacted = Cow::Owned(redacted[..i].to_string());
+            }
+        }
+        for pattern in &self.patterns {
+            if let Cow::Owned(replaced) = pattern.replace_all(&redacted, self.replacement.as_ref())
+            {
+                redacted = Cow::Owned(replaced);
+            }
+        }
+
+        match redacted {
+            Cow::Borrowed(_) => s,
+            Cow::Owned(redacted) => redacted.into(),
+        }
+    }
+
+    /// Redacts span, event and link attributes. The error status description
+    /// is only scrubbed with the patterns.
+    pub(crate) fn redact_span(&self, span: &mut SpanData) {
+        self.redact_attributes(&mut span.attributes);
+        for event in span.events.events.iter_mut() {
+            self.redact_attributes(&mut event.attributes);
+        }
+        for link in span.links.links.iter_mut() {
+            self.redact_attributes(&mut link.attributes);
+        }
+        if let Status::Error { description } = &mut span.status {
+            let redacted = self.redact_str(StringValue::from(description.to_string()), false);
+            if redacted.as_str() != description.as_ref() {
+                *description = Cow::Owned(redacted.to_string());
+            }
+        }
+    }
+}
+
+/// Span processor that applies a [`Redactor`] to ended spans before passing
+/// them to the inner processor.
+#[derive(Debug)]
+pub struct RedactingSpanProcessor<P> {
+    inner: P,
+    redactor: Arc<Redactor>,
+}
+
+impl<P: SpanProcessor> RedactingSpanProcessor<P> {
+    pub fn new(inner: P, redactor: Redactor) -> Self {
+        Self {
+            inner,
+            redactor: Arc::new(redactor),
+        }
+    }
+}
+
+impl<P: SpanProcessor> SpanProcessor for RedactingSpanProcessor<P> {
+    fn on_start(&self, span: &mut Span, cx: &Context) {
+        self.inner.on_start(span, cx)
+    }
+
+    fn on_end(&self, mut span: SpanData) {
+        self.redactor.redact_span(&mut span);
+        self.inner.on_end(span)
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource)
+    }
+}
+
+/// Logger provider that applies a [`Redactor`] to the body and attributes of
+/// log records before they reach the inner provider's records.
+#[derive(Debug, Clone)]
+pub struct RedactingLoggerProvider<P> {
+    inner: P,
+    redactor: Option<Arc<Redactor>>,
+}
+
+impl<P: LoggerProvider> RedactingLoggerProvider<P> {
+    pub fn new(inner: P, redactor: Redactor) -> Self {
+        Self::with_optional(inner, Some(redactor))
+    }
+
+    /// Creates a provider that passes records through unchanged if `redactor`
+    /// is `None`, so the logger type doesn't depend on the configuration.
+    pub(crate) fn with_optional(inner: P, redactor: Option<Redactor>) -> Self {
+        Self {
+            inner,
+            redactor: redactor.map(Arc::new),
+        }
+    }
+
+    /// Returns the inner provider.
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+}
+
+impl<P: LoggerProvider> LoggerProvider for RedactingLoggerProvider<P> {
+    type Logger = RedactingLogger<P::Logger>;
+
+    fn logger_with_scope(&self, scope: InstrumentationScope) -> Self::Logger {
+        RedactingLogger {
+            inner: self.inner.logger_with_scope(scope),
+            redactor: self.redactor.clone(),
+        }
+    }
+}
+
+/// Logger created by [`RedactingLoggerProvider`].
+#[derive(Debug)]
+pub struct RedactingLogger<L> {
+    inner: L,
+    redactor: Option<Arc<Redactor>>,
+}
+
+impl<L: Logger> Logger for RedactingLogger<L> {
+    type LogRecord = RedactingLogRecord<L::LogRecord>;
+
+    fn create_log_record(&self) -> Self::LogRecord {
+        RedactingLogRecord {
+            inner: self.inner.create_log_record(),
+            redactor: self.redactor.clone(),
+        }
+    }
+
+    fn emit(&self, record: Self::LogRecord) {
+        self.inner.emit(record.inner)
+    }
+}
+
+/// Log record created by [`RedactingLogger`].
+#[derive(Debug)]
+pub struct RedactingLogRecord<R> {
+    inner: R,
+    redactor: Option<Arc<Redactor>>,
+}
+
+impl<R: LogRecord> LogRecord for RedactingLogRecord<R> {
+    fn set_event_name(&mut self, name: &'static str) {
+        self.inner.set_event_name(name)
+    }
+
+    fn set_target<T>(&mut self, target: T)
+    where
+        T: Into<Cow<'static, str>>,
+    {
+        self.inner.set_target(target)
+    }
+
+    fn set_timestamp(&mut self, timestamp: SystemTime) {
+        self.inner.set_timestamp(timestamp)
+    }
+
+    fn set_observed_timestamp(&mut self, timestamp: SystemTime) {
+        self.inner.set_observed_timestamp(timestamp)
+    }
+
+    fn set_severity_text(&mut self, text: &'static str) {
+        self.inner.set_severity_text(text)
+    }
+
+    fn set_severity_number(&mut self, number: Severity) {
+        self.inner.set_severity_number(number)
+    }
+
+    fn set_body(&mut self, body: AnyValue) {
+        let body = match &self.redactor {
+            Some(redactor) => redactor.redact_any_value(None, body),
+            None => body,
+        };
+        self.inner.set_body(body)
+    }
+
+    fn add_attributes<I, K, V>(&mut self, attributes: I)
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<Key>,
+        V: Into<AnyValue>,
+    {
+        for (key, value) in attributes {
+            self.add_attribute(key, value);
+        }
+    }
+
+    fn add_attribute<K, V>(&mut self, key: K, value: V)
+    where
+        K: Into<Key>,
+        V: Into<AnyValue>,
+    {
+        let key = key.into();
+        let value = match &self.redactor {
+            Some(redactor) => redactor.redact_any_value(Some(&key), value.into()),
+            None => value.into(),
+        };
+        self.inner.add_attribute(key, value)
+    }
+
+    fn set_trace_context(
+        &mut self,
+        trace_id: TraceId,
+        span_id: SpanId,
+        trace_flags: Option<TraceFlags>,
+    ) {
+        self.inner.set_trace_context(trace_id, span_id, trace_flags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::logs::{AnyValue, LogRecord as _, Logger as _, LoggerProvider as _};
+    use opentelemetry::trace::{
+        Span as _, SpanContext, SpanId, Status, TraceFlags, TraceId, TraceState, Tracer,
+        TracerProvider as _,
+    };
+    use opentelemetry::{Key, KeyValue, Value};
+    use opentelemetry_sdk::logs::{InMemoryLogExporter, SdkLoggerProvider};
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use regex::Regex;
+
+    use super::{RedactingLoggerProvider, RedactingSpanProcessor, Redactor};
+    use crate::testing::Collect;
+
+    fn redact(redactor: &Redactor, key: &'static str, value: &'static str) -> String {
+        redactor
+            .redact_value(&Key::new(key), Value::from(value))
+            .as_str()
+            .into_owned()
+    }
+
+    #[test]
+    fn deny_keys() {
+        let redactor = Redactor::new().with_deny_keys(["password", "Email"]);
+        assert_eq!(redact(&redactor, "db.password", "hunter2"), "[REDACTED]");
+        assert_eq!(
+            redact(&redactor, "user_email", "john@example.com"),
+            "[REDACTED]"
+        );
+        assert_eq!(redact(&redactor, "passwords_count", "2"), "2");
+    }
+
+    #[test]
+    fn allow_keys() {
+        let redactor = Redactor::recommended().with_allow_keys(["enduser.email", "User_Email"]);
+        assert_eq!(
+            redact(&redactor, "enduser.email", "john@example.com"),
+            "john@example.com"
+        );
+        assert_eq!(
+            redact(&redactor, "user_email", "john@example.com"),
+            "john@example.com"
+        );
+    }
+
+    #[test]
+    fn patterns() {
+        let redactor = Redactor::recommended()
+            .with_pattern(Regex::new(r"sk_live_[0-9a-zA-Z]+").unwrap())
+            .with_replacement("***");
+        assert_eq!(
+            redact(
+                &redactor,
+                "message",
+                "paid with 4111 1111 1111 1111 by john@example.com"
+            ),
+            "paid with *** by ***"
+        );
+        assert_eq!(
+            redact(&redactor, "http.request.header.x_api", "Bearer abc.def-ghi"),
+            "***"
+        );
+        assert_eq!(redact(&redactor, "note", "key sk_live_abc123"), "key ***");
+        assert_eq!(redact(&redactor, "http.route", "/posts/:id"), "/posts/:id");
+    }
+
+    #[test]
+    fn url_query() {
+        let redactor = Redactor::new();
+        assert_eq!(
+            redact(
+                &redactor,
+                "http.url",
+                "https://example.com/login?token=abc#top"
+            ),
+            "https://example.com/login"
+        );
+        assert_eq!(
+            redact(&redactor, "http.target", "/login?token=abc"),
+            "/login"
+        );
+        assert_eq!(redact(&redactor, "url.query", "token=abc"), "[REDACTED]");
+        assert_eq!(redact(&redactor, "http.route", "/a?b"), "/a?b");
+    }
+
+    #[test]
+    fn log_values() {
+        let redactor = Redactor::recommended();
+        assert_eq!(
+            redactor.redact_any_value(None, AnyValue::from("login by john@example.com")),
+            AnyValue::from("login by [REDACTED]")
+        );
+        let map = AnyValue::Map(Box::new(
+            [(Key::new("password"), AnyValue::from("hunter2"))]
+                .into_iter()
+                .collect(),
+        ));
+        let AnyValue::Map(map) = redactor.redact_any_value(Some(&Key::new("user")), map) else {
+            panic!("expected a map");
+        };
+        assert_eq!(
+            map.get(&Key::new("password")),
+            Some(&AnyValue::from("[REDACTED]"))
+        );
+    }
+
+    #[test]
+    fn processor() {
+        let spans = Collect::default();
+        let provider = SdkTracerProvider::builder()
+            .with_span_processor(RedactingSpanProcessor::new(
+                spans.clone(),
+                Redactor::recommended(),
+            ))
+            .build();
+
+        let linked = SpanContext::new(
+            TraceId::from(1),
+            SpanId::from(1),
+            TraceFlags::SAMPLED,
+            false,
+            TraceState::NONE,
+        );
+        let mut span = provider.tracer("test").start("GET /login");
+        span.add_link(
+            linked,
+            vec![KeyValue::new("user_email", "john@example.com")],
+        );
+        span.set_status(Status::error("no account for john@example.com"));
+        span.set_attribute(KeyValue::new(
+            "http.url",
+            "http://localhost/login?password=1",
+        ));
+        span.set_attribute(KeyValue::new("user_email", "john@example.com"));
+        span.add_event(
+            "login",
+            vec![KeyValue::new("message", "login by john@example.com")],
+        );
+        span.end();
+
+        let spans = spans.spans();
+        let span = &spans[0];
+        assert_eq!(
+            span.attributes[0].value,
+            Value::from("http://localhost/login")
+        );
+        assert_eq!(span.attributes[1].value, Value::from("[REDACTED]"));
+        let event = span.events.iter().next().unwrap();
+        assert_eq!(
+            event.attributes[0].value,
+            Value::from("login by [REDACTED]")
+        );
+        let link = span.links.iter().next().unwrap();
+        assert_eq!(link.attributes[0].value, Value::from("[REDACTED]"));
+        assert_eq!(span.status, Status::error("no account for [REDACTED]"));
+    }
+
+    #[test]
+    fn logger_provider() {
+        let exporter = InMemoryLogExporter::default();
+        let sdk_provider = SdkLoggerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let provider = RedactingLoggerProvider::new(sdk_provider, Redactor::recommended());
+
+        let logger = provider.logger("test");
+        let mut record = logger.create_log_record();
+        record.set_body("signup by john@example.com".into());
+        record.add_attributes([
+            ("user_email", AnyValue::from("john@example.com")),
+            (
+                "http.url",
+                AnyValue::from("http://localhost/signup?token=1"),
+            ),
+            ("event_id", AnyValue::from(20)),
+        ]);
+        logger.emit(record);
+
+        let logs = exporter.get_emitted_logs().unwrap();
+        let record = &logs[0].record;
+        assert_eq!(record.body(), Some(&AnyValue::from("signup by [REDACTED]")));
+        let attrs: Vec<_> = record.attributes_iter().cloned().collect();
+        assert_eq!(
+            attrs,
+            vec![
+                (Key::new("user_email"), AnyValue::from("[REDACTED]")),
+                (
+                    Key::new("http.url"),
+                    AnyValue::from("http://localhost/signup")
+                ),
+                (Key::new("event_id"), AnyValue::from(20)),
+            ]
+        );
+    }
+}
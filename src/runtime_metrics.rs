@@ -0,0 +1,280 @@
+//! Process and tokio runtime metrics, registered with the meter provider
+//! configured by [`UptraceBuilder`](crate::UptraceBuilder) unless disabled with
+//! [`MetricsConfig::with_runtime_metrics`](crate::metrics::MetricsConfig::with_runtime_metrics).
+//!
+//! Process metrics follow the OpenTelemetry semantic conventions and are read
+//! from `/proc/self` on Linux, so they work in containers without an agent.
+//! On other platforms only the tokio metrics are collected.
+
+use opentelemetry::metrics::Meter;
+use tokio::runtime::Handle;
+
+/// Registers the process metrics and the metrics of the given tokio runtime.
+pub(crate) fn register(meter: &Meter, runtime: &Handle) {
+    #[cfg(target_os = "linux")]
+    process::register(meter);
+    register_tokio(meter, runtime);
+}
+
+fn register_tokio(meter: &Meter, runtime: &Handle) {
+    let handle = runtime.clone();
+    meter
+        .u64_observable_gauge("tokio.worker.count")
+        .with_description("Number of worker threads used by the runtime")
+        .with_unit("{thread}")
+        .with_callback(move |o| o.observe(handle.metrics().num_workers() as u64, &[]))
+        .build();
+
+    let handle = runtime.clone();
+    meter
+        .u64_observable_gauge("tokio.task.alive.count")
+        .with_description("Number of alive tasks in the runtime")
+        .with_unit("{task}")
+        .with_callback(move |o| o.observe(handle.metrics().num_alive_tasks() as u64, &[]))
+        .build();
+
+    let handle = runtime.clone();
+    meter
+        .u64_observable_gauge("tokio.global_queue.depth")
+        .with_description("Number of tasks in the runtime's global queue")
+        .with_unit("{task}")
+        .with_callback(move |o| o.observe(handle.metrics().global_queue_depth() as u64, &[]))
+        .build();
+
+    #[cfg(target_has_atomic = "64")]
+    {
+        let handle = runtime.clone();
+        meter
+            .f64_observable_counter("tokio.worker.busy_duration")
+            .with_description("Time the worker threads spent busy, summed over all workers")
+            .with_unit("s")
+            .with_callback(move |o| {
+                let metrics = handle.metrics();
+                let busy: f64 = (0..metrics.num_workers())
+                    .map(|worker| metrics.worker_total_busy_duration(worker).as_secs_f64())
+                    .sum();
+                o.observe(busy, &[]);
+            })
+            .build();
+
+        let handle = runtime.clone();
+        meter
+            .u64_observable_counter("tokio.worker.park.count")
+            .with_description("Number of times the worker threads parked")
+            .with_unit("{park}")
+            .with_callback(move |o| {
+                let metrics = handle.metrics();
+                let parks = (0..metrics.num_workers())
+                    .map(|worker| metrics.worker_park_count(worker))
+                    .sum();
+                o.observe(parks, &[]);
+            })
+            .build();
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod process {
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    use opentelemetry::metrics::Meter;
+    use opentelemetry::KeyValue;
+
+    /// Process statistics read from `/proc/self/stat`.
+    #[derive(Debug, Default, Clone, Copy, PartialEq)]
+    pub(super) struct Stat {
+        /// CPU time spent in user mode, in seconds.
+        pub(super) user: f64,
+        /// CPU time spent in kernel mode, in seconds.
+        pub(super) system: f64,
+        pub(super) threads: u64,
+        /// Virtual memory size in bytes.
+        pub(super) virtual_memory: u64,
+        /// Resident set size in bytes.
+        pub(super) rss: u64,
+    }
+
+    impl Stat {
+        fn read() -> Option<Self> {
+            let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+            // SAFETY: sysconf has no preconditions.
+            let (ticks, page_size) = unsafe {
+                (
+                    libc::sysconf(libc::_SC_CLK_TCK),
+                    libc::sysconf(libc::_SC_PAGESIZE),
+                )
+            };
+            if ticks <= 0 || page_size <= 0 {
+                return None;
+            }
+            Self::parse(&stat, ticks as f64, page_size as u64)
+        }
+
+        pub(super) fn parse(stat: &str, ticks: f64, page_size: u64) -> Option<Self> {
+            // The command name may contain spaces and parentheses, so the
+            // fields are counted from the last parenthesis, starting with
+            // the third field (state).
+            let (_, fields) = stat.rsplit_once(')')?;
+            let fields: Vec<&str> = fields.split_whitespace().collect();
+            let field = |n: usize| -> Option<u64> { fields.get(n - 3)?.parse().ok() };
+            Some(Self {
+                user: field(14)? as f64 / ticks,
+                system: field(15)? as f64 / ticks,
+                threads: field(20)?,
+                virtual_memory: field(23)?,
+                rss: field(24)? * page_size,
+            })
+        }
+
+        fn cpu_time(&self) -> f64 {
+            self.user + self.system
+        }
+    }
+
+    fn cpu_mode(mode: &'static str) -> [KeyValue; 1] {
+        [KeyValue::new("cpu.mode", mode)]
+    }
+
+    fn open_fds() -> Option<u64> {
+        Some(std::fs::read_dir("/proc/self/fd").ok()?.count() as u64)
+    }
+
+    pub(super) fn register(meter: &Meter) {
+        meter
+            .f64_observable_counter("process.cpu.time")
+            .with_description("Total CPU seconds broken down by different CPU modes")
+            .with_unit("s")
+            .with_callback(|o| {
+                if let Some(stat) = Stat::read() {
+                    o.observe(stat.user, &cpu_mode("user"));
+                    o.observe(stat.system, &cpu_mode("system"));
+                }
+            })
+            .build();
+
+        let last = Mutex::new((Instant::now(), Stat::read().unwrap_or_default().cpu_time()));
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get()) as f64;
+        meter
+            .f64_observable_gauge("process.cpu.utilization")
+            .with_description(
+                "CPU time used by the process since the last measurement, \
+                 divided by the elapsed time and the number of available CPUs",
+            )
+            .with_unit("1")
+            .with_callback(move |o| {
+                let Some(stat) = Stat::read() else {
+                    return;
+                };
+                let now = Instant::now();
+                let mut last = last.lock().unwrap_or_else(|e| e.into_inner());
+                let elapsed = now.duration_since(last.0).as_secs_f64();
+                if elapsed > 0.0 {
+                    let used = (stat.cpu_time() - last.1).max(0.0);
+                    o.observe(used / elapsed / cpus, &[]);
+                }
+                *last = (now, stat.cpu_time());
+            })
+            .build();
+
+        meter
+            .i64_observable_up_down_counter("process.memory.usage")
+            .with_description("The amount of physical memory in use")
+            .with_unit("By")
+            .with_callback(|o| {
+                if let Some(stat) = Stat::read() {
+                    o.observe(stat.rss as i64, &[]);
+                }
+            })
+            .build();
+
+        meter
+            .i64_observable_up_down_counter("process.memory.virtual")
+            .with_description("The amount of committed virtual memory")
+            .with_unit("By")
+            .with_callback(|o| {
+                if let Some(stat) = Stat::read() {
+                    o.observe(stat.virtual_memory as i64, &[]);
+                }
+            })
+            .build();
+
+        meter
+            .i64_observable_up_down_counter("process.thread.count")
+            .with_description("Process threads count")
+            .with_unit("{thread}")
+            .with_callback(|o| {
+                if let Some(stat) = Stat::read() {
+                    o.observe(stat.threads as i64, &[]);
+                }
+            })
+            .build();
+
+        meter
+            .i64_observable_up_down_counter("process.unix.file_descriptor.count")
+            .with_description("Number of unix file descriptors in use by the process")
+            .with_unit("{file_descriptor}")
+            .with_callback(|o| {
+                if let Some(fds) = open_fds() {
+                    o.observe(fds as i64, &[]);
+                }
+            })
+            .build();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::metrics::MeterProvider;
+    use opentelemetry_sdk::metrics::{InMemoryMetricExporter, PeriodicReader, SdkMeterProvider};
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn parse_stat() {
+        use super::process::Stat;
+
+        let line = "4242 (my (weird) app) S 1 4242 4242 0 -1 4194560 1234 0 0 0 \
+                    250 50 0 0 20 0 7 0 123456 104857600 2560 18446744073709551615";
+        let stat = Stat::parse(line, 100.0, 4096).unwrap();
+        assert_eq!(
+            stat,
+            Stat {
+                user: 2.5,
+                system: 0.5,
+                threads: 7,
+                virtual_memory: 104857600,
+                rss: 2560 * 4096,
+            }
+        );
+        assert_eq!(Stat::parse("garbage", 100.0, 4096), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn register() {
+        let exporter = InMemoryMetricExporter::default();
+        let provider = SdkMeterProvider::builder()
+            .with_reader(PeriodicReader::builder(exporter.clone()).build())
+            .build();
+        super::register(&provider.meter("test"), &tokio::runtime::Handle::current());
+        provider.force_flush().unwrap();
+
+        let exported = exporter.get_finished_metrics().unwrap();
+        let names: Vec<_> = exported
+            .iter()
+            .flat_map(|rm| rm.scope_metrics())
+            .flat_map(|sm| sm.metrics())
+            .map(|m| m.name().to_string())
+            .collect();
+        assert!(names.iter().any(|name| name == "tokio.worker.count"));
+        assert!(names.iter().any(|name| name == "tokio.global_queue.depth"));
+        #[cfg(target_os = "linux")]
+        for name in [
+            "process.cpu.time",
+            "process.memory.usage",
+            "process.thread.count",
+            "process.unix.file_descriptor.count",
+        ] {
+            assert!(names.iter().any(|n| n == name), "{name} in {names:?}");
+        }
+    }
+}
@@ -0,0 +1,348 @@
+//! RED metrics (rate, errors, duration) derived from ended spans.
+//!
+//! [`SpanMetricsProcessor`] records two instruments with the meter provider
+//! configured by [`UptraceBuilder`](crate::UptraceBuilder), enabled with
+//! [`UptraceBuilder::with_span_metrics`](crate::UptraceBuilder::with_span_metrics):
+//!
+//! - `traces.span.metrics.calls`, a counter of ended spans; the error rate is
+//!   the rate of the calls with `status.code` set to `STATUS_CODE_ERROR`;
+//! - `traces.span.metrics.duration`, a histogram of span durations in seconds.
+//!
+//! Both have the `span.name`, `span.kind` and `status.code` attributes, plus
+//! the span attributes listed with [`SpanMetricsConfig::with_dimensions`]. When
+//! [exemplars](crate::metrics::ExemplarFilter) are enabled, the duration data
+//! points carry exemplars of the spans they were recorded from.
+//!
+//! ```
+//! use uptrace::span_metrics::SpanMetricsConfig;
+//!
+//! let config = SpanMetricsConfig::default()
+//!     .with_dimensions(["http.request.method", "http.response.status_code"])
+//!     .with_max_series(500);
+//! ```
+
+use std::time::Duration;
+
+use opentelemetry::metrics::{Counter, Histogram, Meter};
+use opentelemetry::trace::{SpanKind, Status, TraceContextExt};
+use opentelemetry::{Context, Key, KeyValue};
+use opentelemetry_sdk::error::OTelSdkResult;
+use opentelemetry_sdk::trace::{Span, SpanData, SpanProcessor};
+
+use crate::cardinality::{self, Limited, Limiter};
+
+/// Default maximum number of distinct attribute sets.
+pub const DEFAULT_MAX_SERIES: usize = 1000;
+
+pub use crate::metrics::OVERFLOW_KEY;
+
+/// Bucket boundaries of the duration histogram, in seconds.
+const DURATION_BUCKETS: [f64; 14] = [
+    0.005, 0.01, 0.025, 0.05, 0.075, 0.1, 0.25, 0.5, 0.75, 1.0, 2.5, 5.0, 7.5, 10.0,
+];
+
+/// Span metrics configuration, see [`UptraceBuilder::with_span_metrics`](crate::UptraceBuilder::with_span_metrics).
+#[derive(Debug, Clone)]
+pub struct SpanMetricsConfig {
+    dimensions: Vec<Key>,
+    max_series: usize,
+}
+
+impl Default for SpanMetricsConfig {
+    fn default() -> Self {
+        Self {
+            dimensions: Vec::new(),
+            max_series: DEFAULT_MAX_SERIES,
+        }
+    }
+}
+
+impl SpanMetricsConfig {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add span attributes to the metric attributes. Spans that don't have
+    /// one of the attributes are recorded without it.
+    pub fn with_dimensions<I, K>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: Into<Key>,
+    {
+        self.dimensions.extend(keys.into_iter().map(Into::into));
+        self
+    }
+
+    /// Set the maximum number of distinct attribute sets. Once it's reached,
+    /// the spans with new attribute sets are recorded in a single series with
+    /// the [`OVERFLOW_KEY`] attribute and counted with the
+    /// `uptrace.metric.overflow` counter. Defaults to [`DEFAULT_MAX_SERIES`].
+    pub fn with_max_series(mut self, max_series: usize) -> Self {
+        self.max_series = max_series;
+        self
+    }
+}
+
+/// Span processor that records the RED metrics of ended spans, see the
+/// [module documentation](self).
+#[derive(Debug)]
+pub struct SpanMetricsProcessor {
+    dimensions: Vec<Key>,
+    calls: Counter<u64>,
+    duration: Histogram<f64>,
+}
+
+impl SpanMetricsProcessor {
+    /// Creates the processor, registering its instruments with the given meter.
+    ///
+    /// The instruments are limited to `max_series` attribute sets like the
+    /// instruments with a [cardinality limit](crate::metrics::MetricsConfig::with_cardinality_limit).
+    pub fn new(config: SpanMetricsConfig, meter: &Meter) -> Self {
+        let overflowed = cardinality::overflow_counter(meter);
+        let limiter = |name: &str| Limiter::new(name, config.max_series, None, overflowed.clone());
+
+        let calls = meter
+            .u64_counter("traces.span.metrics.calls")
+            .with_description("Number of ended spans")
+            .with_unit("{call}")
+            .build();
+        let duration = meter
+            .f64_histogram("traces.span.metrics.duration")
+            .with_description("Duration of the spans")
+            .with_unit("s")
+            .with_boundaries(DURATION_BUCKETS.to_vec())
+            .build();
+        Self {
+            calls: Counter::new(Limited::new(
+                limiter("traces.span.metrics.calls"),
+                move |value, attrs| calls.add(value, attrs),
+            )),
+            duration: Histogram::new(Limited::new(
+                limiter("traces.span.metrics.duration"),
+                move |value, attrs| duration.record(value, attrs),
+            )),
+            dimensions: config.dimensions,
+        }
+    }
+
+    fn attributes(&self, span: &SpanData) -> Vec<KeyValue> {
+        let mut attributes = vec![
+            KeyValue::new("span.name", span.name.clone()),
+            KeyValue::new("span.kind", span_kind(&span.span_kind)),
+            KeyValue::new("status.code", status_code(&span.status)),
+        ];
+        attributes.extend(
+            self.dimensions
+                .iter()
+                .filter_map(|key| span.attributes.iter().find(|kv| &kv.key == key).cloned()),
+        );
+        attributes
+    }
+}
+
+fn span_kind(kind: &SpanKind) -> &'static str {
+    match kind {
+        SpanKind::Client => "SPAN_KIND_CLIENT",
+        SpanKind::Server => "SPAN_KIND_SERVER",
+        SpanKind::Producer => "SPAN_KIND_PRODUCER",
+        SpanKind::Consumer => "SPAN_KIND_CONSUMER",
+        SpanKind::Internal => "SPAN_KIND_INTERNAL",
+    }
+}
+
+fn status_code(status: &Status) -> &'static str {
+    match status {
+        Status::Unset => "STATUS_CODE_UNSET",
+        Status::Ok => "STATUS_CODE_OK",
+        Status::Error { .. } => "STATUS_CODE_ERROR",
+    }
+}
+
+impl SpanProcessor for SpanMetricsProcessor {
+    fn on_start(&self, _span: &mut Span, _cx: &Context) {}
+
+    fn on_end(&self, span: SpanData) {
+        let attributes = self.attributes(&span);
+        let duration = span
+            .end_time
+            .duration_since(span.start_time)
+            .unwrap_or_default()
+            .as_secs_f64();
+
+        self.calls.add(1, &attributes);
+        // The span is the current one while the duration is recorded, so
+        // that the exemplar points at it.
+        let _guard = Context::current()
+            .with_remote_span_context(span.span_context.clone())
+            .attach();
+        self.duration.record(duration, &attributes);
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        Ok(())
+    }
+
+    fn shutdown_with_timeout(&self, _timeout: Duration) -> OTelSdkResult {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::metrics::MeterProvider as _;
+    use opentelemetry::trace::{Span as _, Tracer, TracerProvider as _};
+    use opentelemetry::Value;
+    use opentelemetry_sdk::metrics::data::{AggregatedMetrics, MetricData};
+    use opentelemetry_sdk::metrics::{InMemoryMetricExporter, PeriodicReader, SdkMeterProvider};
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+
+    use super::*;
+    use crate::redact::{RedactingSpanProcessor, Redactor};
+
+    /// Records the spans and returns the calls as (attributes, count) pairs.
+    fn calls(
+        config: SpanMetricsConfig,
+        redactor: Option<Redactor>,
+        spans: &[(&str, Status, &str)],
+    ) -> Vec<(Vec<KeyValue>, u64)> {
+        let exporter = InMemoryMetricExporter::default();
+        let meter_provider = SdkMeterProvider::builder()
+            .with_reader(PeriodicReader::builder(exporter.clone()).build())
+            .build();
+        let processor = SpanMetricsProcessor::new(config, &meter_provider.meter("test"));
+        let tracer_provider = match redactor {
+            Some(redactor) => SdkTracerProvider::builder()
+                .with_span_processor(RedactingSpanProcessor::new(processor, redactor)),
+            None => SdkTracerProvider::builder().with_span_processor(processor),
+        }
+        .build();
+
+        let tracer = tracer_provider.tracer("test");
+        for (name, status, route) in spans {
+            let mut span = tracer.start(name.to_string());
+            span.set_attribute(KeyValue::new("http.route", route.to_string()));
+            span.set_attribute(KeyValue::new("user.id", "42"));
+            span.set_status(status.clone());
+            span.end();
+        }
+        meter_provider.force_flush().unwrap();
+
+        let metrics = exporter.get_finished_metrics().unwrap();
+        let metrics: Vec<_> = metrics
+            .iter()
+            .flat_map(|rm| rm.scope_metrics())
+            .flat_map(|sm| sm.metrics())
+            .collect();
+        let duration = metrics
+            .iter()
+            .find(|m| m.name() == "traces.span.metrics.duration")
+            .unwrap();
+        let AggregatedMetrics::F64(MetricData::Histogram(histogram)) = duration.data() else {
+            panic!("unexpected duration data");
+        };
+        let recorded: u64 = histogram.data_points().map(|point| point.count()).sum();
+        assert_eq!(recorded, spans.len() as u64);
+
+        let calls = metrics
+            .iter()
+            .find(|m| m.name() == "traces.span.metrics.calls")
+            .unwrap();
+        let AggregatedMetrics::U64(MetricData::Sum(sum)) = calls.data() else {
+            panic!("unexpected calls data");
+        };
+        let mut calls: Vec<_> = sum
+            .data_points()
+            .map(|point| {
+                // The SDK doesn't keep the order of the attributes.
+                let mut attributes: Vec<_> = point.attributes().cloned().collect();
+                attributes.sort_by(|a, b| a.key.cmp(&b.key));
+                (attributes, point.value())
+            })
+            .collect();
+        calls.sort_by_key(|(attributes, _)| format!("{attributes:?}"));
+        calls
+    }
+
+    fn attribute<'a>(attributes: &'a [KeyValue], key: &str) -> Option<&'a Value> {
+        attributes
+            .iter()
+            .find(|kv| kv.key.as_str() == key)
+            .map(|kv| &kv.value)
+    }
+
+    #[test]
+    fn red_metrics() {
+        let calls = calls(
+            SpanMetricsConfig::default().with_dimensions(["http.route"]),
+            None,
+            &[
+                ("GET", Status::Ok, "/users"),
+                ("GET", Status::Ok, "/users"),
+                ("GET", Status::error("boom"), "/users"),
+            ],
+        );
+        assert_eq!(calls.len(), 2);
+        let (errors, ok) = (&calls[0], &calls[1]);
+        assert_eq!(
+            attribute(&errors.0, "status.code"),
+            Some(&Value::from("STATUS_CODE_ERROR"))
+        );
+        assert_eq!(errors.1, 1);
+        assert_eq!(ok.1, 2);
+        for (attributes, _) in &calls {
+            assert_eq!(
+                attribute(attributes, "span.name"),
+                Some(&Value::from("GET"))
+            );
+            assert_eq!(
+                attribute(attributes, "span.kind"),
+                Some(&Value::from("SPAN_KIND_INTERNAL"))
+            );
+            assert_eq!(
+                attribute(attributes, "http.route"),
+                Some(&Value::from("/users"))
+            );
+            assert_eq!(attribute(attributes, "user.id"), None);
+        }
+    }
+
+    #[test]
+    fn max_series() {
+        let calls = calls(
+            SpanMetricsConfig::default()
+                .with_dimensions(["http.route"])
+                .with_max_series(2),
+            None,
+            &[
+                ("GET", Status::Unset, "/a"),
+                ("GET", Status::Unset, "/b"),
+                ("GET", Status::Unset, "/c"),
+                ("GET", Status::Unset, "/d"),
+                ("GET", Status::Unset, "/a"),
+            ],
+        );
+        let overflow: Vec<_> = calls
+            .iter()
+            .filter(|(attributes, _)| attribute(attributes, OVERFLOW_KEY).is_some())
+            .collect();
+        assert_eq!(calls.len(), 3);
+        assert_eq!(overflow.len(), 1);
+        assert_eq!(overflow[0].1, 2);
+    }
+
+    #[test]
+    fn redacted_dimensions() {
+        let calls = calls(
+            SpanMetricsConfig::default().with_dimensions(["user.id"]),
+            Some(Redactor::new().with_deny_keys(["user.id"])),
+            &[("GET", Status::Unset, "/"), ("POST", Status::Unset, "/")],
+        );
+        assert_eq!(calls.len(), 2);
+        for (attributes, _) in &calls {
+            assert_eq!(
+                attribute(attributes, "user.id"),
+                Some(&Value::from("[REDACTED]"))
+            );
+        }
+    }
+}
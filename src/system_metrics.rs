@@ -0,0 +1,786 @@
+//! Host and container metrics for deployments that don't run a collector.
+//!
+//! Enable the `system-metrics` feature and add the subsystem to the metrics
+//! configuration:
+//!
+//! ```
+//! use std::time::Duration;
+//! use uptrace::metrics::MetricsConfig;
+//! use uptrace::system_metrics::SystemMetricsConfig;
+//!
+//! let config = MetricsConfig::default()
+//!     .with_system_metrics(SystemMetricsConfig::default().with_interval(Duration::from_secs(30)));
+//! ```
+//!
+//! The metrics are scraped from `/proc` and `/sys` at the configured interval
+//! and exported with the next collection of the meter provider:
+//!
+//! - `system.cpu.utilization` by `cpu.mode`
+//! - `system.memory.usage` and `system.memory.utilization` by `system.memory.state`
+//! - `system.disk.io` by `system.device` and `disk.io.direction`
+//! - `system.filesystem.usage` by `system.device`, `system.filesystem.mountpoint`,
+//!   `system.filesystem.type` and `system.filesystem.state`
+//! - `system.network.io` by `network.interface.name` and `network.io.direction`
+//! - `system.cgroup.memory.limit`, `system.cgroup.memory.usage` and
+//!   `system.cgroup.cpu.limit` when the process runs in a cgroup with limits
+//!
+//! Only Linux is supported; on other platforms no metrics are registered.
+
+#![cfg_attr(not(target_os = "linux"), allow(dead_code))]
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use opentelemetry::metrics::{AsyncInstrument, Meter};
+use opentelemetry::KeyValue;
+use tokio::runtime::Handle;
+
+use crate::Error;
+
+/// Default interval between two scrapes.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Configuration of the system metrics, see
+/// [`MetricsConfig::with_system_metrics`](crate::metrics::MetricsConfig::with_system_metrics).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SystemMetricsConfig {
+    pub interval: Duration,
+}
+
+impl Default for SystemMetricsConfig {
+    fn default() -> Self {
+        Self {
+            interval: DEFAULT_INTERVAL,
+        }
+    }
+}
+
+impl SystemMetricsConfig {
+    /// Set the interval between two scrapes. Defaults to 15 seconds.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+}
+
+/// Registers the system metrics and starts scraping them on the runtime.
+///
+/// The scraping task stops once the meter provider, which owns the
+/// instrument callbacks, is dropped.
+pub(crate) fn register(
+    meter: &Meter,
+    runtime: &Handle,
+    config: &SystemMetricsConfig,
+) -> Result<(), Error> {
+    if config.interval.is_zero() {
+        return Err(Error::MetricsBuildError(
+            "system metrics interval must be greater than zero".into(),
+        ));
+    }
+    if !cfg!(target_os = "linux") {
+        return Ok(());
+    }
+
+    let mut scraper = Scraper::new("/");
+    let snapshot = Arc::new(Mutex::new(scraper.scrape()));
+    register_instruments(meter, &snapshot);
+
+    let snapshot = Arc::downgrade(&snapshot);
+    let interval = config.interval;
+    runtime.spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        // The first tick completes immediately.
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if snapshot.strong_count() == 0 {
+                return;
+            }
+            // Reading the filesystem usage may block, e.g. on network mounts.
+            let scraped = tokio::task::spawn_blocking(move || {
+                let scraped = scraper.scrape();
+                (scraper, scraped)
+            })
+            .await;
+            let Ok((returned, scraped)) = scraped else {
+                return;
+            };
+            scraper = returned;
+            let Some(snapshot) = Weak::upgrade(&snapshot) else {
+                return;
+            };
+            *snapshot.lock().unwrap_or_else(|e| e.into_inner()) = scraped;
+        }
+    });
+    Ok(())
+}
+
+type Shared = Arc<Mutex<Snapshot>>;
+
+/// Returns an instrument callback observing the latest snapshot.
+fn callback<T, F>(snapshot: &Shared, f: F) -> impl Fn(&dyn AsyncInstrument<T>) + Send + Sync
+where
+    F: Fn(&Snapshot, &dyn AsyncInstrument<T>) + Send + Sync,
+{
+    let snapshot = snapshot.clone();
+    move |o| f(&snapshot.lock().unwrap_or_else(|e| e.into_inner()), o)
+}
+
+fn register_instruments(meter: &Meter, snapshot: &Shared) {
+    meter
+        .f64_observable_gauge("system.cpu.utilization")
+        .with_description("Fraction of CPU time spent in each mode since the previous scrape")
+        .with_unit("1")
+        .with_callback(callback(snapshot, |s, o| {
+            for (mode, value) in &s.cpu_utilization {
+                o.observe(*value, &[KeyValue::new("cpu.mode", *mode)]);
+            }
+        }))
+        .build();
+
+    meter
+        .i64_observable_up_down_counter("system.memory.usage")
+        .with_description("Reports memory in use by state")
+        .with_unit("By")
+        .with_callback(callback(snapshot, |s, o| {
+            if let Some(memory) = &s.memory {
+                for (state, bytes) in memory.states() {
+                    o.observe(bytes as i64, &[KeyValue::new("system.memory.state", state)]);
+                }
+            }
+        }))
+        .build();
+
+    meter
+        .f64_observable_gauge("system.memory.utilization")
+        .with_description("Fraction of the total memory in each state")
+        .with_unit("1")
+        .with_callback(callback(snapshot, |s, o| {
+            if let Some(memory) = s.memory.as_ref().filter(|m| m.total > 0) {
+                for (state, bytes) in memory.states() {
+                    let value = bytes as f64 / memory.total as f64;
+                    o.observe(value, &[KeyValue::new("system.memory.state", state)]);
+                }
+            }
+        }))
+        .build();
+
+    meter
+        .u64_observable_counter("system.disk.io")
+        .with_description("Bytes read from and written to the disks")
+        .with_unit("By")
+        .with_callback(callback(snapshot, |s, o| {
+            for disk in &s.disks {
+                for (direction, bytes) in [("read", disk.read), ("write", disk.written)] {
+                    let attrs = [
+                        KeyValue::new("system.device", disk.device.clone()),
+                        KeyValue::new("disk.io.direction", direction),
+                    ];
+                    o.observe(bytes, &attrs);
+                }
+            }
+        }))
+        .build();
+
+    meter
+        .i64_observable_up_down_counter("system.filesystem.usage")
+        .with_description("Filesystem space by state")
+        .with_unit("By")
+        .with_callback(callback(snapshot, |s, o| {
+            for fs in &s.filesystems {
+                let states = [
+                    ("used", fs.used),
+                    ("free", fs.free),
+                    ("reserved", fs.reserved),
+                ];
+                for (state, bytes) in states {
+                    let attrs = [
+                        KeyValue::new("system.device", fs.device.clone()),
+                        KeyValue::new("system.filesystem.mountpoint", fs.mountpoint.clone()),
+                        KeyValue::new("system.filesystem.type", fs.fs_type.clone()),
+                        KeyValue::new("system.filesystem.state", state),
+                    ];
+                    o.observe(bytes as i64, &attrs);
+                }
+            }
+        }))
+        .build();
+
+    meter
+        .u64_observable_counter("system.network.io")
+        .with_description("Bytes received and transmitted by the network interfaces")
+        .with_unit("By")
+        .with_callback(callback(snapshot, |s, o| {
+            for net in &s.networks {
+                let directions = [("receive", net.received), ("transmit", net.transmitted)];
+                for (direction, bytes) in directions {
+                    let attrs = [
+                        KeyValue::new("network.interface.name", net.interface.clone()),
+                        KeyValue::new("network.io.direction", direction),
+                    ];
+                    o.observe(bytes, &attrs);
+                }
+            }
+        }))
+        .build();
+
+    meter
+        .i64_observable_up_down_counter("system.cgroup.memory.limit")
+        .with_description("Memory limit of the process cgroup")
+        .with_unit("By")
+        .with_callback(callback(snapshot, |s, o| {
+            if let Some(limit) = s.cgroup.memory_limit {
+                o.observe(limit as i64, &[]);
+            }
+        }))
+        .build();
+
+    meter
+        .i64_observable_up_down_counter("system.cgroup.memory.usage")
+        .with_description("Memory used by the process cgroup")
+        .with_unit("By")
+        .with_callback(callback(snapshot, |s, o| {
+            if let Some(usage) = s.cgroup.memory_usage {
+                o.observe(usage as i64, &[]);
+            }
+        }))
+        .build();
+
+    meter
+        .f64_observable_gauge("system.cgroup.cpu.limit")
+        .with_description("CPU limit of the process cgroup, in CPUs")
+        .with_unit("{cpu}")
+        .with_callback(callback(snapshot, |s, o| {
+            if let Some(limit) = s.cgroup.cpu_limit {
+                o.observe(limit, &[]);
+            }
+        }))
+        .build();
+}
+
+/// Values scraped at one point in time.
+#[derive(Debug, Default, Clone, PartialEq)]
+struct Snapshot {
+    cpu_utilization: Vec<(&'static str, f64)>,
+    memory: Option<Memory>,
+    disks: Vec<DiskIo>,
+    filesystems: Vec<Filesystem>,
+    networks: Vec<NetworkIo>,
+    cgroup: Cgroup,
+}
+
+struct Scraper {
+    root: PathBuf,
+    cpu: Option<CpuTimes>,
+}
+
+impl Scraper {
+    fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self {
+            root: root.into(),
+            cpu: None,
+        }
+    }
+
+    fn read(&self, path: &str) -> Option<String> {
+        std::fs::read_to_string(self.root.join(path)).ok()
+    }
+
+    fn scrape(&mut self) -> Snapshot {
+        let cpu = self.read("proc/stat").as_deref().and_then(CpuTimes::parse);
+        let cpu_utilization = match (&self.cpu, &cpu) {
+            (Some(prev), Some(cur)) => cur.utilization(prev),
+            _ => Vec::new(),
+        };
+        self.cpu = cpu;
+
+        let filesystems = self
+            .read("proc/self/mounts")
+            .map(|mounts| {
+                parse_mounts(&mounts)
+                    .into_iter()
+                    .filter_map(|mount| mount.usage())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Snapshot {
+            cpu_utilization,
+            memory: self.read("proc/meminfo").as_deref().and_then(Memory::parse),
+            disks: self
+                .read("proc/diskstats")
+                .as_deref()
+                .map(DiskIo::parse)
+                .unwrap_or_default(),
+            filesystems,
+            networks: self
+                .read("proc/net/dev")
+                .as_deref()
+                .map(NetworkIo::parse)
+                .unwrap_or_default(),
+            cgroup: Cgroup::read(|path| self.read(path)),
+        }
+    }
+}
+
+/// Aggregate CPU times from the first line of `/proc/stat`, in clock ticks.
+#[derive(Debug, Clone, PartialEq)]
+struct CpuTimes([u64; 8]);
+
+impl CpuTimes {
+    /// Semantic convention `cpu.mode` of each `/proc/stat` column; the
+    /// interrupt time is the sum of the irq and softirq columns.
+    const MODES: [&'static str; 8] = [
+        "user",
+        "nice",
+        "system",
+        "idle",
+        "iowait",
+        "interrupt",
+        "interrupt",
+        "steal",
+    ];
+
+    fn parse(stat: &str) -> Option<Self> {
+        let line = stat.lines().find(|line| line.starts_with("cpu "))?;
+        let mut times = [0; 8];
+        let mut fields = line.split_whitespace().skip(1);
+        for time in &mut times {
+            *time = fields.next()?.parse().ok()?;
+        }
+        Some(Self(times))
+    }
+
+    fn utilization(&self, prev: &CpuTimes) -> Vec<(&'static str, f64)> {
+        let deltas: Vec<u64> = (0..8)
+            .map(|i| self.0[i].saturating_sub(prev.0[i]))
+            .collect();
+        let total: u64 = deltas.iter().sum();
+        if total == 0 {
+            return Vec::new();
+        }
+        let mut utilization: Vec<(&'static str, f64)> = Vec::with_capacity(7);
+        for (mode, delta) in Self::MODES.iter().zip(deltas) {
+            let value = delta as f64 / total as f64;
+            match utilization.last_mut() {
+                Some((last, sum)) if last == mode => *sum += value,
+                _ => utilization.push((mode, value)),
+            }
+        }
+        utilization
+    }
+}
+
+/// Memory from `/proc/meminfo`, in bytes.
+#[derive(Debug, Clone, PartialEq)]
+struct Memory {
+    total: u64,
+    free: u64,
+    buffers: u64,
+    cached: u64,
+}
+
+impl Memory {
+    fn parse(meminfo: &str) -> Option<Self> {
+        let field = |name: &str| -> Option<u64> {
+            let line = meminfo
+                .lines()
+                .find(|line| line.split_once(':').is_some_and(|(key, _)| key == name))?;
+            let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+            Some(kb * 1024)
+        };
+        Some(Self {
+            total: field("MemTotal")?,
+            free: field("MemFree")?,
+            buffers: field("Buffers").unwrap_or(0),
+            cached: field("Cached").unwrap_or(0),
+        })
+    }
+
+    fn states(&self) -> [(&'static str, u64); 4] {
+        let used = self
+            .total
+            .saturating_sub(self.free + self.buffers + self.cached);
+        [
+            ("used", used),
+            ("free", self.free),
+            ("buffers", self.buffers),
+            ("cached", self.cached),
+        ]
+    }
+}
+
+/// Disk I/O from `/proc/diskstats`, in bytes.
+#[derive(Debug, Clone, PartialEq)]
+struct DiskIo {
+    device: String,
+    read: u64,
+    written: u64,
+}
+
+impl DiskIo {
+    /// Size of the sectors counted by `/proc/diskstats`, regardless of the device.
+    const SECTOR_SIZE: u64 = 512;
+
+    fn parse(diskstats: &str) -> Vec<Self> {
+        diskstats
+            .lines()
+            .filter_map(|line| {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                let device = *fields.get(2)?;
+                if device.starts_with("loop") || device.starts_with("ram") {
+                    return None;
+                }
+                Some(Self {
+                    device: device.to_string(),
+                    read: fields.get(5)?.parse::<u64>().ok()? * Self::SECTOR_SIZE,
+                    written: fields.get(9)?.parse::<u64>().ok()? * Self::SECTOR_SIZE,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Network I/O from `/proc/net/dev`, in bytes.
+#[derive(Debug, Clone, PartialEq)]
+struct NetworkIo {
+    interface: String,
+    received: u64,
+    transmitted: u64,
+}
+
+impl NetworkIo {
+    fn parse(dev: &str) -> Vec<Self> {
+        dev.lines()
+            .filter_map(|line| {
+                let (interface, stats) = line.split_once(':')?;
+                let interface = interface.trim();
+                if interface == "lo" {
+                    return None;
+                }
+                let fields: Vec<&str> = stats.split_whitespace().collect();
+                Some(Self {
+                    interface: interface.to_string(),
+                    received: fields.first()?.parse().ok()?,
+                    transmitted: fields.get(8)?.parse().ok()?,
+                })
+            })
+            .collect()
+    }
+}
+
+/// A filesystem mounted from a block device, from `/proc/self/mounts`.
+#[derive(Debug, Clone, PartialEq)]
+struct Mount {
+    device: String,
+    mountpoint: String,
+    fs_type: String,
+}
+
+/// Parses the mounts backed by a device, keeping the first mountpoint of each
+/// device, e.g. of volumes bind-mounted several times into a container.
+fn parse_mounts(mounts: &str) -> Vec<Mount> {
+    let mut devices = HashSet::new();
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let device = fields.next()?;
+            let mountpoint = fields.next()?;
+            let fs_type = fields.next()?;
+            if !device.starts_with('/') || !devices.insert(device.to_string()) {
+                return None;
+            }
+            Some(Mount {
+                device: device.to_string(),
+                mountpoint: unescape_mount(mountpoint),
+                fs_type: fs_type.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Decodes the octal escapes, e.g. `\040` for a space, used in `/proc/self/mounts`.
+fn unescape_mount(field: &str) -> String {
+    let mut bytes = Vec::with_capacity(field.len());
+    let mut rest = field.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        let code = tail
+            .get(..3)
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+        match (b, code) {
+            (b'\\', Some(code)) => {
+                bytes.push(code);
+                rest = &tail[3..];
+            }
+            _ => {
+                bytes.push(b);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Filesystem space, in bytes.
+#[derive(Debug, Clone, PartialEq)]
+struct Filesystem {
+    device: String,
+    mountpoint: String,
+    fs_type: String,
+    used: u64,
+    free: u64,
+    reserved: u64,
+}
+
+impl Mount {
+    #[cfg(target_os = "linux")]
+    fn usage(self) -> Option<Filesystem> {
+        use std::ffi::CString;
+        use std::mem::MaybeUninit;
+
+        let path = CString::new(self.mountpoint.as_str()).ok()?;
+        let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+        // SAFETY: path is NUL-terminated and stat is only read if the call succeeds.
+        let stat = unsafe {
+            if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+                return None;
+            }
+            stat.assume_init()
+        };
+        let block = stat.f_frsize as u64;
+        let (blocks, bfree, bavail) = (
+            stat.f_blocks as u64,
+            stat.f_bfree as u64,
+            stat.f_bavail as u64,
+        );
+        Some(Filesystem {
+            used: blocks.saturating_sub(bfree) * block,
+            free: bavail * block,
+            reserved: bfree.saturating_sub(bavail) * block,
+            device: self.device,
+            mountpoint: self.mountpoint,
+            fs_type: self.fs_type,
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn usage(self) -> Option<Filesystem> {
+        None
+    }
+}
+
+/// Limits and usage of the cgroup the process runs in, from `/sys/fs/cgroup`.
+/// Both cgroup v2 and v1 are supported; unlimited values are `None`.
+#[derive(Debug, Default, Clone, PartialEq)]
+struct Cgroup {
+    memory_limit: Option<u64>,
+    memory_usage: Option<u64>,
+    /// Number of CPUs the cgroup may use.
+    cpu_limit: Option<f64>,
+}
+
+impl Cgroup {
+    /// cgroup v1 reports a page-aligned `i64::MAX` when memory is unlimited.
+    const V1_UNLIMITED: u64 = 1 << 62;
+
+    fn read<F: Fn(&str) -> Option<String>>(read: F) -> Self {
+        let number = |path: &str| -> Option<u64> { read(path)?.trim().parse().ok() };
+
+        if let Some(cpu_max) = read("sys/fs/cgroup/cpu.max") {
+            return Self {
+                memory_limit: number("sys/fs/cgroup/memory.max"),
+                memory_usage: number("sys/fs/cgroup/memory.current"),
+                cpu_limit: parse_cpu_max(&cpu_max),
+            };
+        }
+
+        let quota = read("sys/fs/cgroup/cpu/cpu.cfs_quota_us")
+            .and_then(|quota| quota.trim().parse::<i64>().ok())
+            .filter(|quota| *quota > 0);
+        let period = number("sys/fs/cgroup/cpu/cpu.cfs_period_us").filter(|period| *period > 0);
+        Self {
+            memory_limit: number("sys/fs/cgroup/memory/memory.limit_in_bytes")
+                .filter(|limit| *limit < Self::V1_UNLIMITED),
+            memory_usage: number("sys/fs/cgroup/memory/memory.usage_in_bytes"),
+            cpu_limit: quota
+                .zip(period)
+                .map(|(quota, period)| quota as f64 / period as f64),
+        }
+    }
+}
+
+/// Parses the cgroup v2 `cpu.max` file: `$MAX $PERIOD`, where `$MAX` may be `max`.
+fn parse_cpu_max(cpu_max: &str) -> Option<f64> {
+    let mut fields = cpu_max.split_whitespace();
+    let quota: u64 = fields.next()?.parse().ok()?;
+    let period: u64 = fields.next()?.parse().ok()?;
+    (period > 0).then(|| quota as f64 / period as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpu_utilization() {
+        let prev = CpuTimes::parse("cpu  100 0 50 800 10 5 5 0 0 0\ncpu0 1 2 3").unwrap();
+        let cur = CpuTimes::parse("cpu  160 0 70 910 10 10 10 0 0 0\n").unwrap();
+        assert_eq!(
+            cur.utilization(&prev),
+            [
+                ("user", 0.3),
+                ("nice", 0.0),
+                ("system", 0.1),
+                ("idle", 0.55),
+                ("iowait", 0.0),
+                ("interrupt", 0.05),
+                ("steal", 0.0),
+            ]
+        );
+        assert!(prev.utilization(&prev).is_empty());
+        assert_eq!(CpuTimes::parse("cpu 1 2"), None);
+    }
+
+    #[test]
+    fn memory() {
+        let meminfo = "MemTotal:       16000 kB\nMemFree:         4000 kB\n\
+                       MemAvailable:    9000 kB\nBuffers:          1000 kB\n\
+                       Cached:           3000 kB\nSwapCached:         0 kB\n";
+        let memory = Memory::parse(meminfo).unwrap();
+        assert_eq!(
+            memory.states(),
+            [
+                ("used", 8000 * 1024),
+                ("free", 4000 * 1024),
+                ("buffers", 1000 * 1024),
+                ("cached", 3000 * 1024),
+            ]
+        );
+    }
+
+    #[test]
+    fn disk_io() {
+        let diskstats = "   7       0 loop0 10 0 20 0 0 0 0 0 0 0 0\n\
+                         259       0 nvme0n1 100 5 2000 30 50 7 4000 60 0 90 90\n";
+        assert_eq!(
+            DiskIo::parse(diskstats),
+            [DiskIo {
+                device: "nvme0n1".into(),
+                read: 2000 * 512,
+                written: 4000 * 512,
+            }]
+        );
+    }
+
+    #[test]
+    fn network_io() {
+        let dev = "Inter-|   Receive                            |  Transmit\n \
+                   face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets\n    \
+                   lo: 500 5 0 0 0 0 0 0 500 5 0 0 0 0 0 0\n  \
+                   eth0: 1200 10 0 0 0 0 0 0 3400 20 0 0 0 0 0 0\n";
+        assert_eq!(
+            NetworkIo::parse(dev),
+            [NetworkIo {
+                interface: "eth0".into(),
+                received: 1200,
+                transmitted: 3400,
+            }]
+        );
+    }
+
+    #[test]
+    fn mounts() {
+        let mounts = "proc /proc proc rw 0 0\n\
+                      /dev/sda1 / ext4 rw 0 0\n\
+                      /dev/sdb1 /mnt/my\\040data xfs rw 0 0\n\
+                      /dev/sda1 /etc/hosts ext4 rw 0 0\n";
+        assert_eq!(
+            parse_mounts(mounts),
+            [
+                Mount {
+                    device: "/dev/sda1".into(),
+                    mountpoint: "/".into(),
+                    fs_type: "ext4".into(),
+                },
+                Mount {
+                    device: "/dev/sdb1".into(),
+                    mountpoint: "/mnt/my data".into(),
+                    fs_type: "xfs".into(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn cgroup() {
+        let v2 = |path: &str| match path {
+            "sys/fs/cgroup/cpu.max" => Some("150000 100000\n".to_string()),
+            "sys/fs/cgroup/memory.max" => Some("max\n".to_string()),
+            "sys/fs/cgroup/memory.current" => Some("1048576\n".to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            Cgroup::read(v2),
+            Cgroup {
+                memory_limit: None,
+                memory_usage: Some(1048576),
+                cpu_limit: Some(1.5),
+            }
+        );
+
+        let v1 = |path: &str| match path {
+            "sys/fs/cgroup/cpu/cpu.cfs_quota_us" => Some("-1\n".to_string()),
+            "sys/fs/cgroup/cpu/cpu.cfs_period_us" => Some("100000\n".to_string()),
+            "sys/fs/cgroup/memory/memory.limit_in_bytes" => Some("536870912\n".to_string()),
+            "sys/fs/cgroup/memory/memory.usage_in_bytes" => Some("1048576\n".to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            Cgroup::read(v1),
+            Cgroup {
+                memory_limit: Some(536870912),
+                memory_usage: Some(1048576),
+                cpu_limit: None,
+            }
+        );
+        assert_eq!(parse_cpu_max("max 100000"), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn register() {
+        use opentelemetry::metrics::MeterProvider;
+        use opentelemetry_sdk::metrics::{
+            InMemoryMetricExporter, PeriodicReader, SdkMeterProvider,
+        };
+
+        let exporter = InMemoryMetricExporter::default();
+        let provider = SdkMeterProvider::builder()
+            .with_reader(PeriodicReader::builder(exporter.clone()).build())
+            .build();
+        let meter = provider.meter("test");
+        let runtime = Handle::current();
+
+        let config = SystemMetricsConfig::default().with_interval(Duration::ZERO);
+        assert!(super::register(&meter, &runtime, &config).is_err());
+
+        super::register(&meter, &runtime, &SystemMetricsConfig::default()).unwrap();
+        provider.force_flush().unwrap();
+
+        let exported = exporter.get_finished_metrics().unwrap();
+        let names: Vec<_> = exported
+            .iter()
+            .flat_map(|rm| rm.scope_metrics())
+            .flat_map(|sm| sm.metrics())
+            .map(|m| m.name().to_string())
+            .collect();
+        assert!(
+            names.iter().any(|name| name == "system.memory.usage"),
+            "{names:?}"
+        );
+    }
+}
@@ -0,0 +1,89 @@
+//! Helpers shared by unit tests.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use opentelemetry::{Context, KeyValue};
+use opentelemetry_sdk::error::OTelSdkResult;
+use opentelemetry_sdk::metrics::data::{AggregatedMetrics, MetricData};
+use opentelemetry_sdk::metrics::{InMemoryMetricExporter, PeriodicReader, SdkMeterProvider};
+use opentelemetry_sdk::trace::{SdkTracerProvider, Span, SpanData, SpanProcessor};
+
+use crate::meter::MeterProvider;
+use crate::uptrace::GlobalConfig;
+use crate::{Dsn, Uptrace};
+
+/// Span processor that keeps ended spans in memory.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Collect(Arc<Mutex<Vec<SpanData>>>);
+
+impl Collect {
+    pub(crate) fn spans(&self) -> Vec<SpanData> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl SpanProcessor for Collect {
+    fn on_start(&self, _span: &mut Span, _cx: &Context) {}
+
+    fn on_end(&self, span: SpanData) {
+        self.0.lock().unwrap().push(span);
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        Ok(())
+    }
+
+    fn shutdown_with_timeout(&self, _timeout: Duration) -> OTelSdkResult {
+        Ok(())
+    }
+}
+
+/// Returns a handle that exports spans to `spans` and metrics to `metrics`
+/// without installing anything globally.
+pub(crate) fn uptrace(spans: &Collect, metrics: &InMemoryMetricExporter) -> Uptrace {
+    let tracer_provider = SdkTracerProvider::builder()
+        .with_span_processor(spans.clone())
+        .build();
+    let meter_provider = SdkMeterProvider::builder()
+        .with_reader(PeriodicReader::builder(metrics.clone()).build())
+        .build();
+    Uptrace::new(
+        Dsn::default(),
+        Some(tracer_provider),
+        Some(MeterProvider::new(meter_provider, None, None)),
+        None,
+        GlobalConfig::default(),
+    )
+}
+
+/// Flushes the metrics of `uptrace` and returns the unit and the data points
+/// of the `f64` histogram `name` as (attributes sorted by key, count).
+#[cfg_attr(not(any(feature = "http", feature = "grpc")), allow(dead_code))]
+pub(crate) fn histogram(
+    uptrace: &Uptrace,
+    metrics: &InMemoryMetricExporter,
+    name: &str,
+) -> (String, Vec<(Vec<KeyValue>, u64)>) {
+    uptrace.meter_provider().unwrap().force_flush().unwrap();
+
+    let mut unit = String::new();
+    let mut points = Vec::new();
+    for rm in metrics.get_finished_metrics().unwrap() {
+        for metric in rm.scope_metrics().flat_map(|sm| sm.metrics()) {
+            let AggregatedMetrics::F64(MetricData::Histogram(histogram)) = metric.data() else {
+                continue;
+            };
+            if metric.name() != name {
+                continue;
+            }
+            unit = metric.unit().to_string();
+            for point in histogram.data_points() {
+                let mut attributes: Vec<_> = point.attributes().cloned().collect();
+                attributes.sort_by(|a, b| a.key.cmp(&b.key));
+                points.push((attributes, point.count()));
+            }
+        }
+    }
+    (unit, points)
+}
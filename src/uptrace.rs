@@ -0,0 +1,500 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use opentelemetry::metrics::{Meter, MeterProvider};
+use opentelemetry::trace::{SpanContext, TracerProvider};
+use opentelemetry::{global, InstrumentationScope};
+use opentelemetry_appender_tracing::layer::OpenTelemetryTracingBridge;
+use opentelemetry_sdk::error::OTelSdkResult;
+use opentelemetry_sdk::metrics::SdkMeterProvider;
+use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider};
+use tracing::Metadata;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::filter::{filter_fn, EnvFilter};
+use tracing_subscriber::fmt::format::Format;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+use crate::error_handler::{
+    self, is_sdk_target, ErrorHandler, ErrorLayer, RateLimitedHandler, INTERNAL_TARGET,
+};
+use crate::format::{FmtLayer, TraceIdFormat};
+use crate::logs::{Logger, LoggerProvider};
+use crate::{meter, propagation, Dsn, Error, Propagator};
+
+/// Name and version of the instrumentation scope used by the crate's tracer.
+pub(crate) const SCOPE_NAME: &str = "uptrace-rust";
+pub(crate) const SCOPE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Targets of the crates used by the exporters. Their events are not exported,
+/// otherwise every export would produce more data to export.
+const EXPORTER_TARGETS: [&str; 4] = ["h2", "hyper", "tonic", "reqwest"];
+
+fn scope() -> InstrumentationScope {
+    InstrumentationScope::builder(SCOPE_NAME)
+        .with_version(SCOPE_VERSION)
+        .build()
+}
+
+/// Returns the meter with the crate's instrumentation scope.
+pub(crate) fn scope_meter<P: MeterProvider + ?Sized>(provider: &P) -> Meter {
+    provider.meter_with_scope(scope())
+}
+
+/// Returns whether `tracing` data with this metadata may be exported to Uptrace.
+fn is_exported(meta: &Metadata<'_>) -> bool {
+    let target = meta.target();
+    target != INTERNAL_TARGET
+        && !is_sdk_target(target)
+        && !EXPORTER_TARGETS
+            .iter()
+            .any(|prefix| target.starts_with(prefix))
+}
+
+/// Settings that only take effect once installed globally by [`Uptrace::install_global`].
+#[derive(Clone)]
+pub(crate) struct GlobalConfig {
+    pub(crate) error_handler: ErrorHandler,
+    pub(crate) error_rate_limit: Duration,
+    pub(crate) propagators: Vec<Propagator>,
+}
+
+impl Default for GlobalConfig {
+    fn default() -> Self {
+        Self {
+            error_handler: Arc::new(error_handler::stderr_handler),
+            error_rate_limit: error_handler::DEFAULT_RATE_LIMIT,
+            propagators: Propagator::DEFAULT.to_vec(),
+        }
+    }
+}
+
+/// Handle to the OpenTelemetry pipelines configured by [`UptraceBuilder::build`].
+///
+/// [`UptraceBuilder::build`]: crate::UptraceBuilder::build
+pub struct Uptrace {
+    dsn: Option<Dsn>,
+    tracer_provider: SdkTracerProvider,
+    tracer: SdkTracer,
+    tracing_enabled: bool,
+    meter_provider: meter::MeterProvider,
+    metrics_enabled: bool,
+    logger_provider: Option<LoggerProvider>,
+    error_handler: Arc<RateLimitedHandler>,
+    propagators: Vec<Propagator>,
+}
+
+impl Uptrace {
+    pub(crate) fn new(
+        dsn: Dsn,
+        tracer_provider: Option<SdkTracerProvider>,
+        meter_provider: Option<meter::MeterProvider>,
+        logger_provider: Option<LoggerProvider>,
+        globals: GlobalConfig,
+    ) -> Self {
+        let mut uptrace = Self::disabled();
+        if let Some(provider) = tracer_provider {
+            uptrace.tracer = provider.tracer_with_scope(scope());
+            uptrace.tracer_provider = provider;
+            uptrace.tracing_enabled = true;
+        }
+        if let Some(provider) = meter_provider {
+            uptrace.meter_provider = provider;
+            uptrace.metrics_enabled = true;
+        }
+        uptrace.dsn = Some(dsn);
+        uptrace.logger_provider = logger_provider;
+        uptrace.error_handler = Arc::new(RateLimitedHandler::new(
+            globals.error_handler,
+            globals.error_rate_limit,
+        ));
+        uptrace.propagators = globals.propagators;
+        uptrace
+    }
+
+    /// Returns a handle that doesn't export anything, used when Uptrace is disabled.
+    pub(crate) fn disabled() -> Self {
+        let tracer_provider = SdkTracerProvider::builder().build();
+        let tracer = tracer_provider.tracer_with_scope(scope());
+        let globals = GlobalConfig::default();
+        Self {
+            dsn: None,
+            tracer_provider,
+            tracer,
+            tracing_enabled: false,
+            meter_provider: meter::MeterProvider::new(SdkMeterProvider::default(), None, None),
+            metrics_enabled: false,
+            logger_provider: None,
+            error_handler: Arc::new(RateLimitedHandler::new(
+                globals.error_handler,
+                globals.error_rate_limit,
+            )),
+            propagators: globals.propagators,
+        }
+    }
+
+    /// Installs the error handler, the text map propagator and the tracer and
+    /// meter providers as the OpenTelemetry globals, and registers the tracer
+    /// and the meter and logger providers with the
+    /// [panic hook](crate::install_panic_hook).
+    ///
+    /// [`UptraceBuilder::build`](crate::UptraceBuilder::build) calls this
+    /// already. Does nothing if Uptrace is disabled.
+    pub fn install_global(&self) {
+        if self.dsn.is_none() {
+            return;
+        }
+
+        error_handler::set_global_handler(self.error_handler.clone());
+        if tracing::dispatcher::has_been_set() {
+            error_handler::check_layer_added();
+        }
+        global::set_text_map_propagator(propagation::composite(&self.propagators));
+
+        if self.tracing_enabled {
+            global::set_tracer_provider(self.tracer_provider.clone());
+            crate::panic::register_tracer(&self.tracer, &self.tracer_provider);
+        }
+        if self.metrics_enabled {
+            global::set_meter_provider(self.meter_provider.clone());
+            crate::panic::register_meter_provider(self.meter_provider.sdk_provider());
+        }
+        if let Some(provider) = &self.logger_provider {
+            crate::panic::register_logger_provider(provider.sdk_provider());
+        }
+    }
+
+    /// Returns the parsed DSN, or `None` if Uptrace is disabled.
+    pub fn dsn(&self) -> Option<&Dsn> {
+        self.dsn.as_ref()
+    }
+
+    /// Returns the tracer with the `uptrace-rust` instrumentation scope.
+    pub fn tracer(&self) -> &SdkTracer {
+        &self.tracer
+    }
+
+    pub fn tracer_provider(&self) -> &SdkTracerProvider {
+        &self.tracer_provider
+    }
+
+    /// Returns the meter provider, or `None` if metrics are disabled.
+    ///
+    /// Instruments created directly from the SDK provider don't record
+    /// exemplars; use [`Uptrace::meter`] or the global meter provider for that.
+    pub fn meter_provider(&self) -> Option<&SdkMeterProvider> {
+        self.metrics_enabled
+            .then_some(self.meter_provider.sdk_provider())
+    }
+
+    /// Returns the address the Prometheus endpoint listens on, or `None` if
+    /// it isn't enabled. Useful when the configured port is 0.
+    #[cfg(feature = "prometheus")]
+    pub fn prometheus_addr(&self) -> Option<std::net::SocketAddr> {
+        self.meter_provider.prometheus_addr()
+    }
+
+    /// Returns the logger provider, or `None` if logs are disabled.
+    pub fn logger_provider(&self) -> Option<&LoggerProvider> {
+        self.logger_provider.as_ref()
+    }
+
+    /// Returns the meter with the `uptrace-rust` instrumentation scope,
+    /// or a no-op meter if metrics are disabled.
+    pub fn meter(&self) -> Meter {
+        scope_meter(&self.meter_provider)
+    }
+
+    /// Exports pending spans, metrics and logs, blocking until the exports finish.
+    ///
+    /// Don't call this from a single-threaded tokio runtime: the exporters need
+    /// the runtime to make progress. Use [`Uptrace::shutdown_with_timeout`] there.
+    pub fn force_flush(&self) -> Result<(), Error> {
+        self.check_error_layer();
+        let logger_provider = self.logger_provider.as_ref().map(|p| p.sdk_provider());
+        let results = [
+            self.tracer_provider.force_flush(),
+            self.meter_provider.sdk_provider().force_flush(),
+            logger_provider.map_or(Ok(()), |provider| provider.force_flush()),
+        ];
+        first_error(results)
+    }
+
+    /// Exports pending data and shuts the providers down, blocking until it finishes.
+    ///
+    /// The same caveat as for [`Uptrace::force_flush`] applies.
+    pub fn shutdown(&self) -> Result<(), Error> {
+        self.check_error_layer();
+        let logger_provider = self.logger_provider.as_ref().map(|p| p.sdk_provider());
+        let results = [
+            self.tracer_provider.shutdown(),
+            self.meter_provider.sdk_provider().shutdown(),
+            logger_provider.map_or(Ok(()), |provider| provider.shutdown()),
+        ];
+        first_error(results)
+    }
+
+    /// Shuts the tracer, meter and logger providers down, which exports the
+    /// pending spans, metrics and logs, waiting at most `timeout` for all of them.
+    ///
+    /// The providers are shut down concurrently on blocking threads. On
+    /// timeout an [`Error::Shutdown`] is returned and the export continues in
+    /// the background.
+    pub async fn shutdown_with_timeout(&self, timeout: Duration) -> Result<(), Error> {
+        self.check_error_layer();
+        let tracer_provider = self.tracer_provider.clone();
+        let traces =
+            tokio::task::spawn_blocking(move || tracer_provider.shutdown_with_timeout(timeout));
+        let meter_provider = self.meter_provider.sdk_provider().clone();
+        let metrics =
+            tokio::task::spawn_blocking(move || meter_provider.shutdown_with_timeout(timeout));
+        let logger_provider = self.logger_provider.clone();
+        let logs = tokio::task::spawn_blocking(move || match logger_provider {
+            Some(provider) => provider.sdk_provider().shutdown_with_timeout(timeout),
+            None => Ok(()),
+        });
+
+        let join = async {
+            let mut results = Vec::with_capacity(3);
+            for task in [traces, metrics, logs] {
+                results.push(task.await.map_err(|err| Error::Shutdown(Box::new(err)))?);
+            }
+            first_error(results)
+        };
+        match tokio::time::timeout(timeout, join).await {
+            Ok(result) => result,
+            Err(_) => Err(Error::Shutdown(
+                format!("shutdown timed out after {:?}", timeout).into(),
+            )),
+        }
+    }
+
+    /// Waits for ctrl-c or, on Unix, `SIGTERM` and then calls
+    /// [`Uptrace::shutdown_with_timeout`].
+    ///
+    /// ```no_run
+    /// # async fn run(uptrace: uptrace::Uptrace) -> Result<(), uptrace::Error> {
+    /// use std::time::Duration;
+    ///
+    /// tokio::select! {
+    ///     result = uptrace.shutdown_on_signal(Duration::from_secs(10)) => result,
+    ///     _ = async { /* serve requests */ } => Ok(()),
+    /// }
+    /// # }
+    /// ```
+    #[cfg(feature = "signal")]
+    pub async fn shutdown_on_signal(&self, timeout: Duration) -> Result<(), Error> {
+        wait_for_signal()
+            .await
+            .map_err(|err| Error::Runtime(Box::new(err)))?;
+        self.shutdown_with_timeout(timeout).await
+    }
+
+    /// Returns the URL of the trace in the Uptrace UI, or `None` if Uptrace is disabled.
+    pub fn trace_url(&self, span_context: &SpanContext) -> Option<String> {
+        self.dsn
+            .as_ref()
+            .map(|dsn| dsn.trace_url(span_context.trace_id()))
+    }
+
+    /// Returns a `tracing` layer that exports spans through the crate's tracer.
+    pub fn tracing_layer<S>(&self) -> OpenTelemetryLayer<S, SdkTracer>
+    where
+        S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+    {
+        tracing_opentelemetry::layer().with_tracer(self.tracer.clone())
+    }
+
+    /// Returns a `tracing` layer that exports events as log records, correlated
+    /// with the span exported by the [`Uptrace::tracing_layer`], or `None` if
+    /// logs are disabled.
+    ///
+    /// Events of the crates used by the exporters, e.g. `tonic`, must be
+    /// filtered out of this layer, as [`Uptrace::init_tracing_subscriber`] does.
+    pub fn logs_layer(&self) -> Option<OpenTelemetryTracingBridge<LoggerProvider, Logger>> {
+        self.logger_provider
+            .as_ref()
+            .map(OpenTelemetryTracingBridge::new)
+    }
+
+    /// Returns a `tracing` layer that passes the warnings and errors reported
+    /// by the OpenTelemetry SDK to the configured error handler.
+    ///
+    /// The SDK reports errors, e.g. failed exports, as `tracing` events, so
+    /// without this layer or another subscriber they are lost.
+    pub fn error_layer(&self) -> ErrorLayer {
+        ErrorLayer::new(self.error_handler.clone())
+    }
+
+    /// Returns a fmt layer writing to stdout that adds trace ids and the trace URL
+    /// to events, see [`TraceIdFormat`].
+    pub fn fmt_layer<S>(&self) -> FmtLayer<S>
+    where
+        S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+    {
+        let ansi = std::io::IsTerminal::is_terminal(&std::io::stdout());
+        let mut format = TraceIdFormat::new(Format::default().with_ansi(ansi));
+        if let Some(dsn) = &self.dsn {
+            format = format.with_trace_url(dsn);
+        }
+
+        tracing_subscriber::fmt::layer().event_format(format)
+    }
+
+    /// Installs a global `tracing` subscriber with the [`Uptrace::tracing_layer`],
+    /// the [`Uptrace::logs_layer`], the [`Uptrace::fmt_layer`], the
+    /// [`Uptrace::error_layer`] and a bridge for `log` records.
+    ///
+    /// Events are filtered with `RUST_LOG`, defaulting to `info`. SDK warnings
+    /// and errors go to the error handler instead of the fmt layer.
+    pub fn init_tracing_subscriber(&self) -> Result<(), Error> {
+        let env_filter =
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+        let otel_layer = self.tracing_layer().with_filter(filter_fn(is_exported));
+        let logs_layer = self.logs_layer().with_filter(filter_fn(is_exported));
+        let fmt_layer = self.fmt_layer().with_filter(filter_fn(|meta| {
+            !(is_sdk_target(meta.target()) && *meta.level() <= tracing::Level::WARN)
+        }));
+
+        tracing_subscriber::registry()
+            .with(env_filter)
+            .with(otel_layer)
+            .with(logs_layer)
+            .with(fmt_layer)
+            .with(self.error_layer())
+            .try_init()
+            .map_err(|e| Error::TracingSubscriber(Box::new(e)))
+    }
+
+    /// Reports once that SDK errors are lost if the [`Uptrace::error_layer`]
+    /// was never added to a subscriber.
+    fn check_error_layer(&self) {
+        if self.dsn.is_some() {
+            error_handler::check_layer_added();
+        }
+    }
+}
+
+fn first_error<I: IntoIterator<Item = OTelSdkResult>>(results: I) -> Result<(), Error> {
+    results
+        .into_iter()
+        .find_map(Result::err)
+        .map_or(Ok(()), |err| Err(Error::Shutdown(Box::new(err))))
+}
+
+#[cfg(all(feature = "signal", unix))]
+async fn wait_for_signal() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = sigterm.recv() => Ok(()),
+        _ = sigint.recv() => Ok(()),
+    }
+}
+
+#[cfg(all(feature = "signal", not(unix)))]
+async fn wait_for_signal() -> std::io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use opentelemetry::trace::{Span as _, TraceContextExt, Tracer as _};
+    use opentelemetry::{global, Context};
+    use opentelemetry_sdk::error::OTelSdkResult;
+    use opentelemetry_sdk::trace::{SdkTracerProvider, Span, SpanData, SpanProcessor};
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::{GlobalConfig, Uptrace};
+    use crate::{Dsn, ErrorKind, UptraceBuilder};
+
+    #[derive(Debug)]
+    struct SlowShutdown;
+
+    impl SpanProcessor for SlowShutdown {
+        fn on_start(&self, _span: &mut Span, _cx: &Context) {}
+
+        fn on_end(&self, _span: SpanData) {}
+
+        fn force_flush(&self) -> OTelSdkResult {
+            Ok(())
+        }
+
+        fn shutdown_with_timeout(&self, _timeout: Duration) -> OTelSdkResult {
+            std::thread::sleep(Duration::from_millis(500));
+            Ok(())
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn build_without_global() {
+        let build = || {
+            UptraceBuilder::new()
+                .with_dsn("http://token@localhost:14317/1")
+                .build_without_global()
+                .unwrap()
+        };
+        let first = build();
+        let second = build();
+
+        assert!(first.dsn().is_some());
+        assert!(second.meter_provider().is_some());
+        assert!(second.logger_provider().is_some());
+        let span = global::tracer("test").start("not recorded");
+        assert!(!span.span_context().is_valid());
+    }
+
+    #[test]
+    fn build_without_runtime() {
+        let err = UptraceBuilder::new()
+            .with_dsn("http://token@localhost:14317/1")
+            .build_without_global()
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::Runtime);
+    }
+
+    #[tokio::test]
+    async fn shutdown_with_timeout() {
+        let uptrace = Uptrace::disabled();
+        uptrace
+            .shutdown_with_timeout(Duration::from_secs(1))
+            .await
+            .unwrap();
+
+        let provider = SdkTracerProvider::builder()
+            .with_span_processor(SlowShutdown)
+            .build();
+        let uptrace = Uptrace::new(
+            Dsn::default(),
+            Some(provider),
+            None,
+            None,
+            GlobalConfig::default(),
+        );
+        let err = uptrace
+            .shutdown_with_timeout(Duration::from_millis(10))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Shutdown);
+    }
+
+    #[test]
+    fn tracing_layer() {
+        let uptrace = Uptrace::disabled();
+        let subscriber = tracing_subscriber::registry().with(uptrace.tracing_layer());
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("my_span");
+            let cx = span.context();
+            assert!(cx.span().span_context().is_valid());
+        });
+    }
+}